@@ -1,3 +1,6 @@
+// Placeholder until the crate gets a real API: nothing calls this yet, and
+// without the allow `cargo clippy --workspace -- -D warnings` fails on it
+#[allow(dead_code)]
 fn main() {
     println!("this going to take a long time\n");
 }
@@ -1,5 +1,9 @@
-use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
-use rust_tokenizer::Tokenizer;
+// Compare against bounds-checked hot loops with:
+//   cargo bench -p rust_tokenizer --features safe
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pyo3::prelude::*;
+use pyo3::types::PyList;
+use rust_tokenizer::{PairReduce, Tokenizer};
 use std::collections::HashMap;
 
 fn create_trained_tokenizer() -> Tokenizer {
@@ -34,6 +38,11 @@ fn bench_encode_by_length(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::new("encode", label), text, |b, text| {
             b.iter(|| tok.encode(black_box(text)))
         });
+        group.bench_with_input(
+            BenchmarkId::new("encode_ordinary", label),
+            text,
+            |b, text| b.iter(|| tok.encode_ordinary(black_box(text))),
+        );
     }
 
     group.finish();
@@ -99,6 +108,35 @@ fn bench_load_merges(c: &mut Criterion) {
     });
 }
 
+/// Training cost dominated by parallel pair counting and its merge, once
+/// per `PairReduce` strategy.
+/// Enough distinct chunks are generated to take the parallel counting path.
+fn bench_train_counting(c: &mut Criterion) {
+    let texts: Vec<String> = (0..5_000)
+        .map(|i| format!("word{} token{} sample{} ", i, i * 7 % 1000, i * 13 % 5000))
+        .collect();
+
+    let mut group = c.benchmark_group("train");
+    group.sample_size(10);
+    for (label, reduce) in [("sharded", PairReduce::Sharded), ("tree", PairReduce::Tree)] {
+        group.bench_function(
+            BenchmarkId::new("train_from_iterator_5k_texts", label),
+            |b| {
+                Python::with_gil(|py| {
+                    let corpus = PyList::new_bound(py, &texts);
+                    b.iter(|| {
+                        let mut tok = Tokenizer::new().unwrap();
+                        tok.pair_reduce = reduce;
+                        tok.train_from_iterator(py, black_box(corpus.as_any()), 300, 1_000)
+                            .unwrap();
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_encode_by_length,
@@ -107,6 +145,7 @@ criterion_group!(
     bench_register_special_token,
     bench_get_merges,
     bench_load_merges,
+    bench_train_counting,
 );
 
 criterion_main!(benches);
@@ -1,22 +1,182 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
 
 use std::collections::HashMap as StdHashMap;
-use std::sync::Arc;
+use std::collections::HashSet as StdHashSet;
+use std::collections::VecDeque;
+use std::sync::{Arc, OnceLock};
 
 use ahash::{AHashMap, AHashSet};
 use compact_str::CompactString;
 use dary_heap::OctonaryHeap;
 use fancy_regex::Regex;
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
-use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use rayon::{current_num_threads, prelude::*};
+#[cfg(not(feature = "parallel"))]
+use sequential::*;
+
+#[cfg(feature = "python")]
+mod python;
+#[cfg(not(feature = "parallel"))]
+mod sequential;
+use serde::{Deserialize, Serialize};
 
 const GPT4_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+";
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const WHITESPACE_PATTERN: &str = r"\S+|\s+";
+/// ASCII approximation of `GPT4_PATTERN` with no lookaround or possessive
+/// quantifiers, so it runs on the plain `regex` engine.
+/// It matches GPT-4 on ASCII text except that trailing spaces before a word
+/// stay in the whitespace run (`"a  b"` gives `"  "`, `"b"`, not `" "`,
+/// `" b"`), and non-ASCII letters and digits are split off like punctuation.
+const ASCII_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\na-zA-Z0-9]?[a-zA-Z]+|[0-9]{1,3}| ?[^\sa-zA-Z0-9]+[\r\n]*|\s*[\r\n]|\s+";
+
+/// Pattern for `Tokenizer::new`: `ASCII_PATTERN` with the `ascii_pattern`
+/// feature, `GPT4_PATTERN` otherwise. The feature only changes this default
+/// (and so which engine runs it); fancy-regex stays linked either way.
+#[cfg(not(feature = "ascii_pattern"))]
+const DEFAULT_PATTERN: &str = GPT4_PATTERN;
+#[cfg(feature = "ascii_pattern")]
+const DEFAULT_PATTERN: &str = ASCII_PATTERN;
+
+/// Named pretokenization patterns accepted by `Tokenizer::with_preset`
+const PATTERN_PRESETS: &[(&str, &str)] = &[
+    ("gpt2", GPT2_PATTERN),
+    ("gpt4", GPT4_PATTERN),
+    ("llama", LLAMA3_PATTERN),
+    ("whitespace", WHITESPACE_PATTERN),
+    ("ascii", ASCII_PATTERN),
+];
 
 type Pair = (u32, u32);
 
+/// Leading byte of `to_bytes` output; bump when `TokenizerData` changes shape.
+const FORMAT_VERSION: u8 = 11;
+
 // Optimized chunk size for parallel processing
 const PARALLEL_CHUNK_SIZE: usize = 256;
 const MIN_PARALLEL_WORK: usize = 1000;
+// Token count above which decode_bytes expands slices in parallel
+const PARALLEL_DECODE_THRESHOLD: usize = 1 << 16;
+// Batch size from which encode_batch and encode_batch_flat go parallel
+const PARALLEL_BATCH_THRESHOLD: usize = 100;
+// Number of hash partitions used when merging parallel pair counts
+const PAIR_SHARDS: usize = 16;
+/// Removed before training and encoding with `strip_zero_width`: zero-width
+/// space, non-joiner, joiner and no-break space (U+FEFF, the BOM)
+const ZERO_WIDTH_CHARS: [char; 4] = ['\u{200b}', '\u{200c}', '\u{200d}', '\u{feff}'];
+/// Longest byte expansion a token may have (1 MiB); a merge table can
+/// double a token's length with every merge, so longer ones are refused
+const MAX_TOKEN_BYTES: usize = 1 << 20;
+/// Longest output one decode call produces (1 GiB), checked before any
+/// bytes are written
+const MAX_DECODE_BYTES: usize = 1 << 30;
+
+/// Heap pops without a recorded merge before `train_core` dumps the heap top
+#[cfg(feature = "trace")]
+const TRACE_STALL_POPS: usize = 100_000;
+/// Heap entries shown per dump
+#[cfg(feature = "trace")]
+const TRACE_TOP_K: usize = 10;
+/// Texts (or words, when counting pairs) between counting progress lines
+#[cfg(feature = "trace")]
+const TRACE_COUNT_EVERY: u64 = 100_000;
+
+/// Merges between checks of `max_merges_per_second`
+const THROTTLE_CHECK_EVERY: u32 = 64;
+
+/// Default `max_chunk_bytes`
+const DEFAULT_MAX_CHUNK_BYTES: usize = 1 << 16;
+
+/// Pair frequencies plus the word indices each pair occurs in
+type PairCounts = (AHashMap<Pair, i32>, AHashMap<Pair, AHashSet<usize>>);
+
+#[inline(always)]
+fn pair_shard(pair: Pair) -> usize {
+    let h = (((pair.0 as u64) << 32) | pair.1 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    (h >> 32) as usize % PAIR_SHARDS
+}
+
+/// `Tokenizer::apply_merges` with an explicit merge table
+#[inline]
+fn apply_merges_of(
+    merges: &StdHashMap<Pair, u32>,
+    ids: &mut Vec<u32>,
+    mut on_merge: impl FnMut(usize, u32),
+) {
+    while ids.len() >= 2 {
+        let mut best_idx = None;
+        let mut best_merge_id = u32::MAX;
+
+        // Find earliest merge (lowest ID = earliest in training)
+        for i in 0..ids.len() - 1 {
+            let pair = (at(ids, i), at(ids, i + 1));
+
+            if let Some(&merge_id) = merges.get(&pair) {
+                if merge_id < best_merge_id {
+                    best_merge_id = merge_id;
+                    best_idx = Some(i);
+                }
+            }
+        }
+
+        if let Some(idx) = best_idx {
+            on_merge(idx, best_merge_id);
+            ids[idx] = best_merge_id;
+            ids.remove(idx + 1);
+        } else {
+            break;
+        }
+    }
+}
+
+/// Hot-loop indexing: unchecked by default, bounds-checked with the `safe`
+/// feature (for Miri/ASan runs). Every call site's index is in range.
+#[inline(always)]
+fn at<T: Copy>(slice: &[T], i: usize) -> T {
+    #[cfg(feature = "safe")]
+    {
+        slice[i]
+    }
+    #[cfg(not(feature = "safe"))]
+    unsafe {
+        *slice.get_unchecked(i)
+    }
+}
+
+#[inline(always)]
+fn at_mut<T>(slice: &mut [T], i: usize) -> &mut T {
+    #[cfg(feature = "safe")]
+    {
+        &mut slice[i]
+    }
+    #[cfg(not(feature = "safe"))]
+    unsafe {
+        slice.get_unchecked_mut(i)
+    }
+}
+
+/// SplitMix64, a small seeded generator for reproducible sampling
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
 
 /// Represents a single word/chunk being processed.
 /// Optimized with inline hints and better memory layout.
@@ -47,17 +207,17 @@ impl Word {
         }
 
         // Pre-allocate with exact upper bounds
-        let mut out = Vec::with_capacity((n + 1) / 2);
+        let mut out = Vec::with_capacity(n.div_ceil(2));
         let mut deltas = Vec::with_capacity(6);
 
         let mut i = 0;
         while i < n {
             // Check for merge opportunity
-            if i + 1 < n
-                && unsafe { *self.ids.get_unchecked(i) } == a
-                && unsafe { *self.ids.get_unchecked(i + 1) } == b
-            {
-                // Left neighbor update
+            if i + 1 < n && at(&self.ids, i) == a && at(&self.ids, i + 1) == b {
+                // Left neighbor update. With `a == b` (`aaaa`), `prev` can be
+                // the `new_id` just pushed: the match before it already
+                // counted `(new_id, a)` as its right neighbor, which this
+                // cancels
                 if let Some(&prev) = out.last() {
                     deltas.push(((prev, a), -1));
                     deltas.push(((prev, new_id), 1));
@@ -68,7 +228,7 @@ impl Word {
 
                 // Right neighbor update
                 if i + 2 < n {
-                    let next = unsafe { *self.ids.get_unchecked(i + 2) };
+                    let next = at(&self.ids, i + 2);
                     deltas.push(((b, next), -1));
                     deltas.push(((new_id, next), 1));
                 }
@@ -76,7 +236,7 @@ impl Word {
                 out.push(new_id);
                 i += 2;
             } else {
-                out.push(unsafe { *self.ids.get_unchecked(i) });
+                out.push(at(&self.ids, i));
                 i += 1;
             }
         }
@@ -91,13 +251,15 @@ impl Word {
 struct MergeJob {
     pair: Pair,
     count: u64,
+    /// From `tie_key`; only consulted when counts are equal
+    tie: u64,
     pos: AHashSet<usize>,
 }
 
 impl PartialEq for MergeJob {
     #[inline(always)]
     fn eq(&self, other: &Self) -> bool {
-        self.count == other.count && self.pair == other.pair
+        self.count == other.count && self.tie == other.tie && self.pair == other.pair
     }
 }
 
@@ -111,395 +273,5310 @@ impl PartialOrd for MergeJob {
 impl Ord for MergeJob {
     #[inline(always)]
     fn cmp(&self, other: &Self) -> Ordering {
-        match self.count.cmp(&other.count) {
-            Ordering::Equal => other.pair.cmp(&self.pair),
-            other => other,
-        }
+        self.count
+            .cmp(&other.count)
+            .then_with(|| other.tie.cmp(&self.tie))
+            .then_with(|| other.pair.cmp(&self.pair))
     }
 }
 
-// Main tokenizer class
-#[pyclass]
-pub struct Tokenizer {
-    pub merges: StdHashMap<Pair, u32>,
-    pub pattern: String,
-    compiled_pattern: Arc<Regex>,
-    pub special_tokens: StdHashMap<String, u32>,
+/// How often a pretokenized chunk occurs and where it was first seen,
+/// as (text index, chunk index within that text)
+#[derive(Clone, Copy, Debug)]
+struct ChunkStat {
+    count: i32,
+    first_seen: (u64, u32),
 }
 
-impl Tokenizer {
-    /// Optimized parallel pair counting with adaptive chunking
-    fn count_pairs_parallel(
-        words: &[Word],
-        counts: &[i32],
-    ) -> (AHashMap<Pair, i32>, AHashMap<Pair, AHashSet<usize>>) {
-        // Skip parallelization for small inputs
-        if words.len() < MIN_PARALLEL_WORK {
-            return Self::count_pairs_sequential(words, counts);
-        }
-
-        // Adaptive chunk size based on work size and thread count
-        let num_threads = rayon::current_num_threads();
-        let chunk_size = (words.len() / (num_threads * 4)).max(PARALLEL_CHUNK_SIZE);
+impl ChunkStat {
+    #[inline]
+    fn absorb(&mut self, other: ChunkStat) {
+        self.count = self.count.saturating_add(other.count);
+        self.first_seen = self.first_seen.min(other.first_seen);
+    }
+}
 
-        words
-            .par_chunks(chunk_size)
-            .enumerate()
-            .map(|(chunk_idx, chunk)| {
-                let base_idx = chunk_idx * chunk_size;
+type ChunkCounts = AHashMap<CompactString, ChunkStat>;
 
-                // [FIX 1] Explicit Type Annotation needed here
-                let mut local_pc: AHashMap<Pair, i32> = AHashMap::with_capacity(chunk.len() * 2);
-                let mut local_wtu: AHashMap<Pair, AHashSet<usize>> =
-                    AHashMap::with_capacity(chunk.len() * 2);
+/// `(added, removed, changed)` merges, as returned by [`Tokenizer::merge_diff`].
+pub type MergeDiff = (Vec<(Pair, u32)>, Vec<(Pair, u32)>, Vec<(Pair, u32, u32)>);
 
-                for (offset, w) in chunk.iter().enumerate() {
-                    let i = base_idx + offset;
-                    let count = unsafe { *counts.get_unchecked(i) };
+/// `(tokens, byte spans)`, as returned by [`Tokenizer::encode_with_offsets`].
+pub type TokenOffsets = (Vec<u32>, Vec<(usize, usize)>);
 
-                    if w.ids.len() >= 2 && count != 0 {
-                        for pair in w.pairs() {
-                            *local_pc.entry(pair).or_insert(0) += count;
-                            local_wtu
-                                .entry(pair)
-                                .or_insert_with(AHashSet::new)
-                                .insert(i);
-                        }
-                    }
+/// Append `bytes` as text, writing control characters and invalid UTF-8
+/// byte by byte as `\xNN`
+fn push_escaped(out: &mut String, bytes: &[u8]) {
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() {
+                let mut buf = [0u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("\\x{:02x}", b));
                 }
-                (local_pc, local_wtu)
-            })
-            .reduce(
-                || {
-                    (
-                        AHashMap::with_capacity(10000),
-                        AHashMap::with_capacity(10000),
-                    )
-                },
-                |(mut acc_pc, mut acc_wtu), (pc, wtu)| {
-                    for (k, v) in pc {
-                        *acc_pc.entry(k).or_insert(0) += v;
-                    }
-                    for (k, s) in wtu {
-                        acc_wtu.entry(k).or_insert_with(AHashSet::new).extend(s);
-                    }
-                    (acc_pc, acc_wtu)
-                },
-            )
+            } else {
+                out.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
     }
+}
 
-    /// Sequential version for small inputs avoiding parallelization overhead
-    #[inline]
-    fn count_pairs_sequential(
-        words: &[Word],
-        counts: &[i32],
-    ) -> (AHashMap<Pair, i32>, AHashMap<Pair, AHashSet<usize>>) {
-        let mut pair_counts = AHashMap::with_capacity(words.len() * 2);
-        let mut where_to_update = AHashMap::with_capacity(words.len() * 2);
-
-        for (i, w) in words.iter().enumerate() {
-            if w.ids.len() >= 2 && counts[i] != 0 {
-                for pair in w.pairs() {
-                    *pair_counts.entry(pair).or_insert(0) += counts[i];
-                    where_to_update
-                        .entry(pair)
-                        .or_insert_with(AHashSet::new)
-                        .insert(i);
+/// Append `bytes` as one line of text per `mode`; see `EscapeMode`
+fn push_escaped_line(out: &mut String, bytes: &[u8], mode: EscapeMode) {
+    let strict = mode == EscapeMode::Strict;
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                '\\' if strict => out.push_str("\\\\"),
+                c if c.is_control() || (strict && !c.is_ascii()) => {
+                    let mut buf = [0u8; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        out.push_str(&format!("\\x{:02x}", b));
+                    }
                 }
+                c => out.push(c),
             }
         }
-
-        (pair_counts, where_to_update)
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
     }
+}
 
-    /// Core BPE training loop
-    fn train_core(&mut self, mut words: Vec<Word>, counts: Vec<i32>, vocab_size: u32) {
-        assert!(vocab_size >= 256, "vocab_size must be >= 256");
-        let num_merges = vocab_size - 256;
-
-        // Initial pair counting
-        let (mut pair_counts, mut where_to_update) = Self::count_pairs_parallel(&words, &counts);
-
-        // Build priority queue
-        let mut heap = OctonaryHeap::with_capacity(pair_counts.len());
-        for (pair, pos) in where_to_update.drain() {
-            if let Some(&c) = pair_counts.get(&pair) {
-                if c > 0 {
-                    heap.push(MergeJob {
-                        pair,
-                        count: c as u64,
-                        pos,
-                    });
-                }
+/// Standard padded base64, as used by tiktoken vocab files
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let n = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
             }
         }
+    }
+    out
+}
 
-        // Pre-allocate merge storage
-        self.merges.reserve(num_merges as usize);
-        let mut merges_done = 0;
-
-        // Reusable buffer for local updates (avoid repeated allocations)
-        let mut local_updates = AHashMap::with_capacity(1000);
-
-        while merges_done < num_merges {
-            let Some(mut top) = heap.pop() else { break };
+/// Remove every occurrence of `marker` from `bytes`
+fn strip_marker(bytes: Vec<u8>, marker: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(marker) {
+            i += marker.len();
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    out
+}
 
-            // Lazy staleness check
-            let current = pair_counts.get(&top.pair).copied().unwrap_or(0);
-            if top.count != current as u64 {
-                if current > 0 {
-                    top.count = current as u64;
-                    heap.push(top);
-                }
-                continue;
-            }
+/// Word-boundary marker (e.g. SentencePiece's `▁`) added to every chunk
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct WordMarker {
+    marker: String,
+    /// Append instead of prepend
+    at_end: bool,
+}
 
-            // Record merge
-            let new_id = 256 + merges_done;
-            self.merges.insert(top.pair, new_id);
+impl WordMarker {
+    fn apply(&self, chunk: &str) -> CompactString {
+        let mut marked = CompactString::with_capacity(chunk.len() + self.marker.len());
+        if self.at_end {
+            marked.push_str(chunk);
+            marked.push_str(&self.marker);
+        } else {
+            marked.push_str(&self.marker);
+            marked.push_str(chunk);
+        }
+        marked
+    }
+}
 
-            // Clear and reuse local_updates buffer
-            local_updates.clear();
+/// Where `Tokenizer::normalize` removed bytes, as `(offset in the normalized
+/// text, bytes removed up to and including that point)`, for mapping offsets
+/// in the normalized text back to the original
+struct NormalizedOffsets(Vec<(usize, usize)>);
 
-            // Update affected words
-            for &word_idx in &top.pos {
-                let changes = words[word_idx].merge_pair(top.pair, new_id);
-                let word_count = counts[word_idx];
+impl NormalizedOffsets {
+    /// Bytes removed before normalized offset `n`, counting removals at `n`
+    /// only with `inclusive`
+    fn removed(&self, n: usize, inclusive: bool) -> usize {
+        let idx = self
+            .0
+            .partition_point(|&(pos, _)| pos < n || (inclusive && pos == n));
+        idx.checked_sub(1).map_or(0, |i| self.0[i].1)
+    }
 
-                for (pair, delta) in changes {
-                    let total_change = delta * word_count;
-                    *pair_counts.entry(pair).or_insert(0) += total_change;
+    /// Original offset of a span starting at `n`: removed bytes at `n` are
+    /// skipped
+    fn start(&self, n: usize) -> usize {
+        n + self.removed(n, true)
+    }
 
-                    if delta > 0 {
-                        local_updates
-                            .entry(pair)
-                            .or_insert_with(AHashSet::new)
-                            .insert(word_idx);
-                    }
-                }
-            }
+    /// Original offset of a span ending at `n`: removed bytes at `n` are left
+    /// out
+    fn end(&self, n: usize) -> usize {
+        n + self.removed(n, false)
+    }
+}
 
-            // Push updated pairs to heap
-            for (pair, pos) in local_updates.drain() {
-                if let Some(&cnt) = pair_counts.get(&pair) {
-                    if cnt > 0 {
-                        heap.push(MergeJob {
-                            pair,
-                            count: cnt as u64,
-                            pos,
-                        });
-                    }
-                }
-            }
+/// Pattern matches of a text as `(start, chunk)`, with whitespace-only
+/// matches cut into pieces of at most `whitespace_width` chars (0 = no limit)
+/// and, with `split_space`, a leading `' '` cut off of other matches
+struct Chunks<'r, 't> {
+    matches: PatternMatches<'r, 't>,
+    whitespace_width: usize,
+    split_space: bool,
+    /// Unsplit tail of the current match
+    rest: Option<(usize, &'t str)>,
+}
 
-            merges_done += 1;
+impl<'r, 't> Chunks<'r, 't> {
+    fn new(
+        pattern: &'r CompiledPattern,
+        text: &'t str,
+        whitespace_width: usize,
+        split_space: bool,
+    ) -> Self {
+        Chunks {
+            matches: pattern.find_iter(text),
+            whitespace_width,
+            split_space,
+            rest: None,
         }
     }
 }
 
-// python interface
-#[pymethods]
-impl Tokenizer {
-    #[new]
-    pub fn new() -> PyResult<Self> {
-        let compiled_pattern = Regex::new(GPT4_PATTERN).map_err(|e| {
-            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
-                "Failed to compile regex: {}",
-                e
-            ))
-        })?;
+impl<'t> Iterator for Chunks<'_, 't> {
+    type Item = Result<(usize, &'t str), Box<fancy_regex::Error>>;
 
-        Ok(Self {
-            merges: StdHashMap::with_capacity(50000),
-            pattern: GPT4_PATTERN.to_string(),
-            compiled_pattern: Arc::new(compiled_pattern),
-            special_tokens: StdHashMap::new(),
-        })
+    fn next(&mut self) -> Option<Self::Item> {
+        let (start, chunk) = match self.rest.take() {
+            Some(rest) => rest,
+            None => match self.matches.next()? {
+                Ok(m) => m,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+
+        if self.whitespace_width > 0 && chunk.chars().all(char::is_whitespace) {
+            if let Some((split, _)) = chunk.char_indices().nth(self.whitespace_width) {
+                self.rest = Some((start + split, &chunk[split..]));
+                return Some(Ok((start, &chunk[..split])));
+            }
+        }
+        if self.split_space
+            && chunk.starts_with(' ')
+            && chunk[1..].starts_with(|c: char| !c.is_whitespace())
+        {
+            self.rest = Some((start + 1, &chunk[1..]));
+            return Some(Ok((start, " ")));
+        }
+        Some(Ok((start, chunk)))
     }
+}
 
-    pub fn register_special_token(&mut self, token: String, id: u32) {
-        self.special_tokens.insert(token, id);
+/// Pretokenize one text into chunk stats, each occurrence weighing `weight`
+fn count_text(
+    chunks: Chunks<'_, '_>,
+    marker: Option<&WordMarker>,
+    text_idx: u64,
+    weight: i32,
+) -> Result<ChunkCounts, Box<fancy_regex::Error>> {
+    let mut local_map = ChunkCounts::with_capacity(128);
+    for (chunk_idx, m) in chunks.enumerate() {
+        let stat = ChunkStat {
+            count: weight,
+            first_seen: (text_idx, chunk_idx as u32),
+        };
+        let (_, chunk) = m?;
+        let key = marker.map_or_else(|| CompactString::from(chunk), |w| w.apply(chunk));
+        local_map
+            .entry(key)
+            .and_modify(|s| s.absorb(stat))
+            .or_insert(stat);
     }
+    Ok(local_map)
+}
 
-    /// Main training entry point - heavily optimized for throughput
-    #[pyo3(signature = (iterator, vocab_size, buffer_size=10_000))]
-    pub fn train_from_iterator(
-        &mut self,
-        py: Python<'_>,
-        iterator: &Bound<'_, PyAny>,
-        vocab_size: u32,
-        buffer_size: usize,
-    ) -> PyResult<()> {
-        let mut py_iter = iterator.iter()?;
-
-        // Pre-sized for large datasets
-        let mut global_counts = AHashMap::with_capacity(200_000);
-        let mut buffer = Vec::with_capacity(buffer_size);
-
-        // Clone Arc for parallel use
-        let pattern = Arc::clone(&self.compiled_pattern);
-
-        loop {
-            buffer.clear();
-
-            // Fill buffer (holds GIL)
-            let mut exhausted = false;
-            for _ in 0..buffer_size {
-                match py_iter.next() {
-                    Some(Ok(item)) => buffer.push(item.extract::<String>()?),
-                    Some(Err(e)) => return Err(e),
-                    None => {
-                        exhausted = true;
-                        break;
-                    }
-                }
-            }
+/// Pretokenization settings `count_texts` applies to every text; the
+/// default counts bare pattern matches
+#[derive(Clone, Copy, Default)]
+struct CountOptions<'a> {
+    whitespace_chunk_width: usize,
+    split_leading_space: bool,
+    word_marker: Option<&'a WordMarker>,
+}
 
-            if buffer.is_empty() {
-                break;
-            }
+/// Pretokenize `texts` in parallel into chunk stats, normalizing each
+/// first and numbering them from `first_text`. The counting behind both
+/// training (`Tokenizer::count_batch`) and `Tokenizer::count_corpus`.
+fn count_texts<S, N>(
+    texts: &[S],
+    pattern: &CompiledPattern,
+    normalize: N,
+    options: CountOptions<'_>,
+    first_text: u64,
+    capacity: usize,
+) -> Result<ChunkCounts, Box<fancy_regex::Error>>
+where
+    S: AsRef<str> + Sync,
+    N: for<'t> Fn(&'t str) -> Cow<'t, str> + Sync,
+{
+    // Starts at `first_text` so progress counts texts across batches
+    #[cfg(feature = "trace")]
+    let texts_done = std::sync::atomic::AtomicU64::new(first_text);
 
-            // Process batch in parallel (releases GIL)
-            let batch_counts = py.allow_threads(|| {
-                buffer
-                    .par_iter()
-                    .map(|text| {
-                        // [FIX 2] Explicit Type Annotation needed here
-                        let mut local_map: AHashMap<CompactString, i32> =
-                            AHashMap::with_capacity(128);
-
-                        for m in pattern.find_iter(text) {
-                            if let Ok(m) = m {
-                                *local_map
-                                    .entry(CompactString::from(m.as_str()))
-                                    .or_insert(0) += 1;
-                            }
-                        }
-                        local_map
-                    })
-                    .reduce(
-                        || AHashMap::with_capacity(2048),
-                        |mut a, b| {
-                            for (k, v) in b {
-                                *a.entry(k).or_insert(0) += v;
-                            }
-                            a
-                        },
-                    )
-            });
+    let counts = texts
+        .par_iter()
+        .enumerate()
+        .map(|(i, text)| {
+            let text = normalize(text.as_ref());
+            let chunks = Chunks::new(
+                pattern,
+                &text,
+                options.whitespace_chunk_width,
+                options.split_leading_space,
+            );
+            let counts = count_text(chunks, options.word_marker, first_text + i as u64, 1);
+            #[cfg(feature = "trace")]
+            trace_count_progress(&texts_done, 1, "texts");
+            counts
+        })
+        .try_reduce(
+            || ChunkCounts::with_capacity(capacity),
+            |a, b| Ok(merge_chunk_counts(a, b)),
+        );
 
-            // Merge into global counts
-            for (k, v) in batch_counts {
-                *global_counts.entry(k).or_insert(0) += v;
-            }
+    #[cfg(feature = "trace")]
+    if let Ok(counts) = &counts {
+        eprintln!(
+            "[count] batch of {} texts: distinct_chunks={}",
+            texts.len(),
+            counts.len()
+        );
+    }
+    counts
+}
 
-            if exhausted {
-                break;
-            }
-        }
+/// Fold `b` into `a`, keeping the earliest first sighting of each chunk
+fn merge_chunk_counts(mut a: ChunkCounts, b: ChunkCounts) -> ChunkCounts {
+    for (k, v) in b {
+        a.entry(k).and_modify(|s| s.absorb(v)).or_insert(v);
+    }
+    a
+}
 
-        // Convert to training format
-        let capacity = global_counts.len();
-        let mut words = Vec::with_capacity(capacity);
-        let mut counts_vec = Vec::with_capacity(capacity);
+/// Serializable snapshot of everything needed to rebuild a `Tokenizer`.
+/// Maps are stored as sorted vectors so the encoding is deterministic.
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenizerData {
+    pattern: String,
+    merges: Vec<(u32, u32, u32)>,
+    special_tokens: Vec<(String, u32)>,
+    /// `None` for the identity byte mapping
+    initial_alphabet: Option<Vec<u32>>,
+    /// Special tokens registered as case-insensitive, sorted
+    case_insensitive_specials: Vec<String>,
+    /// Pattern specials in priority order
+    special_patterns: Vec<(String, u32)>,
+    word_marker: Option<WordMarker>,
+    whitespace_chunk_width: u32,
+    literal_tokens: Vec<(String, u32)>,
+    split_leading_space: bool,
+    strip_bom: bool,
+    strip_zero_width: bool,
+    allowed_bytes: Option<Vec<bool>>,
+    unk_id: u32,
+    byte_level_only: bool,
+}
 
-        for (token_str, count) in global_counts {
-            let ids: Vec<u32> = token_str.bytes().map(|b| b as u32).collect();
-            words.push(Word::new(ids));
-            counts_vec.push(count);
-        }
+/// First line of a `Tokenizer::save` file; `tokenizer.merges` is empty and
+/// the merges follow, one per line
+#[derive(Serialize, Deserialize)]
+struct SaveHeader {
+    format_version: u8,
+    merge_count: usize,
+    tokenizer: TokenizerData,
+}
 
-        // Run BPE
-        self.train_core(words, counts_vec, vocab_size);
-        Ok(())
+/// Identity mapping: byte `b` is base token `b`
+const IDENTITY_ALPHABET: [u32; 256] = {
+    let mut ids = [0u32; 256];
+    let mut b = 0;
+    while b < 256 {
+        ids[b] = b as u32;
+        b += 1;
     }
+    ids
+};
 
-    /// Optimized encoding with better merge selection
-    pub fn encode(&self, text: &str) -> Vec<u32> {
-        let mut result = Vec::with_capacity(text.len() / 4);
+/// Error from a fallible `Tokenizer` method; raised in Python as the
+/// matching exception (see `python.rs`)
+#[derive(Debug)]
+pub enum TokenizerError {
+    /// Invalid argument or data; `ValueError` in Python
+    Value(String),
+    /// Reading or writing a checkpoint failed; `OSError` in Python
+    Io(std::io::Error),
+}
 
-        for m in self.compiled_pattern.find_iter(text) {
-            if let Ok(m) = m {
-                let chunk = m.as_str();
+pub type TokenizerResult<T> = Result<T, TokenizerError>;
 
-                // Fast path for special tokens
-                if let Some(&id) = self.special_tokens.get(chunk) {
-                    result.push(id);
-                    continue;
-                }
+impl std::fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenizerError::Value(msg) => f.write_str(msg),
+            TokenizerError::Io(e) => e.fmt(f),
+        }
+    }
+}
 
-                // Convert to token IDs
-                let mut ids: Vec<u32> = chunk.bytes().map(|b| b as u32).collect();
+impl std::error::Error for TokenizerError {}
 
-                // Iterative merging
-                while ids.len() >= 2 {
-                    let mut best_idx = None;
-                    let mut best_merge_id = u32::MAX;
+impl From<std::io::Error> for TokenizerError {
+    fn from(e: std::io::Error) -> Self {
+        TokenizerError::Io(e)
+    }
+}
 
-                    // Find earliest merge (lowest ID = earliest in training)
-                    for i in 0..ids.len() - 1 {
-                        let pair = unsafe { (*ids.get_unchecked(i), *ids.get_unchecked(i + 1)) };
+fn compile_pattern(pattern: &str) -> TokenizerResult<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| TokenizerError::Value(format!("Failed to compile regex: {}", e)))
+}
 
-                        if let Some(&merge_id) = self.merges.get(&pair) {
-                            if merge_id < best_merge_id {
-                                best_merge_id = merge_id;
-                                best_idx = Some(i);
-                            }
-                        }
-                    }
+/// Which regex engine runs the pretokenization pattern
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PatternEngine {
+    /// The plain `regex` engine when the pattern compiles there (no
+    /// lookaround, backreferences or possessive quantifiers), `fancy_regex`
+    /// otherwise
+    #[default]
+    Auto,
+    /// Always `fancy_regex`
+    Fancy,
+}
 
-                    if let Some(idx) = best_idx {
-                        ids[idx] = best_merge_id;
-                        ids.remove(idx + 1);
-                    } else {
-                        break;
-                    }
-                }
+/// The pretokenization pattern, compiled for the engine `PatternEngine`
+/// picked. Both engines give the same matches for a pattern `regex` accepts.
+#[derive(Debug)]
+enum CompiledPattern {
+    Fancy(Regex),
+    Fast(regex::Regex),
+}
 
-                result.extend(ids);
+impl CompiledPattern {
+    fn new(pattern: &str, engine: PatternEngine) -> TokenizerResult<Self> {
+        if engine == PatternEngine::Auto {
+            if let Ok(regex) = regex::Regex::new(pattern) {
+                return Ok(CompiledPattern::Fast(regex));
             }
         }
-        result
+        compile_pattern(pattern).map(CompiledPattern::Fancy)
     }
 
-    /// Batch encoding for better throughput
-    pub fn encode_batch(&self, texts: Vec<String>) -> Vec<Vec<u32>> {
-        if texts.len() < 100 {
-            // Sequential for small batches
-            texts.iter().map(|t| self.encode(t)).collect()
-        } else {
-            // Parallel for large batches
-            texts.par_iter().map(|t| self.encode(t)).collect()
+    fn find_iter<'r, 't>(&'r self, text: &'t str) -> PatternMatches<'r, 't> {
+        match self {
+            CompiledPattern::Fancy(regex) => PatternMatches::Fancy(regex.find_iter(text)),
+            CompiledPattern::Fast(regex) => PatternMatches::Fast(regex.find_iter(text)),
         }
     }
+}
 
-    /// Export merges for serialization
-    pub fn get_merges(&self) -> StdHashMap<(u32, u32), u32> {
-        self.merges.clone()
-    }
+/// `CompiledPattern::find_iter` matches as `(start, match)`; only
+/// `fancy_regex` can fail at runtime
+enum PatternMatches<'r, 't> {
+    Fancy(fancy_regex::Matches<'r, 't>),
+    Fast(regex::Matches<'r, 't>),
+}
 
-    /// Load pre-trained merges
-    pub fn load_merges(&mut self, merges: StdHashMap<(u32, u32), u32>) {
-        self.merges = merges;
-    }
+impl<'t> Iterator for PatternMatches<'_, 't> {
+    type Item = Result<(usize, &'t str), Box<fancy_regex::Error>>;
 
-    /// Get vocabulary size
-    pub fn vocab_size(&self) -> usize {
-        256 + self.merges.len()
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            PatternMatches::Fancy(matches) => Some(
+                matches
+                    .next()?
+                    .map(|m| (m.start(), m.as_str()))
+                    .map_err(Box::new),
+            ),
+            PatternMatches::Fast(matches) => matches.next().map(|m| Ok((m.start(), m.as_str()))),
+        }
     }
 }
 
-impl Default for Tokenizer {
+/// Initial capacities for the tokenizer's hash maps.
+/// Defaults preserve the previously hardcoded sizes; shrink them for tiny
+/// vocabularies or grow them to avoid rehashing on huge corpora.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityHints {
+    /// Expected number of merges (target vocab size minus the 256 base bytes)
+    pub merges: usize,
+    /// Expected distinct pretokenized chunks across the training corpus
+    pub unique_chunks: usize,
+    /// Expected distinct chunks within one training batch
+    pub batch_chunks: usize,
+}
+
+impl Default for CapacityHints {
     fn default() -> Self {
-        Self::new().unwrap()
+        Self {
+            merges: 50_000,
+            unique_chunks: 200_000,
+            batch_chunks: 2048,
+        }
     }
 }
-// Rust unit tests are in `src/tests.rs`
-#[cfg(test)]
-mod tests;
 
-#[pymodule]
-fn rust_tokenizer(_: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
-    m.add_class::<Tokenizer>()?;
-    Ok(())
+/// How long `merges` merges should take at `rate` merges per second. Rates
+/// so small that this overflows a `Duration` saturate instead of panicking.
+fn throttle_due(merges: u32, rate: f64) -> std::time::Duration {
+    std::time::Duration::try_from_secs_f64(merges as f64 / rate).unwrap_or(std::time::Duration::MAX)
+}
+
+/// Whether merge `id` falls outside the first `max_merges` merges
+fn merge_past_limit(id: u32, max_merges: Option<usize>) -> bool {
+    max_merges.is_some_and(|max| id as u64 >= 256 + max as u64)
 }
+
+/// Check that merge IDs are unique and exactly cover `256..256 + len`
+fn check_merge_ids(merges: &StdHashMap<Pair, u32>) -> Result<(), String> {
+    let mut ids: Vec<(u32, Pair)> = merges.iter().map(|(&pair, &id)| (id, pair)).collect();
+    ids.sort_unstable();
+
+    for (expected, &(id, pair)) in (256u32..).zip(ids.iter()) {
+        if id < expected {
+            return Err(format!(
+                "Duplicate merge ID {} (assigned to {:?} and another pair)",
+                id, pair
+            ));
+        }
+        if id != expected {
+            return Err(format!(
+                "Merge IDs must be contiguous from 256: expected {}, found {} for {:?}",
+                expected, id, pair
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Lookup tables derived from `merges` and `special_tokens`, built on first use.
+/// Reset by `Tokenizer::clear_caches` whenever either map changes.
+#[derive(Default, Debug)]
+struct VocabCache {
+    /// Byte expansion of every token ID, indexed by ID
+    bytes: OnceLock<TokenTable>,
+    /// Special token ID -> its literal string
+    special_by_id: OnceLock<StdHashMap<u32, String>>,
+    /// Byte expansion -> token ID, plus the longest expansion length
+    id_by_bytes: OnceLock<(AHashMap<Vec<u8>, u32>, usize)>,
+    /// Matcher over all special tokens, `None` when none are registered
+    special_matcher: OnceLock<Option<SpecialMatcher>>,
+    /// IDs appearing as the left and as the right part of some merge
+    merge_sides: OnceLock<(AHashSet<u32>, AHashSet<u32>)>,
+}
+
+/// A clone starts empty and rebuilds its tables on first use
+impl Clone for VocabCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Byte expansions of all token IDs, built bottom-up in merge order
+#[derive(Debug)]
+struct TokenTable {
+    /// Indexed by ID; empty for unused IDs and for `oversized` ones
+    bytes: Vec<Vec<u8>>,
+    /// Sorted IDs whose expansion would exceed `MAX_TOKEN_BYTES`
+    oversized: Vec<u32>,
+}
+
+/// Alternation matching any of `tokens`, longest first so the leftmost
+/// match is also the longest; `None` if there are no non-empty tokens
+fn literal_alternation<'a>(
+    tokens: impl Iterator<Item = &'a String>,
+    case_insensitive: &StdHashSet<String>,
+) -> Option<Regex> {
+    let mut tokens: Vec<&String> = tokens.filter(|s| !s.is_empty()).collect();
+    if tokens.is_empty() {
+        return None;
+    }
+    tokens.sort_unstable_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+    let alternatives: Vec<String> = tokens
+        .iter()
+        .map(|s| {
+            let escaped = fancy_regex::escape(s);
+            if case_insensitive.contains(*s) {
+                format!("(?i:{})", escaped)
+            } else {
+                escaped.into_owned()
+            }
+        })
+        .collect();
+    Some(Regex::new(&alternatives.join("|")).expect("escaped literals always compile"))
+}
+
+/// Finds special tokens in raw text: registered literals (longest first),
+/// `register_special_pattern` regexes, and protected `literal_tokens`
+#[derive(Debug)]
+struct SpecialMatcher {
+    /// Alternation of all literal specials
+    literals: Option<Regex>,
+    /// Lowercased case-insensitive token -> its registered ID
+    folded: AHashMap<String, u32>,
+    /// Special patterns in priority (registration) order
+    patterns: Vec<(Regex, u32)>,
+    /// Alternation of all `literal_tokens`
+    protected: Option<Regex>,
+}
+
+impl SpecialMatcher {
+    /// Number of independent searches: the literal specials, each pattern,
+    /// then the protected literals. Lower candidates win ties.
+    fn candidates(&self) -> usize {
+        2 + self.patterns.len()
+    }
+
+    /// Candidates that are special tokens (all but the protected literals)
+    fn special_candidates(&self) -> usize {
+        1 + self.patterns.len()
+    }
+
+    /// Next non-empty match of candidate `which` starting at or after
+    /// `from`, as `(id, start, end)`; `Err` if the regex fails at runtime
+    /// (e.g. a special pattern hits the backtrack limit)
+    fn find(
+        &self,
+        tokenizer: &Tokenizer,
+        text: &str,
+        mut from: usize,
+        which: usize,
+    ) -> Result<Option<(u32, usize, usize)>, Box<fancy_regex::Error>> {
+        let protected = which == self.candidates() - 1;
+        let (regex, pattern_id) = match which {
+            0 => match &self.literals {
+                Some(regex) => (regex, None),
+                None => return Ok(None),
+            },
+            _ if protected => match &self.protected {
+                Some(regex) => (regex, None),
+                None => return Ok(None),
+            },
+            i => {
+                let (regex, id) = &self.patterns[i - 1];
+                (regex, Some(*id))
+            }
+        };
+
+        while from <= text.len() {
+            let Some(m) = regex.find_from_pos(text, from).map_err(Box::new)? else {
+                return Ok(None);
+            };
+            if m.start() == m.end() {
+                from = m.end() + text[m.end()..].chars().next().map_or(1, char::len_utf8);
+                continue;
+            }
+            let id = if protected {
+                tokenizer.literal_tokens.get(m.as_str()).copied()
+            } else {
+                pattern_id
+                    .or_else(|| tokenizer.special_tokens.get(m.as_str()).copied())
+                    .or_else(|| self.folded.get(&m.as_str().to_lowercase()).copied())
+            };
+            match id {
+                Some(id) => return Ok(Some((id, m.start(), m.end()))),
+                None => from = m.end(),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A piece of input text after special-token splitting
+enum Segment<'t> {
+    /// Ordinary text starting at the given byte offset
+    Text(usize, &'t str),
+    /// A special token ID and its byte span in the original text
+    Special(u32, usize, usize),
+}
+
+/// Iterator behind `Tokenizer::try_segments`. A special pattern's runtime
+/// error is yielded once and ends the iteration.
+struct Segments<'a, 't> {
+    tokenizer: &'a Tokenizer,
+    matcher: Option<&'a SpecialMatcher>,
+    text: &'t str,
+    pos: usize,
+    pending: Option<(u32, usize, usize)>,
+    /// Each candidate's next match; refreshed once `pos` passes its start
+    upcoming: Vec<Option<(u32, usize, usize)>>,
+    /// Error from the initial search, reported by the first `next`
+    failed: Option<Box<fancy_regex::Error>>,
+}
+
+impl<'t> Iterator for Segments<'_, 't> {
+    type Item = Result<Segment<'t>, Box<fancy_regex::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.failed.take() {
+            self.pos = self.text.len();
+            return Some(Err(e));
+        }
+        if let Some((id, start, end)) = self.pending.take() {
+            self.pos = end;
+            return Some(Ok(Segment::Special(id, start, end)));
+        }
+        if self.pos >= self.text.len() {
+            return None;
+        }
+
+        let start = self.pos;
+        let mut found = None;
+        if let Some(matcher) = self.matcher {
+            for (which, slot) in self.upcoming.iter_mut().enumerate() {
+                if slot.is_some_and(|(_, s, _)| s < start) {
+                    match matcher.find(self.tokenizer, self.text, start, which) {
+                        Ok(next) => *slot = next,
+                        Err(e) => {
+                            self.pos = self.text.len();
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            }
+            // Earliest start wins; ties go to literals, then earlier patterns,
+            // then protected literals
+            found = self
+                .upcoming
+                .iter()
+                .flatten()
+                .min_by_key(|&&(_, s, _)| s)
+                .copied();
+        }
+
+        Some(Ok(match found {
+            Some((id, m_start, m_end)) if m_start == start => {
+                self.pos = m_end;
+                Segment::Special(id, m_start, m_end)
+            }
+            Some(special) => {
+                self.pending = Some(special);
+                Segment::Text(start, &self.text[start..special.1])
+            }
+            None => {
+                self.pos = self.text.len();
+                Segment::Text(start, &self.text[start..])
+            }
+        }))
+    }
+}
+
+/// How `encode_with_strategy` segments each pretokenized chunk
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EncodeStrategy {
+    /// Standard BPE: repeatedly apply the lowest-ID (earliest trained) merge
+    #[default]
+    EarliestMerge,
+    /// Greedy left-to-right: at each position take the longest vocab token
+    LongestToken,
+}
+
+/// Which pair training merges when several share the highest count
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// The lexicographically smaller `(left, right)` ID pair
+    #[default]
+    SmallerPair,
+    /// The pair whose merged token has the fewest bytes
+    ShorterToken,
+    /// The pair seen earliest in the corpus (by first chunk containing it)
+    ByFirstOccurrence,
+}
+
+/// Named rules that make training skip some merges
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeFilter {
+    /// Every pair may merge
+    #[default]
+    AllowAll,
+    /// Skip merges joining tokens of different scripts (see `Script`), so no
+    /// token mixes, say, Latin letters and CJK ideographs
+    NoCrossScript,
+}
+
+/// How parallel pair counting combines the counts of its worker tasks.
+/// Both give identical counts; which is faster depends on the corpus.
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PairReduce {
+    /// Each task splits its counts into `PAIR_SHARDS` maps by pair hash and
+    /// the shards are merged independently, in parallel
+    #[default]
+    Sharded,
+    /// Each task keeps one map, and maps are merged pairwise (the smaller
+    /// into the larger) in a log-depth tree
+    Tree,
+}
+
+/// What `decode_escaped` escapes
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EscapeMode {
+    /// `\n`, `\t` and `\r` by name, other control characters and invalid
+    /// UTF-8 as `\xNN`; everything else, non-ASCII included, as is
+    #[default]
+    Display,
+    /// Like `Display`, but also non-ASCII characters as `\xNN` per byte and
+    /// `\` as `\\`, so the output is plain ASCII and unambiguous
+    Strict,
+}
+
+/// What decoding does with an ID that is neither a base byte, a merge nor a
+/// registered special token, e.g. a special ID from a different config
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownIdPolicy {
+    /// Leave it out of the output
+    #[default]
+    Skip,
+    /// Emit `<unk_special:ID>` in its place
+    Placeholder,
+    /// Fail in `try_decode_bytes` and `try_decode` (plain `decode` skips it)
+    Error,
+}
+
+/// What `encode` does with a pretokenized chunk longer than
+/// `max_chunk_bytes`
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverlongChunkPolicy {
+    /// Run the merge loop over the whole chunk
+    #[default]
+    Full,
+    /// Merge each `max_chunk_bytes` window on its own; no token spans a
+    /// window boundary, so the result can differ from `Full` there
+    Windowed,
+    /// Fail in `try_encode` (plain `encode` panics)
+    Error,
+}
+
+/// Coarse Unicode script class of a token, for `MergeFilter::NoCrossScript`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    /// Digits, punctuation, whitespace and symbols; joins any script
+    Neutral,
+    Latin,
+    Greek,
+    Cyrillic,
+    Hebrew,
+    Arabic,
+    /// Han, kana and Hangul, plus CJK punctuation
+    Cjk,
+    /// Letters of any other script
+    Other,
+}
+
+impl Script {
+    fn of(c: char) -> Script {
+        match c as u32 {
+            0x41..=0x5a | 0x61..=0x7a | 0xc0..=0xd6 | 0xd8..=0xf6 | 0xf8..=0x24f => Script::Latin,
+            0x1e00..=0x1eff => Script::Latin,
+            0x370..=0x3ff | 0x1f00..=0x1fff => Script::Greek,
+            0x400..=0x52f => Script::Cyrillic,
+            0x590..=0x5ff => Script::Hebrew,
+            0x600..=0x6ff => Script::Arabic,
+            0x1100..=0x11ff | 0x2e80..=0x9fff | 0xac00..=0xd7af | 0xf900..=0xfaff => Script::Cjk,
+            0x20000..=0x3ffff => Script::Cjk,
+            _ if c.is_alphabetic() => Script::Other,
+            _ => Script::Neutral,
+        }
+    }
+
+    /// Script of the first non-neutral character in `bytes`. A character cut
+    /// off at the end is judged by its lowest possible code point, so a lone
+    /// lead byte already carries its script.
+    fn of_bytes(bytes: &[u8]) -> Script {
+        let mut i = 0;
+        while i < bytes.len() {
+            let lead = bytes[i];
+            let (width, mut cp) = match lead {
+                0x00..=0x7f => (1, lead as u32),
+                0xc2..=0xdf => (2, (lead & 0x1f) as u32),
+                0xe0..=0xef => (3, (lead & 0x0f) as u32),
+                0xf0..=0xf4 => (4, (lead & 0x07) as u32),
+                // Continuation or invalid bytes
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            for k in 1..width {
+                let next = bytes.get(i + k).copied().unwrap_or(0x80);
+                cp = cp << 6 | (next & 0x3f) as u32;
+            }
+            let script = char::from_u32(cp).map_or(Script::Neutral, Script::of);
+            if script != Script::Neutral {
+                return script;
+            }
+            i += 1;
+        }
+        Script::Neutral
+    }
+
+    fn clashes(self, other: Script) -> bool {
+        self != other && self != Script::Neutral && other != Script::Neutral
+    }
+}
+
+/// What registering a special token does when its string is already
+/// registered with a different ID
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpecialConflict {
+    /// Fail, registering nothing
+    Error,
+    /// Replace the old ID, like `register_special_token`
+    #[default]
+    Overwrite,
+    /// Keep the old ID
+    Ignore,
+}
+
+/// Secondary `MergeJob` key; lower wins among equal counts
+#[inline]
+fn tie_key(tie_break: TieBreak, token_lens: &[usize], pair: Pair, pos: &AHashSet<usize>) -> u64 {
+    match tie_break {
+        TieBreak::SmallerPair => 0,
+        TieBreak::ShorterToken => {
+            (token_lens[pair.0 as usize] + token_lens[pair.1 as usize]) as u64
+        }
+        TieBreak::ByFirstOccurrence => pos.iter().min().map_or(u64::MAX, |&i| i as u64),
+    }
+}
+
+/// Print the `TRACE_TOP_K` highest-count heap entries to stderr
+#[cfg(feature = "trace")]
+fn trace_heap(heap: &OctonaryHeap<MergeJob>, merges_done: u32, reason: &str) {
+    let mut top: Vec<(u64, Pair)> = heap.iter().map(|job| (job.count, job.pair)).collect();
+    top.sort_unstable_by(|a, b| b.cmp(a));
+    eprintln!(
+        "[train] {}: merges_done={} heap_len={}",
+        reason,
+        merges_done,
+        heap.len()
+    );
+    for (count, pair) in top.into_iter().take(TRACE_TOP_K) {
+        eprintln!("[train]   pair={:?} count={}", pair, count);
+    }
+}
+
+/// One counting progress line per `TRACE_COUNT_EVERY` items; `done` is
+/// shared by all workers, so the totals are across threads
+#[cfg(feature = "trace")]
+fn trace_count_progress(done: &std::sync::atomic::AtomicU64, items: u64, what: &str) {
+    use std::sync::atomic::Ordering::Relaxed;
+    let before = done.fetch_add(items, Relaxed);
+    if (before + items) / TRACE_COUNT_EVERY > before / TRACE_COUNT_EVERY {
+        eprintln!("[count] {}={}", what, before + items);
+    }
+}
+
+/// Training needs at least the 256 base tokens
+fn check_vocab_size(vocab_size: u32) -> Result<(), String> {
+    if vocab_size < 256 {
+        return Err(format!(
+            "vocab_size must be at least 256, got {}",
+            vocab_size
+        ));
+    }
+    Ok(())
+}
+
+/// Each merge removes at least one adjacent pair from some word, so a target
+/// needing more merges than `words` has pairs can never be reached
+fn check_vocab_reachable(
+    words: &[Word],
+    merges_so_far: usize,
+    vocab_size: u32,
+) -> Result<(), String> {
+    let available: usize = words.iter().map(|w| w.ids.len().saturating_sub(1)).sum();
+    let needed = (vocab_size as usize).saturating_sub(256 + merges_so_far);
+    if needed > available {
+        return Err(format!(
+            "vocab_size {} is unreachable: the corpus allows at most {} more merges (vocab_size {})",
+            vocab_size,
+            available,
+            256 + merges_so_far + available
+        ));
+    }
+    Ok(())
+}
+
+fn regex_error(e: Box<fancy_regex::Error>) -> TokenizerError {
+    TokenizerError::Value(format!("pretokenization regex failed: {}", e))
+}
+
+fn decode_too_long(len: usize) -> String {
+    format!(
+        "Decoded output of about {} bytes exceeds the {} byte limit",
+        len, MAX_DECODE_BYTES
+    )
+}
+
+fn special_regex_error(e: Box<fancy_regex::Error>) -> TokenizerError {
+    TokenizerError::Value(format!("special token regex failed: {}", e))
+}
+
+/// The panic behind the infallible wrappers of the `try_` encoders
+fn or_panic<T>(result: TokenizerResult<T>) -> T {
+    result.unwrap_or_else(|e| panic!("{}", e))
+}
+
+// Main tokenizer class
+#[derive(Clone)]
+pub struct Tokenizer {
+    pub merges: StdHashMap<Pair, u32>,
+    pub pattern: String,
+    compiled_pattern: Arc<CompiledPattern>,
+    pattern_engine: PatternEngine,
+    pub special_tokens: StdHashMap<String, u32>,
+    /// Protected vocabulary: strings always encoded as one fixed ID, matched
+    /// like specials but not special (see `register_literal_token`)
+    pub literal_tokens: StdHashMap<String, u32>,
+    /// Keep the pair-frequency map left over at the end of `train_core`.
+    /// Off by default since it can hold millions of entries.
+    pub retain_pair_counts: bool,
+    final_pair_counts: StdHashMap<Pair, i64>,
+    capacity_hints: CapacityHints,
+    cache: VocabCache,
+    last_train_unique_chunks: usize,
+    last_train_reached_target: bool,
+    /// Held-out texts encoded after each training run; see
+    /// `last_train_validation_ratio`
+    pub validation_texts: Vec<String>,
+    last_train_validation_ratio: Option<f64>,
+    /// Base token ID for each byte value; see `set_initial_alphabet`
+    byte_ids: [u32; 256],
+    /// When set, training periodically writes a `to_bytes` snapshot here
+    pub checkpoint_path: Option<String>,
+    /// Merges between checkpoints (0 disables checkpointing)
+    pub checkpoint_every: u32,
+    /// Ceiling on training speed for shared hardware: every
+    /// `THROTTLE_CHECK_EVERY` merges, training sleeps until it is back under
+    /// this rate, trading wall-clock time for lower CPU pressure. `None` (or
+    /// a non-positive rate) trains unthrottled. Timed with
+    /// `std::time::Instant`, which is unavailable on `wasm32-unknown-unknown`.
+    pub max_merges_per_second: Option<f64>,
+    /// Bound on training memory: only this many most frequent pairs are
+    /// kept in `pair_counts` and the heap; the rest are dropped for good,
+    /// and only pairs created by later merges are added. Counting first
+    /// tallies every pair without positions, then records word positions
+    /// for the kept pairs alone, so dropped pairs never get position sets.
+    /// Approximate: a dropped pair that would have risen above the cutoff as
+    /// merges reshape the words is never learned. `None` keeps all.
+    pub max_heap_pairs: Option<usize>,
+    /// Special tokens that match regardless of case
+    case_insensitive_specials: StdHashSet<String>,
+    /// Cut whitespace-only chunks into pieces of at most this many chars
+    /// (e.g. 4 for stable indentation tokens); 0 leaves them whole
+    pub whitespace_chunk_width: usize,
+    /// Emit the space before a word as its own `' '` chunk, so `" hello"`
+    /// and `"hello"` share one token
+    pub split_leading_space: bool,
+    /// Drop a U+FEFF byte order mark at the start of each text before
+    /// training or encoding; decode cannot bring it back. Methods reporting
+    /// byte offsets into the input (`encode_with_offsets`, `encode_budget`,
+    /// `encode_trace`) see the text as given.
+    pub strip_bom: bool,
+    /// Drop zero-width characters (`ZERO_WIDTH_CHARS`) anywhere in a text,
+    /// like `strip_bom`
+    pub strip_zero_width: bool,
+    /// Bytes encode may emit, and the ID any other byte encodes as; see
+    /// `set_allowed_bytes`
+    allowed_bytes: Option<([bool; 256], u32)>,
+    /// Encode to base byte IDs (and specials) only, skipping the merge loop
+    /// even if merges are loaded: a fast character-level baseline with the
+    /// same API. `vocab_size()` still counts merges; with none it is 256,
+    /// and `max_token_id() + 1` includes specials. Training ignores this.
+    pub byte_level_only: bool,
+    /// Marker added to every chunk for training and encoding; see `set_word_marker`
+    word_marker: Option<WordMarker>,
+    /// `(regex source, id)` specials in priority order; see `register_special_pattern`
+    special_patterns: Vec<(String, u32)>,
+    /// Training skips merges that would put two ASCII digits in one token
+    pub split_digits: bool,
+    /// How training picks among equally frequent pairs
+    pub tie_break: TieBreak,
+    /// Which merges training skips
+    pub merge_filter: MergeFilter,
+    /// How training merges parallel pair counts; a performance knob only
+    pub pair_reduce: PairReduce,
+    /// Batch sizes from which the methods taking many texts (`encode_batch`,
+    /// `token_histogram`, `specialize` and the like) run in parallel
+    /// (default `PARALLEL_BATCH_THRESHOLD`); results are the same either way
+    pub parallel_threshold: usize,
+    /// What decoding does with IDs this tokenizer doesn't know
+    pub unknown_id_policy: UnknownIdPolicy,
+    /// What `encode` does with chunks longer than `max_chunk_bytes`
+    pub overlong_chunk_policy: OverlongChunkPolicy,
+    /// Chunk length in bytes (word marker included) above which
+    /// `overlong_chunk_policy` applies, and its window size
+    pub max_chunk_bytes: usize,
+}
+
+impl Tokenizer {
+    pub fn new() -> TokenizerResult<Self> {
+        Self::with_capacity_hints(CapacityHints::default())
+    }
+
+    /// Construct with explicit sizing for the internal maps
+    pub fn with_capacity_hints(hints: CapacityHints) -> TokenizerResult<Self> {
+        let compiled_pattern = CompiledPattern::new(DEFAULT_PATTERN, PatternEngine::Auto)?;
+
+        Ok(Self {
+            merges: StdHashMap::with_capacity(hints.merges),
+            pattern: DEFAULT_PATTERN.to_string(),
+            compiled_pattern: Arc::new(compiled_pattern),
+            pattern_engine: PatternEngine::Auto,
+            special_tokens: StdHashMap::new(),
+            literal_tokens: StdHashMap::new(),
+            retain_pair_counts: false,
+            final_pair_counts: StdHashMap::new(),
+            capacity_hints: hints,
+            checkpoint_path: None,
+            checkpoint_every: 1000,
+            max_merges_per_second: None,
+            max_heap_pairs: None,
+            cache: VocabCache::default(),
+            last_train_unique_chunks: 0,
+            last_train_reached_target: false,
+            validation_texts: Vec::new(),
+            last_train_validation_ratio: None,
+            byte_ids: IDENTITY_ALPHABET,
+            case_insensitive_specials: StdHashSet::new(),
+            whitespace_chunk_width: 0,
+            split_leading_space: false,
+            strip_bom: false,
+            strip_zero_width: false,
+            allowed_bytes: None,
+            byte_level_only: false,
+            word_marker: None,
+            special_patterns: Vec::new(),
+            split_digits: false,
+            tie_break: TieBreak::default(),
+            merge_filter: MergeFilter::default(),
+            pair_reduce: PairReduce::default(),
+            parallel_threshold: PARALLEL_BATCH_THRESHOLD,
+            unknown_id_policy: UnknownIdPolicy::default(),
+            overlong_chunk_policy: OverlongChunkPolicy::default(),
+            max_chunk_bytes: DEFAULT_MAX_CHUNK_BYTES,
+        })
+    }
+
+    /// Drop the lazily built decode tables. Called by every method that edits
+    /// `merges`, `special_tokens` or `literal_tokens`; call it yourself after
+    /// editing those fields directly.
+    pub fn clear_caches(&mut self) {
+        self.cache = VocabCache::default();
+    }
+
+    /// `text` with `strip_bom` and `strip_zero_width` applied
+    fn normalize<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let text = if self.strip_bom {
+            text.strip_prefix('\u{feff}').unwrap_or(text)
+        } else {
+            text
+        };
+        if self.strip_zero_width && text.contains(ZERO_WIDTH_CHARS) {
+            Cow::Owned(text.replace(ZERO_WIDTH_CHARS, ""))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// `normalize` with the removals it made, so offsets into the result can
+    /// be reported against `text`
+    fn normalize_with_offsets<'t>(&self, text: &'t str) -> (Cow<'t, str>, NormalizedOffsets) {
+        let mut removed = Vec::new();
+        let mut rest = text;
+        if self.strip_bom {
+            if let Some(stripped) = text.strip_prefix('\u{feff}') {
+                removed.push((0, text.len() - stripped.len()));
+                rest = stripped;
+            }
+        }
+        if self.strip_zero_width {
+            let before = text.len() - rest.len();
+            for (idx, c) in rest.match_indices(ZERO_WIDTH_CHARS) {
+                let total = removed.last().map_or(0, |&(_, total)| total);
+                removed.push((before + idx - total, total + c.len()));
+            }
+        }
+        (self.normalize(text), NormalizedOffsets(removed))
+    }
+
+    /// Special token IDs must lie above the vocab and be distinct
+    fn check_special_ids(&self, special_tokens: &StdHashMap<String, u32>) -> TokenizerResult<()> {
+        let vocab_end = 256 + self.merges.len() as u32;
+        let mut owners: StdHashMap<u32, &str> = StdHashMap::new();
+        for (token, &id) in special_tokens {
+            if id < vocab_end {
+                return Err(TokenizerError::Value(format!(
+                    "Special token {:?} has ID {}, which is already a vocab token",
+                    token, id
+                )));
+            }
+            if let Some(other) = owners.insert(id, token) {
+                return Err(TokenizerError::Value(format!(
+                    "Special tokens {:?} and {:?} share ID {}",
+                    other, token, id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Map raw bytes to their base token IDs
+    #[inline]
+    fn base_ids(&self, bytes: &[u8]) -> Vec<u32> {
+        bytes.iter().map(|&b| self.byte_ids[b as usize]).collect()
+    }
+
+    #[inline]
+    fn byte_allowed(&self, b: u8) -> bool {
+        self.allowed_bytes
+            .is_none_or(|(allowed, _)| allowed[b as usize])
+    }
+
+    /// Base IDs of a pretokenized chunk, with the word marker if one is set
+    /// and bytes outside `allowed_bytes` as `unk_id`
+    fn chunk_ids(&self, chunk: &str) -> Vec<u32> {
+        let marked;
+        let bytes = match &self.word_marker {
+            Some(marker) => {
+                marked = marker.apply(chunk);
+                marked.as_bytes()
+            }
+            None => chunk.as_bytes(),
+        };
+        match &self.allowed_bytes {
+            Some((allowed, unk_id)) => bytes
+                .iter()
+                .map(|&b| {
+                    if allowed[b as usize] {
+                        self.byte_ids[b as usize]
+                    } else {
+                        *unk_id
+                    }
+                })
+                .collect(),
+            None => self.base_ids(bytes),
+        }
+    }
+
+    /// `chunk_ids` plus each ID's length in the source text: one byte each,
+    /// except marker bytes, which cover nothing
+    fn chunk_ids_with_lens(&self, chunk: &str) -> (Vec<u32>, Vec<usize>) {
+        let ids = self.chunk_ids(chunk);
+        let mut lens = vec![1usize; ids.len()];
+        if let Some(marker) = &self.word_marker {
+            let (start, end) = if marker.at_end {
+                (chunk.len(), ids.len())
+            } else {
+                (0, marker.marker.len())
+            };
+            lens[start..end].fill(0);
+        }
+        (ids, lens)
+    }
+
+    /// Byte expansion table for every token ID up to the highest merge ID
+    fn token_bytes(&self) -> &[Vec<u8>] {
+        &self.token_table().bytes
+    }
+
+    /// Merge IDs left out of `token_bytes` for expanding past `MAX_TOKEN_BYTES`
+    fn oversized_tokens(&self) -> &[u32] {
+        &self.token_table().oversized
+    }
+
+    fn token_table(&self) -> &TokenTable {
+        self.cache.bytes.get_or_init(|| {
+            let mut ordered: Vec<(u32, Pair)> =
+                self.merges.iter().map(|(&pair, &id)| (id, pair)).collect();
+            ordered.sort_unstable();
+
+            let len = ordered
+                .last()
+                .map_or(256, |&(id, _)| (id as usize + 1).max(256));
+            let mut table: Vec<Vec<u8>> = vec![Vec::new(); len];
+            // Base IDs decode to the lowest byte mapped onto them
+            for b in 0..=255u8 {
+                let slot = &mut table[self.byte_ids[b as usize] as usize];
+                if slot.is_empty() {
+                    slot.push(b);
+                }
+            }
+
+            // Expansions are built in ID order from their already built
+            // parts, so chain depth costs neither recursion nor rework
+            let mut oversized = Vec::new();
+            for (id, (a, b)) in ordered {
+                let part = |p: u32| table.get(p as usize).map_or(&[][..], |v| v.as_slice());
+                let too_long = |p: u32| oversized.binary_search(&p).is_ok();
+                if too_long(a) || too_long(b) || part(a).len() + part(b).len() > MAX_TOKEN_BYTES {
+                    oversized.push(id);
+                    continue;
+                }
+                let bytes = [part(a), part(b)].concat();
+                table[id as usize] = bytes;
+            }
+            TokenTable {
+                bytes: table,
+                oversized,
+            }
+        })
+    }
+
+    fn id_by_bytes(&self) -> &(AHashMap<Vec<u8>, u32>, usize) {
+        self.cache.id_by_bytes.get_or_init(|| {
+            let mut map = AHashMap::with_capacity(self.merges.len() + 256);
+            let mut max_len = 1;
+            for (id, bytes) in self.token_bytes().iter().enumerate() {
+                if !bytes.is_empty() {
+                    max_len = max_len.max(bytes.len());
+                    map.insert(bytes.clone(), id as u32);
+                }
+            }
+            (map, max_len)
+        })
+    }
+
+    /// Segment a chunk by always taking the longest matching vocab token
+    fn longest_match_chunk(&self, chunk: &[u8], out: &mut Vec<u32>) {
+        let (by_bytes, max_len) = self.id_by_bytes();
+        let mut i = 0;
+        while i < chunk.len() {
+            if let Some((allowed, unk_id)) = &self.allowed_bytes {
+                if !allowed[chunk[i] as usize] {
+                    out.push(*unk_id);
+                    i += 1;
+                    continue;
+                }
+            }
+            // Only tokens made of allowed bytes may match
+            let run = chunk[i..]
+                .iter()
+                .take_while(|&&b| self.byte_allowed(b))
+                .count();
+            let longest = (1..=(*max_len).min(run))
+                .rev()
+                .find_map(|len| by_bytes.get(&chunk[i..i + len]).map(|&id| (id, len)));
+            // Bytes that share a base ID with a lower byte aren't in the table
+            let (id, len) = longest.unwrap_or((self.byte_ids[chunk[i] as usize], 1));
+            out.push(id);
+            i += len;
+        }
+    }
+
+    /// Literal string for special and protected-literal IDs (specials win
+    /// if both use an ID)
+    fn special_by_id(&self) -> &StdHashMap<u32, String> {
+        self.cache.special_by_id.get_or_init(|| {
+            self.literal_tokens
+                .iter()
+                .chain(&self.special_tokens)
+                .map(|(s, &id)| (id, s.clone()))
+                .collect()
+        })
+    }
+
+    fn merge_sides(&self) -> &(AHashSet<u32>, AHashSet<u32>) {
+        self.cache
+            .merge_sides
+            .get_or_init(|| self.merges.keys().copied().unzip())
+    }
+
+    fn special_matcher(&self) -> Option<&SpecialMatcher> {
+        self.cache
+            .special_matcher
+            .get_or_init(|| {
+                if self.special_tokens.is_empty()
+                    && self.special_patterns.is_empty()
+                    && self.literal_tokens.is_empty()
+                {
+                    return None;
+                }
+                let literals = literal_alternation(
+                    self.special_tokens.keys(),
+                    &self.case_insensitive_specials,
+                );
+                let protected = literal_alternation(self.literal_tokens.keys(), &StdHashSet::new());
+
+                let folded = self
+                    .case_insensitive_specials
+                    .iter()
+                    .filter_map(|s| Some((s.to_lowercase(), *self.special_tokens.get(s)?)))
+                    .collect();
+                let patterns = self
+                    .special_patterns
+                    .iter()
+                    .map(|(source, id)| {
+                        let regex = compile_pattern(source)
+                            .expect("special patterns are validated on registration");
+                        (regex, *id)
+                    })
+                    .collect();
+                Some(SpecialMatcher {
+                    literals,
+                    folded,
+                    patterns,
+                    protected,
+                })
+            })
+            .as_ref()
+    }
+
+    /// Split `text` into ordinary spans and special-token matches.
+    /// Specials are found in the raw text before pretokenization, so they
+    /// match even when the pattern would split them across chunks.
+    ///
+    /// Protected `literal_tokens` are matched the same way. Where matches
+    /// overlap, the one starting first wins; at the same start, literal
+    /// specials win over special patterns, which win over protected literals.
+    fn try_segments<'a, 't>(&'a self, text: &'t str) -> Segments<'a, 't> {
+        self.try_segments_with(text, true)
+    }
+
+    /// `try_segments`, optionally ignoring special tokens (protected
+    /// literals still match)
+    fn try_segments_with<'a, 't>(&'a self, text: &'t str, specials: bool) -> Segments<'a, 't> {
+        let matcher = self.special_matcher();
+        let upcoming = matcher.map_or_else(
+            || Ok(Vec::new()),
+            |matcher| {
+                (0..matcher.candidates())
+                    .map(|which| {
+                        if !specials && which < matcher.special_candidates() {
+                            return Ok(None);
+                        }
+                        matcher.find(self, text, 0, which)
+                    })
+                    .collect()
+            },
+        );
+        let (upcoming, failed) = match upcoming {
+            Ok(upcoming) => (upcoming, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        Segments {
+            tokenizer: self,
+            matcher,
+            text,
+            pos: 0,
+            pending: None,
+            upcoming,
+            failed,
+        }
+    }
+
+    /// `try_segments` that panics if a special pattern fails at runtime,
+    /// like `chunks` does for the pretokenization pattern
+    fn segments<'a, 't: 'a>(&'a self, text: &'t str) -> impl Iterator<Item = Segment<'t>> + 'a {
+        self.segments_with(text, true)
+    }
+
+    /// `try_segments_with` that panics like `segments`
+    fn segments_with<'a, 't: 'a>(
+        &'a self,
+        text: &'t str,
+        specials: bool,
+    ) -> impl Iterator<Item = Segment<'t>> + 'a {
+        self.try_segments_with(text, specials)
+            .map(|segment| segment.unwrap_or_else(|e| panic!("{}", special_regex_error(e))))
+    }
+
+    /// Expand token IDs back into raw bytes. Special IDs expand to their
+    /// registered string; IDs unknown to this tokenizer are handled per
+    /// `unknown_id_policy`, and tokens longer than `MAX_TOKEN_BYTES` are
+    /// written as `<oversized_token:ID>` (see `try_decode_bytes`).
+    /// Sequences of at least `PARALLEL_DECODE_THRESHOLD` IDs are expanded in
+    /// parallel slices and joined; the output is identical either way.
+    /// Panics if the output would exceed `MAX_DECODE_BYTES` (1 GiB);
+    /// `try_decode_bytes` returns an error instead.
+    pub fn decode_bytes(&self, ids: &[u32]) -> Vec<u8> {
+        let len = self.decoded_len(ids);
+        if len > MAX_DECODE_BYTES {
+            panic!("{}", decode_too_long(len));
+        }
+        let out = self.decode_raw(ids);
+        match &self.word_marker {
+            Some(marker) => strip_marker(out, marker.marker.as_bytes()),
+            None => out,
+        }
+    }
+
+    /// Render tokens for logs as `[the]·[ quick]·[<|eot|>]`: each token's
+    /// text, specials by name, control characters and invalid UTF-8 as `\xNN`,
+    /// and IDs with no byte expansion as `[#id]`.
+    pub fn debug_tokens(&self, ids: &[u32]) -> String {
+        let table = self.token_bytes();
+        let specials = self.special_by_id();
+        let mut out = String::with_capacity(ids.len() * 8);
+
+        for (i, &id) in ids.iter().enumerate() {
+            if i > 0 {
+                out.push('·');
+            }
+            out.push('[');
+            if let Some(special) = specials.get(&id) {
+                out.push_str(special);
+            } else {
+                match table.get(id as usize).filter(|bytes| !bytes.is_empty()) {
+                    Some(bytes) => push_escaped(&mut out, bytes),
+                    None => out.push_str(&format!("#{}", id)),
+                }
+            }
+            out.push(']');
+        }
+        out
+    }
+
+    /// Decoded text made safe for single-line logs: control bytes, invalid
+    /// UTF-8 and (in `EscapeMode::Strict`) non-ASCII text are escaped,
+    /// ordinary text is left intact. Unlike `debug_tokens`, tokens are not
+    /// marked off.
+    pub fn decode_escaped(&self, ids: &[u32], mode: EscapeMode) -> String {
+        let bytes = self.decode_bytes(ids);
+        let mut out = String::with_capacity(bytes.len());
+        push_escaped_line(&mut out, &bytes, mode);
+        out
+    }
+
+    /// Like `decode_bytes`, but fails on tokens expanding past
+    /// `MAX_TOKEN_BYTES` (1 MiB) instead of writing placeholders, on output
+    /// past `MAX_DECODE_BYTES` (1 GiB), and on unknown IDs under
+    /// `UnknownIdPolicy::Error`
+    pub fn try_decode_bytes(&self, ids: &[u32]) -> TokenizerResult<Vec<u8>> {
+        let len = self.decoded_len(ids);
+        if len > MAX_DECODE_BYTES {
+            return Err(TokenizerError::Value(decode_too_long(len)));
+        }
+        self.check_decodable(ids)?;
+        Ok(self.decode_bytes(ids))
+    }
+
+    /// Fail on the IDs `try_decode_bytes` refuses: oversized tokens, and
+    /// unknown IDs under `UnknownIdPolicy::Error`
+    fn check_decodable(&self, ids: &[u32]) -> TokenizerResult<()> {
+        let oversized = self.oversized_tokens();
+        if let Some(id) = ids.iter().find(|id| oversized.binary_search(id).is_ok()) {
+            return Err(TokenizerError::Value(format!(
+                "Token {} expands to more than {} bytes",
+                id, MAX_TOKEN_BYTES
+            )));
+        }
+        if self.unknown_id_policy == UnknownIdPolicy::Error {
+            if let Some(id) = ids.iter().find(|&&id| self.is_unknown_id(id)) {
+                return Err(TokenizerError::Value(format!(
+                    "Token {} is neither a byte, a merge nor a registered special token",
+                    id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `id` has no expansion: not a base byte, merge or special
+    /// (oversized merges are known, just too long to expand)
+    fn is_unknown_id(&self, id: u32) -> bool {
+        let table = self.token_table();
+        !self.special_by_id().contains_key(&id)
+            && table.bytes.get(id as usize).is_none_or(Vec::is_empty)
+            && table.oversized.binary_search(&id).is_err()
+    }
+
+    /// `decode_bytes` without stripping the word marker
+    fn decode_raw(&self, ids: &[u32]) -> Vec<u8> {
+        if ids.len() < PARALLEL_DECODE_THRESHOLD {
+            let mut out = Vec::with_capacity(ids.len() * 4);
+            self.decode_into(ids, &mut out);
+            return out;
+        }
+
+        // Build the shared tables once before fanning out
+        self.token_bytes();
+        self.special_by_id();
+
+        let chunk = ids.len().div_ceil(current_num_threads());
+        let parts: Vec<Vec<u8>> = ids
+            .par_chunks(chunk.max(PARALLEL_DECODE_THRESHOLD / 4))
+            .map(|slice| {
+                let mut out = Vec::with_capacity(slice.len() * 4);
+                self.decode_into(slice, &mut out);
+                out
+            })
+            .collect();
+
+        let mut out = Vec::with_capacity(parts.iter().map(Vec::len).sum());
+        for part in parts {
+            out.extend_from_slice(&part);
+        }
+        out
+    }
+
+    fn decode_into(&self, ids: &[u32], out: &mut Vec<u8>) {
+        let table = self.token_bytes();
+        let specials = self.special_by_id();
+        let oversized = self.oversized_tokens();
+
+        for &id in ids {
+            if let Some(special) = specials.get(&id) {
+                out.extend_from_slice(special.as_bytes());
+            } else if let Some(bytes) = table.get(id as usize).filter(|b| !b.is_empty()) {
+                out.extend_from_slice(bytes);
+            } else if oversized.binary_search(&id).is_ok() {
+                out.extend_from_slice(format!("<oversized_token:{}>", id).as_bytes());
+            } else if self.unknown_id_policy == UnknownIdPolicy::Placeholder
+                && self.is_unknown_id(id)
+            {
+                out.extend_from_slice(format!("<unk_special:{}>", id).as_bytes());
+            }
+        }
+    }
+
+    /// Bytes `decode_raw(ids)` produces, found without expanding anything;
+    /// placeholders count at their worst-case length
+    fn decoded_len(&self, ids: &[u32]) -> usize {
+        let table = self.token_bytes();
+        let specials = self.special_by_id();
+        // "<oversized_token:" or "<unk_special:", a u32 and ">"
+        const PLACEHOLDER_BYTES: usize = 17 + 10 + 1;
+        ids.iter()
+            .map(|&id| match specials.get(&id) {
+                Some(special) => special.len(),
+                None => match table.get(id as usize).filter(|b| !b.is_empty()) {
+                    Some(bytes) => bytes.len(),
+                    None => PLACEHOLDER_BYTES,
+                },
+            })
+            .fold(0usize, usize::saturating_add)
+    }
+
+    /// Compile and install a new pretokenization pattern
+    fn replace_pattern(&mut self, pattern: &str) -> TokenizerResult<()> {
+        self.compiled_pattern = Arc::new(CompiledPattern::new(pattern, self.pattern_engine)?);
+        self.pattern = pattern.to_string();
+        Ok(())
+    }
+
+    /// Optimized parallel pair counting with adaptive chunking
+    fn count_pairs_parallel(words: &[Word], counts: &[i32], reduce: PairReduce) -> PairCounts {
+        // Skip parallelization for small inputs
+        if words.len() < MIN_PARALLEL_WORK {
+            return Self::count_pairs_sequential(words, counts);
+        }
+
+        // Adaptive chunk size based on work size and thread count
+        let num_threads = current_num_threads();
+        let chunk_size = (words.len() / (num_threads * 4)).max(PARALLEL_CHUNK_SIZE);
+        if reduce == PairReduce::Tree {
+            return Self::count_pairs_tree(words, counts, chunk_size);
+        }
+        #[cfg(feature = "trace")]
+        let words_done = std::sync::atomic::AtomicU64::new(0);
+
+        // Each worker folds its chunks into PAIR_SHARDS disjoint local maps
+        // keyed by pair hash, so only one set of partial maps per worker (not
+        // per chunk) is alive before the merge, and the merge below works
+        // shard-by-shard instead of funnelling every pair through one pair of
+        // giant accumulator maps.
+        let per_worker: Vec<Vec<PairCounts>> = words
+            .par_chunks(chunk_size)
+            .enumerate()
+            .fold(
+                || vec![PairCounts::default(); PAIR_SHARDS],
+                |mut shards, (chunk_idx, chunk)| {
+                    let base_idx = chunk_idx * chunk_size;
+                    for (offset, w) in chunk.iter().enumerate() {
+                        let i = base_idx + offset;
+                        let count = at(counts, i);
+
+                        if w.ids.len() >= 2 && count != 0 {
+                            for pair in w.pairs() {
+                                let (local_pc, local_wtu) = at_mut(&mut shards, pair_shard(pair));
+                                *local_pc.entry(pair).or_insert(0) += count;
+                                local_wtu.entry(pair).or_default().insert(i);
+                            }
+                        }
+                    }
+                    #[cfg(feature = "trace")]
+                    trace_count_progress(&words_done, chunk.len() as u64, "words");
+                    shards
+                },
+            )
+            .collect();
+
+        // Transpose to shard-major so each shard owns its per-worker partials
+        let mut by_shard: Vec<Vec<PairCounts>> = (0..PAIR_SHARDS)
+            .map(|_| Vec::with_capacity(per_worker.len()))
+            .collect();
+        for shards in per_worker {
+            for (shard_idx, part) in shards.into_iter().enumerate() {
+                by_shard[shard_idx].push(part);
+            }
+        }
+
+        // Merge shards in parallel; shards never share keys
+        let merged: Vec<PairCounts> = by_shard
+            .into_par_iter()
+            .map(|parts| {
+                let mut parts = parts.into_iter();
+                let (mut acc_pc, mut acc_wtu) = parts.next().unwrap_or_default();
+                for (pc, wtu) in parts {
+                    for (k, v) in pc {
+                        *acc_pc.entry(k).or_insert(0) += v;
+                    }
+                    for (k, s) in wtu {
+                        acc_wtu.entry(k).or_default().extend(s);
+                    }
+                }
+                (acc_pc, acc_wtu)
+            })
+            .collect();
+
+        let total = merged.iter().map(|(pc, _)| pc.len()).sum();
+        #[cfg(feature = "trace")]
+        eprintln!("[count] {} words: distinct_pairs={}", words.len(), total);
+        let mut pair_counts = AHashMap::with_capacity(total);
+        let mut where_to_update = AHashMap::with_capacity(total);
+        for (pc, wtu) in merged {
+            pair_counts.extend(pc);
+            where_to_update.extend(wtu);
+        }
+        (pair_counts, where_to_update)
+    }
+
+    /// `PairReduce::Tree` counting: one map pair per task, merged pairwise
+    fn count_pairs_tree(words: &[Word], counts: &[i32], chunk_size: usize) -> PairCounts {
+        #[cfg(feature = "trace")]
+        let words_done = std::sync::atomic::AtomicU64::new(0);
+
+        let merged = words
+            .par_chunks(chunk_size)
+            .enumerate()
+            .map(|(chunk_idx, chunk)| {
+                let base_idx = chunk_idx * chunk_size;
+                let mut local_pc = AHashMap::with_capacity(chunk.len() * 2);
+                let mut local_wtu: AHashMap<Pair, AHashSet<usize>> =
+                    AHashMap::with_capacity(chunk.len() * 2);
+
+                for (offset, w) in chunk.iter().enumerate() {
+                    let i = base_idx + offset;
+                    let count = at(counts, i);
+
+                    if w.ids.len() >= 2 && count != 0 {
+                        for pair in w.pairs() {
+                            *local_pc.entry(pair).or_insert(0) += count;
+                            local_wtu.entry(pair).or_default().insert(i);
+                        }
+                    }
+                }
+                #[cfg(feature = "trace")]
+                trace_count_progress(&words_done, chunk.len() as u64, "words");
+                (local_pc, local_wtu)
+            })
+            .reduce(PairCounts::default, |a, b| {
+                // Fold the smaller side into the larger to move fewer entries
+                let ((mut acc_pc, mut acc_wtu), (pc, wtu)) = if a.0.len() >= b.0.len() {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                acc_pc.reserve(pc.len());
+                for (k, v) in pc {
+                    *acc_pc.entry(k).or_insert(0) += v;
+                }
+                for (k, s) in wtu {
+                    acc_wtu.entry(k).or_default().extend(s);
+                }
+                (acc_pc, acc_wtu)
+            });
+
+        #[cfg(feature = "trace")]
+        eprintln!(
+            "[count] {} words: distinct_pairs={}",
+            words.len(),
+            merged.0.len()
+        );
+        merged
+    }
+
+    /// Pair counting for `max_heap_pairs`: counts alone first, then word
+    /// positions only for the `cap` most frequent pairs (highest count first,
+    /// then smaller pair). The position sets are the bulk of counting memory,
+    /// and those of dropped pairs are never built; the count-only map still
+    /// holds every distinct pair for a moment.
+    fn count_pairs_capped(words: &[Word], counts: &[i32], cap: usize) -> PairCounts {
+        let totals = words
+            .par_chunks(PARALLEL_CHUNK_SIZE)
+            .enumerate()
+            .fold(
+                AHashMap::new,
+                |mut acc: AHashMap<Pair, i32>, (chunk_idx, chunk)| {
+                    let base_idx = chunk_idx * PARALLEL_CHUNK_SIZE;
+                    for (offset, w) in chunk.iter().enumerate() {
+                        let count = at(counts, base_idx + offset);
+                        if w.ids.len() >= 2 && count != 0 {
+                            for pair in w.pairs() {
+                                *acc.entry(pair).or_insert(0) += count;
+                            }
+                        }
+                    }
+                    acc
+                },
+            )
+            .reduce(AHashMap::new, |a, b| {
+                let (mut acc, rest) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+                for (pair, n) in rest {
+                    *acc.entry(pair).or_insert(0) += n;
+                }
+                acc
+            });
+
+        let pair_counts: AHashMap<Pair, i32> = if totals.len() > cap {
+            let mut ranked: Vec<(Pair, i32)> = totals.into_iter().collect();
+            ranked.sort_unstable_by_key(|&(pair, c)| (std::cmp::Reverse(c), pair));
+            ranked.truncate(cap);
+            ranked.into_iter().collect()
+        } else {
+            totals
+        };
+
+        let where_to_update = words
+            .par_chunks(PARALLEL_CHUNK_SIZE)
+            .enumerate()
+            .fold(
+                AHashMap::new,
+                |mut acc: AHashMap<Pair, AHashSet<usize>>, (chunk_idx, chunk)| {
+                    let base_idx = chunk_idx * PARALLEL_CHUNK_SIZE;
+                    for (offset, w) in chunk.iter().enumerate() {
+                        let i = base_idx + offset;
+                        if w.ids.len() >= 2 && at(counts, i) != 0 {
+                            for pair in w.pairs().filter(|p| pair_counts.contains_key(p)) {
+                                acc.entry(pair).or_default().insert(i);
+                            }
+                        }
+                    }
+                    acc
+                },
+            )
+            .reduce(AHashMap::new, |a, b| {
+                let (mut acc, rest) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+                for (pair, pos) in rest {
+                    acc.entry(pair).or_default().extend(pos);
+                }
+                acc
+            });
+        (pair_counts, where_to_update)
+    }
+
+    /// Sequential version for small inputs avoiding parallelization overhead
+    #[inline]
+    fn count_pairs_sequential(words: &[Word], counts: &[i32]) -> PairCounts {
+        let mut pair_counts = AHashMap::with_capacity(words.len() * 2);
+        let mut where_to_update: AHashMap<Pair, AHashSet<usize>> =
+            AHashMap::with_capacity(words.len() * 2);
+
+        for (i, w) in words.iter().enumerate() {
+            if w.ids.len() >= 2 && counts[i] != 0 {
+                for pair in w.pairs() {
+                    *pair_counts.entry(pair).or_insert(0) += counts[i];
+                    where_to_update.entry(pair).or_default().insert(i);
+                }
+            }
+        }
+
+        (pair_counts, where_to_update)
+    }
+
+    /// Serialize merges, pattern and special tokens into a versioned byte blob
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![FORMAT_VERSION];
+        bincode::serialize_into(&mut out, &self.to_data())
+            .expect("in-memory serialization cannot fail");
+        out
+    }
+
+    /// Snapshot for `to_bytes` and `save`
+    fn to_data(&self) -> TokenizerData {
+        let mut merges: Vec<(u32, u32, u32)> = self
+            .merges
+            .iter()
+            .map(|(&(a, b), &id)| (a, b, id))
+            .collect();
+        merges.sort_unstable_by_key(|&(_, _, id)| id);
+
+        let mut special_tokens: Vec<(String, u32)> = self
+            .special_tokens
+            .iter()
+            .map(|(s, &id)| (s.clone(), id))
+            .collect();
+        special_tokens.sort_unstable_by_key(|(_, id)| *id);
+
+        let mut literal_tokens: Vec<(String, u32)> = self
+            .literal_tokens
+            .iter()
+            .map(|(s, &id)| (s.clone(), id))
+            .collect();
+        literal_tokens.sort_unstable();
+
+        let mut case_insensitive_specials: Vec<String> =
+            self.case_insensitive_specials.iter().cloned().collect();
+        case_insensitive_specials.sort_unstable();
+
+        TokenizerData {
+            pattern: self.pattern.clone(),
+            merges,
+            special_tokens,
+            initial_alphabet: (self.byte_ids != IDENTITY_ALPHABET).then(|| self.byte_ids.to_vec()),
+            case_insensitive_specials,
+            special_patterns: self.special_patterns.clone(),
+            word_marker: self.word_marker.clone(),
+            whitespace_chunk_width: self.whitespace_chunk_width as u32,
+            literal_tokens,
+            split_leading_space: self.split_leading_space,
+            strip_bom: self.strip_bom,
+            strip_zero_width: self.strip_zero_width,
+            allowed_bytes: self.allowed_bytes.map(|(allowed, _)| allowed.to_vec()),
+            unk_id: self.allowed_bytes.map_or(0, |(_, unk_id)| unk_id),
+            byte_level_only: self.byte_level_only,
+        }
+    }
+
+    /// Rebuild from a `to_data` snapshot
+    fn from_data(data: TokenizerData) -> TokenizerResult<Self> {
+        let mut tokenizer = Self::new()?;
+        tokenizer.replace_pattern(&data.pattern)?;
+        tokenizer.merges = data
+            .merges
+            .into_iter()
+            .map(|(a, b, id)| ((a, b), id))
+            .collect();
+        tokenizer.special_tokens = data.special_tokens.into_iter().collect();
+        tokenizer.case_insensitive_specials = data.case_insensitive_specials.into_iter().collect();
+        for (regex, id) in data.special_patterns {
+            tokenizer.register_special_pattern(regex, id)?;
+        }
+        tokenizer.word_marker = data.word_marker;
+        tokenizer.whitespace_chunk_width = data.whitespace_chunk_width as usize;
+        tokenizer.split_leading_space = data.split_leading_space;
+        tokenizer.strip_bom = data.strip_bom;
+        tokenizer.strip_zero_width = data.strip_zero_width;
+        tokenizer.allowed_bytes = match data.allowed_bytes {
+            Some(allowed) => {
+                let allowed = allowed.try_into().map_err(|allowed: Vec<bool>| {
+                    TokenizerError::Value(format!(
+                        "allowed_bytes must have 256 entries, got {}",
+                        allowed.len()
+                    ))
+                })?;
+                Some((allowed, data.unk_id))
+            }
+            None => None,
+        };
+        tokenizer.byte_level_only = data.byte_level_only;
+        tokenizer.literal_tokens = data.literal_tokens.into_iter().collect();
+        tokenizer.set_initial_alphabet(data.initial_alphabet)?;
+        Ok(tokenizer)
+    }
+
+    /// Optimized encoding with better merge selection.
+    /// Panics if the pattern fails at runtime (e.g. fancy-regex's backtrack
+    /// limit is hit); use `try_encode` to get an error instead.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut result = Vec::with_capacity(text.len() / 4);
+        if let Err(e) = self.encode_into(text, &mut result) {
+            panic!("{}", e);
+        }
+        result
+    }
+
+    /// Like `encode`, but surfaces regex runtime errors (and overlong chunks
+    /// under `OverlongChunkPolicy::Error`) instead of panicking
+    pub fn try_encode(&self, text: &str) -> TokenizerResult<Vec<u32>> {
+        let per_token = if self.byte_level_only { 1 } else { 4 };
+        let mut result = Vec::with_capacity(text.len() / per_token);
+        self.encode_into(text, &mut result)?;
+        Ok(result)
+    }
+
+    fn encode_into(&self, text: &str, result: &mut Vec<u32>) -> TokenizerResult<()> {
+        let text = &*self.normalize(text);
+        for segment in self.try_segments(text) {
+            let (base, piece) = match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => {
+                    result.push(id);
+                    continue;
+                }
+                Segment::Text(base, piece) => (base, piece),
+            };
+
+            for m in self.try_chunks(piece) {
+                let (start, chunk) = m.map_err(regex_error)?;
+                self.encode_chunk_into(self.chunk_ids(chunk), base + start, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge one chunk's base IDs onto `result`, applying
+    /// `overlong_chunk_policy`; `offset` is where the chunk starts in the text
+    fn encode_chunk_into(
+        &self,
+        mut ids: Vec<u32>,
+        offset: usize,
+        result: &mut Vec<u32>,
+    ) -> TokenizerResult<()> {
+        self.merge_chunk_checked(&mut ids, offset, |_, _| {})?;
+        result.extend(ids);
+        Ok(())
+    }
+
+    /// `merge_chunk` under `overlong_chunk_policy`, for every encoder that
+    /// merges whole chunks. With `Windowed`, `on_merge` indices still refer
+    /// to positions in `ids` as it shrinks, exactly as for one whole-chunk
+    /// merge, so callers tracking per-token data need not care.
+    fn merge_chunk_checked(
+        &self,
+        ids: &mut Vec<u32>,
+        offset: usize,
+        mut on_merge: impl FnMut(usize, u32),
+    ) -> TokenizerResult<()> {
+        let windows = self.chunk_windows(ids, offset)?;
+        if windows.len() == 1 {
+            self.merge_chunk(ids, on_merge);
+            return Ok(());
+        }
+        let mut merged = Vec::with_capacity(ids.len());
+        for window in windows {
+            let mut window = window.to_vec();
+            let done = merged.len();
+            self.merge_chunk(&mut window, |idx, id| on_merge(done + idx, id));
+            merged.extend(window);
+        }
+        *ids = merged;
+        Ok(())
+    }
+
+    /// The pieces of one chunk's base IDs (or bytes) that merge on their own
+    /// under `overlong_chunk_policy`: the whole chunk, its `max_chunk_bytes`
+    /// windows, or an error. For encoders that merge chunks their own way
+    /// rather than through `merge_chunk_checked`.
+    fn chunk_windows<'a, T>(
+        &self,
+        ids: &'a [T],
+        offset: usize,
+    ) -> TokenizerResult<std::slice::Chunks<'a, T>> {
+        let width = if ids.len() <= self.max_chunk_bytes {
+            ids.len()
+        } else {
+            match self.overlong_chunk_policy {
+                OverlongChunkPolicy::Full => ids.len(),
+                OverlongChunkPolicy::Windowed => self.max_chunk_bytes,
+                OverlongChunkPolicy::Error => {
+                    return Err(TokenizerError::Value(format!(
+                        "Chunk of {} bytes at offset {} exceeds max_chunk_bytes ({})",
+                        ids.len(),
+                        offset,
+                        self.max_chunk_bytes
+                    )));
+                }
+            }
+        };
+        Ok(ids.chunks(width.max(1)))
+    }
+
+    /// `encode`'s output for an already normalized `text`, one chunk (or
+    /// special) at a time, so callers can stop early. Panics like `encode`.
+    fn token_chunks<'a>(&'a self, text: &'a str) -> impl Iterator<Item = Vec<u32>> + 'a {
+        self.segments(text)
+            .flat_map(move |segment| -> Box<dyn Iterator<Item = Vec<u32>> + 'a> {
+                match segment {
+                    Segment::Special(id, _, _) => Box::new(std::iter::once(vec![id])),
+                    Segment::Text(base, piece) => Box::new(self.try_chunks(piece).map(move |m| {
+                        let mut ids = Vec::new();
+                        let encoded = m.map_err(regex_error).and_then(|(start, chunk)| {
+                            self.encode_chunk_into(self.chunk_ids(chunk), base + start, &mut ids)
+                        });
+                        if let Err(e) = encoded {
+                            panic!("{}", e);
+                        }
+                        ids
+                    })),
+                }
+            })
+    }
+
+    /// Whether `a` and `b` encode to the same tokens. Both are encoded a
+    /// chunk at a time in lockstep, stopping at the first difference, so
+    /// inputs that diverge early cost little. Panics like `encode`.
+    pub fn same_tokens(&self, a: &str, b: &str) -> bool {
+        if a == b {
+            return true;
+        }
+        let (a, b) = (self.normalize(a), self.normalize(b));
+        self.token_chunks(&a)
+            .flatten()
+            .eq(self.token_chunks(&b).flatten())
+    }
+
+    /// The text of each token `encode(text)` produces, kept inline for short
+    /// pieces. Tokens that end mid-character show U+FFFD for the partial
+    /// bytes; specials show their registered string.
+    pub fn encode_pieces_compact(&self, text: &str) -> Vec<CompactString> {
+        let table = self.token_bytes();
+        let specials = self.special_by_id();
+        self.encode(text)
+            .into_iter()
+            .map(|id| match specials.get(&id) {
+                Some(special) => CompactString::from(special.as_str()),
+                None => {
+                    CompactString::from_utf8_lossy(table.get(id as usize).map_or(&[][..], |b| b))
+                }
+            })
+            .collect()
+    }
+
+    /// Fold `texts` into one value, across threads once there are at least
+    /// `parallel_threshold` of them. `fold` adds a text to an accumulator and
+    /// `reduce` combines two; they must not care how texts were grouped.
+    fn fold_texts<S, T, ID, F, R>(&self, texts: &[S], identity: ID, fold: F, reduce: R) -> T
+    where
+        S: Sync,
+        T: Send,
+        ID: Fn() -> T + Sync + Send,
+        F: Fn(T, &S) -> T + Sync + Send,
+        R: Fn(T, T) -> T + Sync + Send,
+    {
+        if texts.len() < self.parallel_threshold {
+            texts.iter().fold(identity(), fold)
+        } else {
+            texts
+                .par_iter()
+                .fold(&identity, &fold)
+                .reduce(&identity, &reduce)
+        }
+    }
+
+    /// `fold_texts` with a fallible `fold`; stops at the first error
+    fn try_fold_texts<S, T, ID, F, R>(
+        &self,
+        texts: &[S],
+        identity: ID,
+        fold: F,
+        reduce: R,
+    ) -> TokenizerResult<T>
+    where
+        S: Sync,
+        T: Send,
+        ID: Fn() -> T + Sync + Send,
+        F: Fn(T, &S) -> TokenizerResult<T> + Sync + Send,
+        R: Fn(T, T) -> T + Sync + Send,
+    {
+        if texts.len() < self.parallel_threshold {
+            texts.iter().try_fold(identity(), fold)
+        } else {
+            texts
+                .par_iter()
+                .try_fold(&identity, &fold)
+                .try_reduce(&identity, |a, b| Ok(reduce(a, b)))
+        }
+    }
+
+    /// Pretokenized `(start, chunk)` pairs of `text`, after any
+    /// `whitespace_chunk_width` and `split_leading_space` splitting
+    fn try_chunks<'a, 't>(&'a self, text: &'t str) -> Chunks<'a, 't> {
+        Chunks::new(
+            &self.compiled_pattern,
+            text,
+            self.whitespace_chunk_width,
+            self.split_leading_space,
+        )
+    }
+
+    /// `try_chunks` that stops with a panic on a regex runtime error rather
+    /// than skipping it: fancy-regex keeps returning the same error without
+    /// advancing, so ignoring it would spin forever.
+    fn chunks<'a, 't: 'a>(&'a self, text: &'t str) -> impl Iterator<Item = (usize, &'t str)> + 'a {
+        self.try_chunks(text)
+            .map(|m| m.unwrap_or_else(|e| panic!("pretokenization regex failed: {}", e)))
+    }
+
+    /// `apply_merges` for encoding: a no-op under `byte_level_only`
+    #[inline]
+    fn merge_chunk(&self, ids: &mut Vec<u32>, on_merge: impl FnMut(usize, u32)) {
+        if !self.byte_level_only {
+            self.apply_merges(ids, on_merge);
+        }
+    }
+
+    /// Apply merges to one chunk's IDs in place, earliest merge first.
+    /// `on_merge(idx, merge_id)` runs before `ids[idx]` and `ids[idx + 1]` are fused.
+    #[inline]
+    fn apply_merges(&self, ids: &mut Vec<u32>, on_merge: impl FnMut(usize, u32)) {
+        apply_merges_of(&self.merges, ids, on_merge);
+    }
+
+    /// Up to `width` fully merged segmentations of one chunk reachable by
+    /// applying its merges in any order, as `(sum of merge ranks, ids)`,
+    /// lowest sum first. A beam of `width` states is kept per merge step.
+    fn chunk_alternatives(&self, ids: Vec<u32>, width: usize) -> Vec<(u64, Vec<u32>)> {
+        if self.byte_level_only {
+            return vec![(0, ids)];
+        }
+        let mut done = Vec::new();
+        let mut frontier = vec![(0u64, ids)];
+        while !frontier.is_empty() {
+            let mut next = Vec::new();
+            for (score, ids) in frontier {
+                let mut merged = false;
+                for i in 0..ids.len().saturating_sub(1) {
+                    if let Some(&merge_id) = self.merges.get(&(at(&ids, i), at(&ids, i + 1))) {
+                        merged = true;
+                        let mut child = ids.clone();
+                        child[i] = merge_id;
+                        child.remove(i + 1);
+                        // Rank 1 for merge 256; an unchecked table (`load_merges`)
+                        // may hold IDs below that, which count as rank 0
+                        next.push((score + u64::from(merge_id.saturating_sub(255)), child));
+                    }
+                }
+                if !merged {
+                    done.push((score, ids));
+                }
+            }
+            // The final ids fix which merges were applied, so equal states
+            // always have equal scores and sort next to each other
+            next.sort_unstable();
+            next.dedup();
+            next.truncate(width);
+            frontier = next;
+        }
+        done.sort_unstable();
+        done.dedup();
+        done.truncate(width);
+        done
+    }
+
+    /// Convert chunk counts to the parallel `words` / `counts` training format,
+    /// ordered by first sighting so word indices follow the corpus order
+    fn words_from_counts(&self, global_counts: ChunkCounts) -> (Vec<Word>, Vec<i32>) {
+        self.words_from_chunks(
+            global_counts
+                .iter()
+                .map(|(chunk, &stat)| (chunk.as_bytes(), stat))
+                .collect(),
+        )
+    }
+
+    /// `words_from_counts` for chunks given as bytes
+    fn words_from_chunks(&self, mut chunks: Vec<(&[u8], ChunkStat)>) -> (Vec<Word>, Vec<i32>) {
+        chunks.sort_unstable_by_key(|(_, stat)| stat.first_seen);
+        chunks
+            .into_iter()
+            .map(|(bytes, stat)| (Word::new(self.base_ids(bytes)), stat.count))
+            .unzip()
+    }
+
+    /// Pretokenize a batch of texts in parallel into chunk stats, numbering
+    /// them from `first_text`
+    #[cfg(any(feature = "python", not(target_arch = "wasm32")))]
+    fn count_batch<S: AsRef<str> + Sync>(
+        &self,
+        pattern: &CompiledPattern,
+        texts: &[S],
+        first_text: u64,
+    ) -> Result<ChunkCounts, Box<fancy_regex::Error>> {
+        let options = CountOptions {
+            whitespace_chunk_width: self.whitespace_chunk_width,
+            split_leading_space: self.split_leading_space,
+            word_marker: self.word_marker.as_ref(),
+        };
+        count_texts(
+            texts,
+            pattern,
+            |text| self.normalize(text),
+            options,
+            first_text,
+            self.capacity_hints.batch_chunks,
+        )
+    }
+
+    /// Train from scratch without Python. Each entry is a text (or an already
+    /// pretokenized chunk) and how many times it occurs; texts are split with
+    /// the pattern and their chunks weighted by that count. Discards any
+    /// existing merges, like `train_from_iterator`.
+    pub fn train(&mut self, corpus: &[(String, u32)], vocab_size: u32) -> std::io::Result<()> {
+        let (words, counts) = self.count_training_corpus(corpus, vocab_size)?;
+        self.train_core(words, counts, vocab_size)
+    }
+
+    /// Train from scratch on a precomputed chunk-frequency table, skipping
+    /// the corpus scan. Each key is taken as an already pretokenized chunk:
+    /// it is not normalized or split by the pattern, only given the word
+    /// marker if one is set. Equal-count ties resolve as if the chunks were
+    /// first seen in sorted order. Discards any existing merges, like `train`.
+    pub fn train_from_counts(
+        &mut self,
+        counts: StdHashMap<String, u32>,
+        vocab_size: u32,
+    ) -> std::io::Result<()> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        check_vocab_size(vocab_size).map_err(invalid)?;
+
+        let mut chunks: Vec<(String, u32)> = counts
+            .into_iter()
+            .filter(|(chunk, count)| !chunk.is_empty() && *count > 0)
+            .collect();
+        chunks.sort_unstable();
+        let (words, weights): (Vec<Word>, Vec<i32>) = chunks
+            .into_iter()
+            .map(|(chunk, count)| {
+                let key = self
+                    .word_marker
+                    .as_ref()
+                    .map_or_else(|| CompactString::from(chunk.as_str()), |w| w.apply(&chunk));
+                let weight = count.min(i32::MAX as u32) as i32;
+                (Word::new(self.base_ids(key.as_bytes())), weight)
+            })
+            .unzip();
+
+        self.merges.clear();
+        self.last_train_unique_chunks = words.len();
+        check_vocab_reachable(&words, 0, vocab_size).map_err(invalid)?;
+        self.train_core(words, weights, vocab_size)
+    }
+
+    /// `train`'s counting: `corpus` as words and weights, merges cleared
+    fn count_training_corpus(
+        &mut self,
+        corpus: &[(String, u32)],
+        vocab_size: u32,
+    ) -> std::io::Result<(Vec<Word>, Vec<i32>)> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        check_vocab_size(vocab_size).map_err(invalid)?;
+        let pattern = &self.compiled_pattern;
+        let global_counts = corpus
+            .par_iter()
+            .enumerate()
+            .map(|(i, (text, count))| {
+                let weight = (*count).min(i32::MAX as u32) as i32;
+                let text = self.normalize(text);
+                let chunks = Chunks::new(
+                    pattern,
+                    &text,
+                    self.whitespace_chunk_width,
+                    self.split_leading_space,
+                );
+                count_text(chunks, self.word_marker.as_ref(), i as u64, weight)
+            })
+            .try_reduce(AHashMap::new, |a, b| Ok(merge_chunk_counts(a, b)))
+            .map_err(|e: Box<fancy_regex::Error>| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+            })?;
+
+        self.merges.clear();
+        self.last_train_unique_chunks = global_counts.len();
+        let (words, counts) = self.words_from_counts(global_counts);
+        check_vocab_reachable(&words, 0, vocab_size).map_err(invalid)?;
+        Ok((words, counts))
+    }
+
+    /// Count `corpus` like `train` (discarding existing merges) and return a
+    /// session that learns one merge per `TrainingSession::step`. Only the
+    /// merges are learned: the throttle, checkpoints, `validation_texts` and
+    /// `retain_pair_counts` apply to `train` alone.
+    pub fn start_training(
+        &mut self,
+        corpus: &[(String, u32)],
+        vocab_size: u32,
+    ) -> std::io::Result<TrainingSession> {
+        let (words, counts) = self.count_training_corpus(corpus, vocab_size)?;
+        Ok(TrainingSession::new(self, words, counts, vocab_size))
+    }
+
+    /// Train from scratch on text files, one text per `\n`-separated line,
+    /// like `train_from_iterator` over their lines. Each file is memory-mapped
+    /// and scanned in place, so files larger than RAM are fine; `buffer_size`
+    /// lines at a time are counted in parallel. A final line without a
+    /// newline still counts, and invalid UTF-8 is replaced with U+FFFD.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn train_from_files(
+        &mut self,
+        paths: &[String],
+        vocab_size: u32,
+        buffer_size: usize,
+    ) -> TokenizerResult<()> {
+        check_vocab_size(vocab_size).map_err(TokenizerError::Value)?;
+        let hints = self.capacity_hints;
+        let mut global_counts = ChunkCounts::with_capacity(hints.unique_chunks);
+        let mut texts_seen = 0u64;
+
+        for path in paths {
+            let file = std::fs::File::open(path)?;
+            // SAFETY: the map is only read; as with any mapping, the file must
+            // not be truncated by another process while training runs
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+            let mut lines = map.split(|&b| b == b'\n');
+
+            loop {
+                let batch: Vec<&[u8]> = lines.by_ref().take(buffer_size.max(1)).collect();
+                if batch.is_empty() {
+                    break;
+                }
+                let texts: Vec<_> = batch.iter().map(|l| String::from_utf8_lossy(l)).collect();
+                let batch_counts = self
+                    .count_batch(&self.compiled_pattern, &texts, texts_seen)
+                    .map_err(regex_error)?;
+                texts_seen += batch.len() as u64;
+                global_counts = merge_chunk_counts(global_counts, batch_counts);
+            }
+        }
+
+        self.last_train_unique_chunks = global_counts.len();
+        let (words, counts) = self.words_from_counts(global_counts);
+        check_vocab_reachable(&words, 0, vocab_size).map_err(TokenizerError::Value)?;
+        self.merges.clear();
+        self.train_core(words, counts, vocab_size)?;
+        Ok(())
+    }
+
+    /// Core BPE training loop: a `TrainingSession` stepped to the end, plus
+    /// the throttle, checkpoints and end-of-training bookkeeping.
+    /// Appends to any existing merges (IDs contiguous from 256), so `words`
+    /// must already have those merges applied.
+    ///
+    /// The result depends only on `words`, `counts` and the settings, never
+    /// on thread count or hash order: counts are integer sums, `words` come
+    /// sorted by first occurrence, and the heap orders jobs totally by
+    /// (count, tie key, pair) with at most one live job per pair, so the
+    /// order pairs are drained into it doesn't matter.
+    fn train_core(
+        &mut self,
+        words: Vec<Word>,
+        counts: Vec<i32>,
+        vocab_size: u32,
+    ) -> std::io::Result<()> {
+        let mut session = TrainingSession::new(self, words, counts, vocab_size);
+
+        let throttle = self
+            .max_merges_per_second
+            .filter(|&rate| rate > 0.0)
+            .map(|rate| (rate, std::time::Instant::now(), session.merges_done));
+
+        while session.advance(self).is_some() {
+            let merges_done = session.merges_done;
+
+            if let Some((rate, start, first)) = throttle {
+                if merges_done.is_multiple_of(THROTTLE_CHECK_EVERY) {
+                    let due = throttle_due(merges_done - first, rate);
+                    if let Some(ahead) = due.checked_sub(start.elapsed()) {
+                        std::thread::sleep(ahead);
+                    }
+                }
+            }
+
+            if self.checkpoint_every > 0 && merges_done.is_multiple_of(self.checkpoint_every) {
+                if let Some(path) = &self.checkpoint_path {
+                    self.write_checkpoint(path)?;
+                }
+            }
+        }
+
+        // The heap runs dry when no pair occurs anymore (or, with
+        // `split_digits`, only digit pairs are left)
+        self.last_train_reached_target = session.merges_done >= session.num_merges;
+        self.clear_caches();
+        self.last_train_validation_ratio = (!self.validation_texts.is_empty())
+            .then(|| self.compression_ratio(self.validation_texts.clone()));
+
+        // Snapshot surviving pairs (those just below the cutoff) for analysis
+        self.final_pair_counts.clear();
+        if self.retain_pair_counts {
+            self.final_pair_counts.extend(
+                session
+                    .pair_counts
+                    .into_iter()
+                    .filter(|&(_, c)| c > 0)
+                    .map(|(pair, c)| (pair, c as i64)),
+            );
+        }
+        Ok(())
+    }
+
+    /// The merges with IDs below `frozen_until_id`, which must form a
+    /// contiguous run from 256, so continued training can append after them
+    #[cfg(feature = "python")]
+    fn frozen_merges(&self, frozen_until_id: u32) -> TokenizerResult<StdHashMap<Pair, u32>> {
+        let available = 256 + self.merges.len() as u32;
+        if !(256..=available).contains(&frozen_until_id) {
+            return Err(TokenizerError::Value(format!(
+                "frozen_until_id must be between 256 and {}, got {}",
+                available, frozen_until_id
+            )));
+        }
+
+        let frozen: StdHashMap<Pair, u32> = self
+            .merges
+            .iter()
+            .filter(|&(_, &id)| id < frozen_until_id)
+            .map(|(&pair, &id)| (pair, id))
+            .collect();
+        check_merge_ids(&frozen).map_err(TokenizerError::Value)?;
+        if frozen.len() as u32 != frozen_until_id - 256 {
+            return Err(TokenizerError::Value(format!(
+                "Merges below frozen_until_id {} are not contiguous from 256",
+                frozen_until_id
+            )));
+        }
+
+        Ok(frozen)
+    }
+
+    /// Atomically write `to_bytes` to `path` via a temporary sibling file
+    fn write_checkpoint(&self, path: &str) -> std::io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, self.to_bytes())?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+// Public API; `python.rs` exports it to Python
+impl Tokenizer {
+    pub fn register_special_token(&mut self, token: String, id: u32) {
+        self.case_insensitive_specials.remove(&token);
+        self.special_tokens.insert(token, id);
+        self.clear_caches();
+    }
+
+    /// How often each chunk of `pattern` occurs across `texts`, counted in
+    /// parallel the way training counts its corpus, without any word marker
+    /// or whitespace splitting. Counts saturate at `i32::MAX`, as in
+    /// training. Fails if the pattern fails at runtime.
+    pub fn count_corpus(
+        texts: &[&str],
+        pattern: &Regex,
+    ) -> TokenizerResult<AHashMap<CompactString, u32>> {
+        let pattern = CompiledPattern::Fancy(pattern.clone());
+        let counts = count_texts(
+            texts,
+            &pattern,
+            |text| Cow::Borrowed(text),
+            CountOptions::default(),
+            0,
+            CapacityHints::default().batch_chunks,
+        )
+        .map_err(regex_error)?;
+        // Never negative: each occurrence adds 1, saturating
+        Ok(counts
+            .into_iter()
+            .map(|(chunk, stat)| (chunk, stat.count.unsigned_abs()))
+            .collect())
+    }
+
+    /// The special token `encode` emits as `id`, if any. Protected literal
+    /// tokens are not special and give `None`.
+    pub fn special_token_for_id(&self, id: u32) -> Option<String> {
+        self.special_by_id()
+            .get(&id)
+            .filter(|s| self.special_tokens.get(*s) == Some(&id))
+            .cloned()
+    }
+
+    /// Each special token in `text` with its byte offset, in order, found
+    /// with the same leftmost-longest, non-overlapping matching `encode`
+    /// uses, but without encoding anything else. Registered specials are
+    /// reported by their registered string (even if matched case
+    /// insensitively), special patterns by the matched text; protected
+    /// literal tokens are not special and are left out.
+    pub fn scan_specials(&self, text: &str) -> Vec<(String, usize)> {
+        self.segments(text)
+            .filter_map(|segment| match segment {
+                Segment::Special(id, start, end) if self.is_special_id(id) => Some((
+                    self.special_token_for_id(id)
+                        .unwrap_or_else(|| text[start..end].to_string()),
+                    start,
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether `id` is emitted for special tokens, by a registered string
+    /// or by a special pattern
+    pub fn is_special_id(&self, id: u32) -> bool {
+        self.special_token_for_id(id).is_some()
+            || self.special_patterns.iter().any(|&(_, p)| p == id)
+    }
+
+    /// `register_special_token` with `policy` deciding what happens when
+    /// `token` is already registered with another ID
+    pub fn register_special_token_with_policy(
+        &mut self,
+        token: String,
+        id: u32,
+        policy: SpecialConflict,
+    ) -> TokenizerResult<()> {
+        self.register_special_tokens(vec![(token, id)], policy)
+    }
+
+    /// Register several special tokens in order, resolving clashes with
+    /// existing or earlier strings by `policy`. With `SpecialConflict::Error`
+    /// all of them are checked first, so a clash registers none. The special
+    /// matcher is rebuilt once, on the next encode, not per token.
+    pub fn register_special_tokens(
+        &mut self,
+        tokens: Vec<(String, u32)>,
+        policy: SpecialConflict,
+    ) -> TokenizerResult<()> {
+        if policy == SpecialConflict::Error {
+            let mut ids: StdHashMap<&str, u32> = self
+                .special_tokens
+                .iter()
+                .map(|(token, &id)| (token.as_str(), id))
+                .collect();
+            for (token, id) in &tokens {
+                match ids.insert(token, *id) {
+                    Some(existing) if existing != *id => {
+                        return Err(TokenizerError::Value(format!(
+                            "Special token {:?} is already registered as {}, not {}",
+                            token, existing, id
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for (token, id) in tokens {
+            if policy == SpecialConflict::Ignore && self.special_tokens.contains_key(&token) {
+                continue;
+            }
+            self.case_insensitive_specials.remove(&token);
+            self.special_tokens.insert(token, id);
+        }
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Register a special token that also matches any casing of `token`
+    /// (e.g. `<|EOT|>` for `<|eot|>`), still emitting `id`
+    pub fn register_special_token_case_insensitive(&mut self, token: String, id: u32) {
+        self.case_insensitive_specials.insert(token.clone());
+        self.special_tokens.insert(token, id);
+        self.clear_caches();
+    }
+
+    /// Protect `token` so encode always emits it as the single ID `id`, even
+    /// where BPE would split it. Protected literals are found in the raw text
+    /// like specials but lose to a special token starting at the same
+    /// position, and they also apply in `encode_ordinary`. `id` must not
+    /// already name a byte, a merge, a special token or another literal, so
+    /// decode can expand it to `token` unambiguously.
+    pub fn register_literal_token(&mut self, token: String, id: u32) -> TokenizerResult<()> {
+        let taken = if (id as usize) < 256 + self.merges.len() {
+            Some("the vocab")
+        } else if self
+            .special_tokens
+            .values()
+            .chain(self.special_patterns.iter().map(|(_, id)| id))
+            .any(|&special| special == id)
+        {
+            Some("a special token")
+        } else if self
+            .literal_tokens
+            .iter()
+            .any(|(other, &literal)| literal == id && *other != token)
+        {
+            Some("another literal token")
+        } else {
+            None
+        };
+        if let Some(owner) = taken {
+            return Err(TokenizerError::Value(format!(
+                "Literal token {:?} cannot use ID {}, already used by {}",
+                token, id, owner
+            )));
+        }
+        self.literal_tokens.insert(token, id);
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Register a special token family: any non-empty match of `regex` in the
+    /// input encodes to `id`. Precedence during special splitting: the match
+    /// starting earliest wins; at the same start a literal special beats any
+    /// pattern, and earlier-registered patterns beat later ones. Pattern IDs
+    /// decode to nothing unless also registered as a literal special.
+    pub fn register_special_pattern(&mut self, regex: String, id: u32) -> TokenizerResult<()> {
+        compile_pattern(&regex)?;
+        self.special_patterns.push((regex, id));
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Forget all training so the tokenizer can be retrained from scratch.
+    /// Clears the merges and per-run statistics and restores the default
+    /// capacity hints; the pattern, alphabet and encode options are kept, so
+    /// the regex is not recompiled. With `clear_specials`, special tokens,
+    /// special patterns and literal tokens are dropped too.
+    pub fn reset(&mut self, clear_specials: bool) {
+        self.merges.clear();
+        self.final_pair_counts.clear();
+        self.last_train_unique_chunks = 0;
+        self.last_train_reached_target = false;
+        self.last_train_validation_ratio = None;
+        self.capacity_hints = CapacityHints::default();
+        if clear_specials {
+            self.special_tokens.clear();
+            self.case_insensitive_specials.clear();
+            self.special_patterns.clear();
+            self.literal_tokens.clear();
+        }
+        self.clear_caches();
+    }
+
+    /// Distinct pretokenized chunks counted by the last training run.
+    /// Much larger than expected usually means the regex is over-splitting.
+    pub fn last_train_unique_chunks(&self) -> usize {
+        self.last_train_unique_chunks
+    }
+
+    /// Whether the last training run reached its `vocab_size`. Training
+    /// stops early, without an error, once no mergeable pair is left; false
+    /// before any training.
+    pub fn last_train_reached_target(&self) -> bool {
+        self.last_train_reached_target
+    }
+
+    /// `compression_ratio` of `validation_texts` under the vocab the last
+    /// training run produced; `None` if no validation texts were set then
+    pub fn last_train_validation_ratio(&self) -> Option<f64> {
+        self.last_train_validation_ratio
+    }
+
+    /// Rough peak memory in bytes for training on `num_unique_chunks` distinct
+    /// chunks of `avg_chunk_len` bytes each. Assumptions:
+    /// - every chunk contributes `avg_chunk_len - 1` pair positions, and the
+    ///   distinct initial pairs are capped at 256 * 256 byte pairs;
+    /// - hash tables cost about twice (entry + control byte) per entry;
+    /// - position sets are held twice over: once when seeding the heap and
+    ///   again as merges re-push sets for newly formed pairs;
+    /// - every merge leaves one new heap entry and pair-count entry behind.
+    ///
+    /// The chunk-count map from counting is included since it is alive while
+    /// `words` is built. Real usage is usually within a factor of two.
+    pub fn estimate_training_memory(
+        num_unique_chunks: usize,
+        avg_chunk_len: usize,
+        vocab_size: u32,
+    ) -> usize {
+        use std::mem::size_of;
+        let slot = |entry: usize| (entry + 1) * 2;
+
+        let n = num_unique_chunks;
+        let positions = n * avg_chunk_len.saturating_sub(1);
+        let distinct_pairs = positions.min(256 * 256);
+        let num_merges = vocab_size.saturating_sub(256) as usize;
+
+        let chunk_counts = n * (slot(size_of::<(CompactString, ChunkStat)>()) + avg_chunk_len);
+        let words = n * (size_of::<Word>() + avg_chunk_len * size_of::<u32>());
+        let counts = n * size_of::<i32>();
+        let pair_counts = (distinct_pairs + num_merges) * slot(size_of::<(Pair, i32)>());
+        let position_sets = 2 * positions * slot(size_of::<usize>());
+        let heap = (distinct_pairs + num_merges) * size_of::<MergeJob>();
+        let merges = num_merges * slot(size_of::<(Pair, u32)>());
+
+        chunk_counts + words + counts + pair_counts + position_sets + heap + merges
+    }
+
+    /// Load a checkpoint file written during training (see `checkpoint_path`)
+    pub fn load_checkpoint(path: &str) -> TokenizerResult<Self> {
+        let data = std::fs::read(path)?;
+        Self::from_bytes(&data)
+    }
+
+    /// Encode using an explicit segmentation strategy. `EarliestMerge` is
+    /// identical to `encode`; `LongestToken` is offered for comparison.
+    /// Panics like `encode`; see `try_encode_with_strategy`.
+    pub fn encode_with_strategy(&self, text: &str, strategy: EncodeStrategy) -> Vec<u32> {
+        or_panic(self.try_encode_with_strategy(text, strategy))
+    }
+
+    /// `encode_with_strategy` that returns errors instead of panicking
+    pub fn try_encode_with_strategy(
+        &self,
+        text: &str,
+        strategy: EncodeStrategy,
+    ) -> TokenizerResult<Vec<u32>> {
+        if strategy == EncodeStrategy::EarliestMerge || self.byte_level_only {
+            return self.try_encode(text);
+        }
+        let text = &*self.normalize(text);
+
+        let mut result = Vec::with_capacity(text.len() / 4);
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => result.push(id),
+                Segment::Text(base, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        let chunk = match &self.word_marker {
+                            Some(marker) => marker.apply(chunk),
+                            None => CompactString::from(chunk),
+                        };
+                        for window in self.chunk_windows(chunk.as_bytes(), base + start)? {
+                            self.longest_match_chunk(window, &mut result);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Encode without looking up special tokens; for pre-sanitized input.
+    /// Protected `literal_tokens` still apply. Panics like `encode`; see
+    /// `try_encode_ordinary`.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<u32> {
+        or_panic(self.try_encode_ordinary(text))
+    }
+
+    /// `encode_ordinary` that returns errors instead of panicking
+    pub fn try_encode_ordinary(&self, text: &str) -> TokenizerResult<Vec<u32>> {
+        let text = &*self.normalize(text);
+        let mut result = Vec::with_capacity(text.len() / 4);
+
+        for segment in self.try_segments_with(text, false) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => result.push(id),
+                Segment::Text(base, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        self.encode_chunk_into(self.chunk_ids(chunk), base + start, &mut result)?;
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// `encode` with every merge ignored: each chunk stays its base byte
+    /// IDs (word marker included), while specials and protected literals
+    /// still map to their IDs. A no-compression baseline for `encode`.
+    pub fn encode_bytes_only(&self, text: &str) -> Vec<u32> {
+        let text = &*self.normalize(text);
+        let mut result = Vec::with_capacity(text.len());
+        for segment in self.segments(text) {
+            match segment {
+                Segment::Special(id, _, _) => result.push(id),
+                Segment::Text(_, piece) => {
+                    for (_, chunk) in self.chunks(piece) {
+                        result.extend(self.chunk_ids(chunk));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Last token of `encode(text)`, running BPE on the final chunk only.
+    /// Pretokenization still scans the whole text, since the chunk
+    /// boundaries depend on what precedes them.
+    pub fn last_token(&self, text: &str) -> Option<u32> {
+        let text = &*self.normalize(text);
+        match self.segments(text).last()? {
+            Segment::Special(id, _, _) => Some(id),
+            Segment::Text(base, piece) => {
+                let (start, chunk) = self.chunks(piece).last()?;
+                let mut ids = self.chunk_ids(chunk);
+                or_panic(self.merge_chunk_checked(&mut ids, base + start, |_, _| {}));
+                ids.last().copied()
+            }
+        }
+    }
+
+    /// Segmentation of `text` as one string per token; see `encode_pieces_compact`
+    pub fn encode_to_pieces(&self, text: &str) -> Vec<String> {
+        self.encode_pieces_compact(text)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Encode and return the byte span `(start, end)` of each token in `text`.
+    /// With `trim_offsets`, leading whitespace is excluded from word tokens'
+    /// spans (whitespace-only tokens keep their full span). Spans index the
+    /// text as given; characters `strip_bom` and `strip_zero_width` drop
+    /// between two tokens fall outside both spans.
+    /// Panics like `encode`; see `try_encode_with_offsets`.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        trim_offsets: bool,
+    ) -> (Vec<u32>, Vec<(usize, usize)>) {
+        or_panic(self.try_encode_with_offsets(text, trim_offsets))
+    }
+
+    /// `encode_with_offsets` that returns regex runtime errors instead of panicking
+    pub fn try_encode_with_offsets(
+        &self,
+        text: &str,
+        trim_offsets: bool,
+    ) -> TokenizerResult<TokenOffsets> {
+        let (text, removed) = self.normalize_with_offsets(text);
+        let text = &*text;
+        let mut tokens = Vec::with_capacity(text.len() / 4);
+        let mut offsets = Vec::with_capacity(text.len() / 4);
+
+        for segment in self.try_segments(text) {
+            let (base, piece) = match segment.map_err(special_regex_error)? {
+                Segment::Special(id, start, end) => {
+                    tokens.push(id);
+                    offsets.push((removed.start(start), removed.end(end)));
+                    continue;
+                }
+                Segment::Text(base, piece) => (base, piece),
+            };
+
+            for m in self.try_chunks(piece) {
+                let (chunk_start, chunk) = m.map_err(regex_error)?;
+                // Track each token's byte length alongside the merge loop
+                let (mut ids, mut lens) = self.chunk_ids_with_lens(chunk);
+                self.merge_chunk_checked(&mut ids, base + chunk_start, |idx, _| {
+                    lens[idx] += lens.remove(idx + 1);
+                })?;
+
+                let mut start = base + chunk_start;
+                for (id, len) in ids.into_iter().zip(lens) {
+                    let end = start + len;
+                    let mut span_start = start;
+                    if trim_offsets {
+                        let piece = &text.as_bytes()[start..end];
+                        let ws = piece.iter().take_while(|b| b.is_ascii_whitespace()).count();
+                        if ws < piece.len() {
+                            span_start += ws;
+                        }
+                    }
+                    tokens.push(id);
+                    offsets.push((removed.start(span_start), removed.end(end)));
+                    start = end;
+                }
+            }
+        }
+        Ok((tokens, offsets))
+    }
+
+    /// The last `max_len` tokens of `encode(text)`. Pretokenization still
+    /// scans the whole text, but merging runs back to front over the chunks
+    /// and stops once `max_len` tokens are collected, so the discarded head
+    /// is never merged. Chunks merge independently, so the result is exact.
+    /// Panics like `encode`; see `try_encode_truncate_left`.
+    pub fn encode_truncate_left(&self, text: &str, max_len: usize) -> Vec<u32> {
+        or_panic(self.try_encode_truncate_left(text, max_len))
+    }
+
+    /// `encode_truncate_left` that returns errors instead of panicking.
+    /// Overlong chunks in the discarded head are never merged, so they
+    /// don't trip `OverlongChunkPolicy::Error`.
+    pub fn try_encode_truncate_left(
+        &self,
+        text: &str,
+        max_len: usize,
+    ) -> TokenizerResult<Vec<u32>> {
+        let text = &*self.normalize(text);
+        // (special ID, chunk start, chunk)
+        let mut pieces: Vec<(Option<u32>, usize, &str)> = Vec::new();
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, start, _) => pieces.push((Some(id), start, "")),
+                Segment::Text(base, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        pieces.push((None, base + start, chunk));
+                    }
+                }
+            }
+        }
+
+        let mut reversed = Vec::with_capacity(max_len);
+        for (special, start, chunk) in pieces.into_iter().rev() {
+            if reversed.len() >= max_len {
+                break;
+            }
+            match special {
+                Some(id) => reversed.push(id),
+                None => {
+                    let mut ids = self.chunk_ids(chunk);
+                    self.merge_chunk_checked(&mut ids, start, |_, _| {})?;
+                    reversed.extend(ids.into_iter().rev());
+                }
+            }
+        }
+        reversed.truncate(max_len);
+        reversed.reverse();
+        Ok(reversed)
+    }
+
+    /// Encode chunks in order until `budget` tokens are produced, returning
+    /// them with the number of input bytes consumed. Only whole chunks are
+    /// taken, so encoding `&text[consumed..]` next continues where this left
+    /// off; the one exception is a first chunk alone longer than the budget,
+    /// which is cut after the last whole character within `budget` tokens so
+    /// that progress is always made. If even one character takes more tokens
+    /// than `budget` (e.g. 1 for a 2-byte character without a merge), that
+    /// character is returned whole, over the budget; a budget of 0 always
+    /// consumes nothing.
+    /// Panics like `encode`; see `try_encode_budget`.
+    pub fn encode_budget(&self, text: &str, budget: usize) -> (Vec<u32>, usize) {
+        or_panic(self.try_encode_budget(text, budget))
+    }
+
+    /// `encode_budget` that returns regex runtime errors instead of panicking
+    pub fn try_encode_budget(
+        &self,
+        text: &str,
+        budget: usize,
+    ) -> TokenizerResult<(Vec<u32>, usize)> {
+        let mut tokens = Vec::with_capacity(budget.min(text.len()));
+        let mut consumed = 0;
+        if budget == 0 {
+            return Ok((tokens, consumed));
+        }
+        let (text, removed) = self.normalize_with_offsets(text);
+        let text = &*text;
+
+        for segment in self.try_segments(text) {
+            let (base, piece) = match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, end) => {
+                    if tokens.len() == budget {
+                        break;
+                    }
+                    tokens.push(id);
+                    consumed = end;
+                    continue;
+                }
+                Segment::Text(base, piece) => (base, piece),
+            };
+
+            for m in self.try_chunks(piece) {
+                let (chunk_start, chunk) = m.map_err(regex_error)?;
+                let (mut ids, mut lens) = self.chunk_ids_with_lens(chunk);
+                self.merge_chunk_checked(&mut ids, base + chunk_start, |idx, _| {
+                    lens[idx] += lens.remove(idx + 1);
+                })?;
+
+                if tokens.len() + ids.len() > budget {
+                    if tokens.is_empty() {
+                        // Cut where a char ends, so `&text[consumed..]` stays
+                        // valid: the last such cut within the budget, or else
+                        // the first one past it
+                        let mut cut = None;
+                        let mut end = base + chunk_start;
+                        for (n, len) in (1..).zip(&lens) {
+                            end += len;
+                            if !text.is_char_boundary(end) {
+                                continue;
+                            }
+                            if n > budget && cut.is_some() {
+                                break;
+                            }
+                            cut = Some((n, end));
+                            if n >= budget {
+                                break;
+                            }
+                        }
+                        let (n, end) = cut.expect("a chunk ends on a char boundary");
+                        consumed = end;
+                        tokens.extend_from_slice(&ids[..n]);
+                    }
+                    return Ok((tokens, removed.end(consumed)));
+                }
+                tokens.extend(ids);
+                consumed = base + chunk_start + chunk.len();
+            }
+        }
+        Ok((tokens, removed.end(consumed)))
+    }
+
+    /// Input bytes `encode_budget(text, token_budget)` consumes, for cutting
+    /// a document into pieces of about `token_budget` tokens: each piece ends
+    /// at a chunk boundary (or, inside an overlong chunk, a character
+    /// boundary), so `&text[n..]` continues with the next one. Nonzero for a
+    /// nonempty `text` unless `token_budget` is 0.
+    pub fn bytes_for_token_budget(&self, text: &str, token_budget: usize) -> usize {
+        self.encode_budget(text, token_budget).1
+    }
+
+    /// Encode a sequence pair as `a`, `sep_id`, `b`, with BERT-style type IDs:
+    /// 0 for the tokens of `a` and the separator, 1 for the tokens of `b`
+    pub fn encode_pair(&self, a: &str, b: &str, sep_id: u32) -> (Vec<u32>, Vec<u8>) {
+        let mut tokens = self.encode(a);
+        tokens.push(sep_id);
+        let first_len = tokens.len();
+        tokens.extend(self.encode(b));
+
+        let mut type_ids = vec![0u8; first_len];
+        type_ids.resize(tokens.len(), 1);
+        (tokens, type_ids)
+    }
+
+    /// Up to `k` distinct segmentations of `text`. The first is always
+    /// `encode(text)`; the rest apply merges in orders other than
+    /// lowest-rank-first, found by a beam search of width `k` over each
+    /// chunk, and are ranked by the total rank of the merges they apply,
+    /// lowest first. Panics like `encode`; see `try_encode_nbest`.
+    pub fn encode_nbest(&self, text: &str, k: usize) -> Vec<Vec<u32>> {
+        or_panic(self.try_encode_nbest(text, k))
+    }
+
+    /// `encode_nbest` that returns errors instead of panicking
+    pub fn try_encode_nbest(&self, text: &str, k: usize) -> TokenizerResult<Vec<Vec<u32>>> {
+        let text = &*self.normalize(text);
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut beam: Vec<(u64, Vec<u32>)> = vec![(0, Vec::new())];
+        let mut extend = |options: Vec<(u64, Vec<u32>)>| {
+            let mut next: Vec<(u64, Vec<u32>)> = beam
+                .iter()
+                .flat_map(|(score, ids)| {
+                    options
+                        .iter()
+                        .map(move |(s, more)| (score + s, [ids.as_slice(), more].concat()))
+                })
+                .collect();
+            next.sort_unstable();
+            next.dedup();
+            next.truncate(k);
+            beam = next;
+        };
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => extend(vec![(0, vec![id])]),
+                Segment::Text(base, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        let ids = self.chunk_ids(chunk);
+                        for window in self.chunk_windows(&ids, base + start)? {
+                            extend(self.chunk_alternatives(window.to_vec(), k));
+                        }
+                    }
+                }
+            }
+        }
+
+        let greedy = self.try_encode(text)?;
+        let others = beam
+            .into_iter()
+            .map(|(_, ids)| ids)
+            .filter(|ids| *ids != greedy);
+        Ok(std::iter::once(greedy.clone())
+            .chain(others)
+            .take(k)
+            .collect())
+    }
+
+    /// A randomized segmentation of `text` for subword regularization. Each
+    /// step lists the merges that apply, lowest rank first (leftmost first
+    /// on ties), and picks the `k`-th with weight `exp(-k / temperature)`,
+    /// so `k = 0` is the merge `encode` would apply. Merging continues until
+    /// none applies. A `temperature` of 0 (or below) gives `encode(text)`;
+    /// the same `seed` always gives the same result. Panics like `encode`;
+    /// see `try_encode_sampled`.
+    pub fn encode_sampled(&self, text: &str, temperature: f64, seed: u64) -> Vec<u32> {
+        or_panic(self.try_encode_sampled(text, temperature, seed))
+    }
+
+    /// `encode_sampled` that returns errors instead of panicking
+    pub fn try_encode_sampled(
+        &self,
+        text: &str,
+        temperature: f64,
+        seed: u64,
+    ) -> TokenizerResult<Vec<u32>> {
+        if temperature <= 0.0 || self.byte_level_only {
+            return self.try_encode(text);
+        }
+        let text = &*self.normalize(text);
+
+        let mut rng = SplitMix64(seed);
+        let mut result = Vec::with_capacity(text.len() / 4);
+        let mut candidates: Vec<(u32, usize)> = Vec::new();
+        for segment in self.try_segments(text) {
+            let (base, piece) = match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => {
+                    result.push(id);
+                    continue;
+                }
+                Segment::Text(base, piece) => (base, piece),
+            };
+            for m in self.try_chunks(piece) {
+                let (start, chunk) = m.map_err(regex_error)?;
+                let chunk_ids = self.chunk_ids(chunk);
+                for window in self.chunk_windows(&chunk_ids, base + start)? {
+                    let mut ids = window.to_vec();
+                    loop {
+                        candidates.clear();
+                        for i in 0..ids.len().saturating_sub(1) {
+                            if let Some(&merge_id) =
+                                self.merges.get(&(at(&ids, i), at(&ids, i + 1)))
+                            {
+                                candidates.push((merge_id, i));
+                            }
+                        }
+                        if candidates.is_empty() {
+                            break;
+                        }
+                        candidates.sort_unstable();
+
+                        let weight = |k: usize| (-(k as f64) / temperature).exp();
+                        let total: f64 = (0..candidates.len()).map(weight).sum();
+                        let mut target = rng.next_f64() * total;
+                        let mut pick = candidates.len() - 1;
+                        for k in 0..candidates.len() {
+                            target -= weight(k);
+                            if target < 0.0 {
+                                pick = k;
+                                break;
+                            }
+                        }
+
+                        let (merge_id, i) = candidates[pick];
+                        ids[i] = merge_id;
+                        ids.remove(i + 1);
+                    }
+                    result.extend(ids);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// `encode(text)` with every token outside `allowed_ids` replaced by
+    /// `rare_id`, e.g. to see how much survives with only the frequent core
+    /// of `rank_tokens_by_frequency`. Specials count like any other ID.
+    pub fn encode_collapse_rare(
+        &self,
+        text: &str,
+        allowed_ids: StdHashSet<u32>,
+        rare_id: u32,
+    ) -> Vec<u32> {
+        let mut ids = self.encode(text);
+        for id in &mut ids {
+            if !allowed_ids.contains(id) {
+                *id = rare_id;
+            }
+        }
+        ids
+    }
+
+    /// `encode(text)` with each token replaced by `mask_id` with probability
+    /// `mask_prob`, for building masked-input eval sets. Unlike
+    /// [`encode_sampled`](Self::encode_sampled) the segmentation is fixed;
+    /// only tokens are masked. A `mask_prob` of 0 (or below) gives
+    /// `encode(text)`; the same `seed` always gives the same result.
+    pub fn encode_with_mask(
+        &self,
+        text: &str,
+        mask_id: u32,
+        mask_prob: f64,
+        seed: u64,
+    ) -> Vec<u32> {
+        let mut ids = self.encode(text);
+        if mask_prob <= 0.0 {
+            return ids;
+        }
+        let mut rng = SplitMix64(seed);
+        for id in &mut ids {
+            if rng.next_f64() < mask_prob {
+                *id = mask_id;
+            }
+        }
+        ids
+    }
+
+    /// Where `encode(text)` first differs from `reference`, as `(token
+    /// index, our ID, reference ID)`, an ID being `None` where that sequence
+    /// already ended; `None` if they are identical. `encode_with_offsets`
+    /// maps the index back to text and `encode_trace` shows the merges that
+    /// built the token.
+    pub fn first_divergence(
+        &self,
+        text: &str,
+        reference: Vec<u32>,
+    ) -> Option<(usize, Option<u32>, Option<u32>)> {
+        let ids = self.encode(text);
+        let index = ids
+            .iter()
+            .zip(&reference)
+            .position(|(a, b)| a != b)
+            .or_else(|| (ids.len() != reference.len()).then(|| ids.len().min(reference.len())))?;
+        Some((
+            index,
+            ids.get(index).copied(),
+            reference.get(index).copied(),
+        ))
+    }
+
+    /// Every merge `encode(text)` applies, in order, as `(byte offset of the
+    /// merged token in text, merge ID)`; for comparing step by step against
+    /// a reference encoder. Special tokens involve no merges. Panics like
+    /// `encode`; see `try_encode_trace`.
+    pub fn encode_trace(&self, text: &str) -> Vec<(usize, u32)> {
+        or_panic(self.try_encode_trace(text))
+    }
+
+    /// `encode_trace` that returns errors instead of panicking
+    pub fn try_encode_trace(&self, text: &str) -> TokenizerResult<Vec<(usize, u32)>> {
+        let (text, removed) = self.normalize_with_offsets(text);
+        let text = &*text;
+        let mut steps = Vec::new();
+        for segment in self.try_segments(text) {
+            let Segment::Text(base, piece) = segment.map_err(special_regex_error)? else {
+                continue;
+            };
+            for m in self.try_chunks(piece) {
+                let (chunk_start, chunk) = m.map_err(regex_error)?;
+                let (mut ids, mut lens) = self.chunk_ids_with_lens(chunk);
+                self.merge_chunk_checked(&mut ids, base + chunk_start, |idx, merge_id| {
+                    let offset: usize = lens[..idx].iter().sum();
+                    steps.push((removed.start(base + chunk_start + offset), merge_id));
+                    lens[idx] += lens.remove(idx + 1);
+                })?;
+            }
+        }
+        Ok(steps)
+    }
+
+    /// The `n` merges `encode` applies most often over `texts`, as
+    /// `(pair, times applied)`, most frequent first (lower merge ID first on
+    /// ties); shows whether a few merges dominate the encoder's hot path.
+    /// Texts are encoded in parallel. Panics like `encode`; see `try_hot_merges`.
+    pub fn hot_merges(&self, texts: Vec<String>, n: usize) -> Vec<((u32, u32), u64)> {
+        or_panic(self.try_hot_merges(texts, n))
+    }
+
+    /// `hot_merges` that returns regex runtime errors instead of panicking
+    pub fn try_hot_merges(
+        &self,
+        texts: Vec<String>,
+        n: usize,
+    ) -> TokenizerResult<Vec<((u32, u32), u64)>> {
+        let fired = self.try_fold_texts(
+            &texts,
+            AHashMap::new,
+            |mut fired: AHashMap<u32, u64>, text| {
+                let text = &*self.normalize(text);
+                for segment in self.try_segments(text) {
+                    let Segment::Text(base, piece) = segment.map_err(special_regex_error)? else {
+                        continue;
+                    };
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        let mut ids = self.chunk_ids(chunk);
+                        self.merge_chunk_checked(&mut ids, base + start, |_, merge_id| {
+                            *fired.entry(merge_id).or_insert(0) += 1;
+                        })?;
+                    }
+                }
+                Ok(fired)
+            },
+            |mut acc, part| {
+                for (id, count) in part {
+                    *acc.entry(id).or_insert(0) += count;
+                }
+                acc
+            },
+        )?;
+
+        let pairs: AHashMap<u32, Pair> = self.merges.iter().map(|(&p, &id)| (id, p)).collect();
+        let mut ranked: Vec<(u32, u64)> = fired.into_iter().collect();
+        ranked.sort_unstable_by_key(|&(id, count)| (std::cmp::Reverse(count), id));
+        Ok(ranked
+            .into_iter()
+            .take(n)
+            .map(|(id, count)| (pairs[&id], count))
+            .collect())
+    }
+
+    /// Shannon entropy, in bits, of the adjacent base-byte pairs in the
+    /// pretokenized `texts` (merges are not applied; special tokens are
+    /// skipped). A low value suggests few merges capture most of the corpus,
+    /// a high one that a larger `vocab_size` pays off. Texts are counted in
+    /// parallel; 0.0 when there are no pairs.
+    pub fn pair_entropy(&self, texts: Vec<String>) -> f64 {
+        let pair_counts = self.fold_texts(
+            &texts,
+            AHashMap::new,
+            |mut counts: AHashMap<Pair, u64>, text| {
+                let text = &*self.normalize(text);
+                for segment in self.segments(text) {
+                    let Segment::Text(_, piece) = segment else {
+                        continue;
+                    };
+                    for (_, chunk) in self.chunks(piece) {
+                        for pair in self.chunk_ids(chunk).windows(2) {
+                            *counts.entry((pair[0], pair[1])).or_insert(0) += 1;
+                        }
+                    }
+                }
+                counts
+            },
+            |mut acc, part| {
+                for (pair, count) in part {
+                    *acc.entry(pair).or_insert(0) += count;
+                }
+                acc
+            },
+        );
+
+        let total: u64 = pair_counts.values().sum();
+        pair_counts
+            .values()
+            .map(|&count| {
+                let p = count as f64 / total as f64;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// The chunks `encode(text)` runs BPE on, in order: special tokens are
+    /// split out first and kept as chunks of their own, and the rest is cut
+    /// by the pretokenization pattern. The chunks concatenate to `text` (after
+    /// `strip_bom` / `strip_zero_width`). Panics like `encode` if the pattern
+    /// fails at runtime; see `try_pretokenize`.
+    pub fn pretokenize(&self, text: &str) -> Vec<String> {
+        or_panic(self.try_pretokenize(text))
+    }
+
+    /// `pretokenize` that returns regex runtime errors instead of panicking
+    pub fn try_pretokenize(&self, text: &str) -> TokenizerResult<Vec<String>> {
+        let text = &*self.normalize(text);
+        let mut chunks = Vec::new();
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(_, start, end) => chunks.push(text[start..end].to_string()),
+                Segment::Text(_, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (_, chunk) = m.map_err(regex_error)?;
+                        chunks.push(chunk.to_string());
+                    }
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// How disruptive switching to `new_pattern` would be: the fraction of
+    /// the chunks `pretokenize` gives for `texts` today that the new pattern
+    /// would not produce (same text at the same offset). 0 means no change;
+    /// special tokens split out the same either way. Fails on an invalid
+    /// pattern or a runtime regex error.
+    pub fn pattern_impact(&self, new_pattern: &str, texts: Vec<String>) -> TokenizerResult<f64> {
+        let new_pattern = CompiledPattern::new(new_pattern, self.pattern_engine)?;
+        let (changed, total) = self.try_fold_texts(
+            &texts,
+            || (0, 0),
+            |(changed, total), text| {
+                let old = self.chunk_spans(&self.compiled_pattern, text)?;
+                let new: AHashSet<(usize, usize)> =
+                    self.chunk_spans(&new_pattern, text)?.into_iter().collect();
+                let moved = old.iter().filter(|span| !new.contains(span)).count();
+                Ok((changed + moved, total + old.len()))
+            },
+            |a, b| (a.0 + b.0, a.1 + b.1),
+        )?;
+
+        if total == 0 {
+            return Ok(0.0);
+        }
+        Ok(changed as f64 / total as f64)
+    }
+
+    /// `(start, end)` of each chunk `pretokenize` would give with `pattern`
+    fn chunk_spans(
+        &self,
+        pattern: &CompiledPattern,
+        text: &str,
+    ) -> TokenizerResult<Vec<(usize, usize)>> {
+        let text = &*self.normalize(text);
+        let mut spans = Vec::new();
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(_, start, end) => spans.push((start, end)),
+                Segment::Text(base, piece) => {
+                    let chunks = Chunks::new(
+                        pattern,
+                        piece,
+                        self.whitespace_chunk_width,
+                        self.split_leading_space,
+                    );
+                    for m in chunks {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        spans.push((base + start, base + start + chunk.len()));
+                    }
+                }
+            }
+        }
+        Ok(spans)
+    }
+
+    /// `encode(text)` plus, for each token, the index of the pretokenized
+    /// chunk it came from (like Hugging Face's `word_ids()`), for aligning
+    /// word-level labels. A special token counts as a word of its own.
+    /// Panics like `encode`; see `try_encode_with_word_ids`.
+    pub fn encode_with_word_ids(&self, text: &str) -> (Vec<u32>, Vec<u32>) {
+        or_panic(self.try_encode_with_word_ids(text))
+    }
+
+    /// `encode_with_word_ids` that returns errors instead of panicking
+    pub fn try_encode_with_word_ids(&self, text: &str) -> TokenizerResult<(Vec<u32>, Vec<u32>)> {
+        let text = &*self.normalize(text);
+        let mut tokens = Vec::with_capacity(text.len() / 4);
+        let mut word_ids = Vec::with_capacity(text.len() / 4);
+        let mut word = 0u32;
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => {
+                    tokens.push(id);
+                    word_ids.push(word);
+                    word += 1;
+                }
+                Segment::Text(base, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        let before = tokens.len();
+                        self.encode_chunk_into(self.chunk_ids(chunk), base + start, &mut tokens)?;
+                        word_ids.resize(word_ids.len() + tokens.len() - before, word);
+                        word += 1;
+                    }
+                }
+            }
+        }
+        Ok((tokens, word_ids))
+    }
+
+    /// `encode(text)` plus how many tokens each pretokenized chunk produced,
+    /// in chunk order, for word-level perplexity normalization. The counts
+    /// sum to the token count; a special token counts as a word of one.
+    /// Panics like `encode`; see `try_encode_with_word_token_counts`.
+    pub fn encode_with_word_token_counts(&self, text: &str) -> (Vec<u32>, Vec<usize>) {
+        or_panic(self.try_encode_with_word_token_counts(text))
+    }
+
+    /// `encode_with_word_token_counts` that returns errors instead of panicking
+    pub fn try_encode_with_word_token_counts(
+        &self,
+        text: &str,
+    ) -> TokenizerResult<(Vec<u32>, Vec<usize>)> {
+        let text = &*self.normalize(text);
+        let mut tokens = Vec::with_capacity(text.len() / 4);
+        let mut counts = Vec::new();
+        for segment in self.try_segments(text) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, _, _) => {
+                    tokens.push(id);
+                    counts.push(1);
+                }
+                Segment::Text(base, piece) => {
+                    for m in self.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        let before = tokens.len();
+                        self.encode_chunk_into(self.chunk_ids(chunk), base + start, &mut tokens)?;
+                        counts.push(tokens.len() - before);
+                    }
+                }
+            }
+        }
+        Ok((tokens, counts))
+    }
+
+    /// Batch encoding for better throughput. Panics like `encode`; see
+    /// `try_encode_batch`.
+    pub fn encode_batch(&self, texts: Vec<String>) -> Vec<Vec<u32>> {
+        or_panic(self.try_encode_batch(texts))
+    }
+
+    /// `encode_batch` that returns the first text's error instead of panicking
+    pub fn try_encode_batch(&self, texts: Vec<String>) -> TokenizerResult<Vec<Vec<u32>>> {
+        if texts.len() < self.parallel_threshold {
+            // Sequential for small batches
+            texts.iter().map(|t| self.try_encode(t)).collect()
+        } else {
+            // Parallel for large batches
+            texts.par_iter().map(|t| self.try_encode(t)).collect()
+        }
+    }
+
+    /// `encode` with signed 64-bit IDs, the dtype PyTorch embeddings index
+    /// with; every `u32` ID fits, so the values are unchanged
+    pub fn encode_i64(&self, text: &str) -> Vec<i64> {
+        or_panic(self.try_encode_i64(text))
+    }
+
+    /// `encode_i64` that returns regex runtime errors instead of panicking
+    pub fn try_encode_i64(&self, text: &str) -> TokenizerResult<Vec<i64>> {
+        Ok(self.try_encode(text)?.into_iter().map(i64::from).collect())
+    }
+
+    /// `encode_batch` with signed 64-bit IDs; see `encode_i64`
+    pub fn encode_batch_i64(&self, texts: Vec<String>) -> Vec<Vec<i64>> {
+        or_panic(self.try_encode_batch_i64(texts))
+    }
+
+    /// `encode_batch_i64` that returns errors instead of panicking
+    pub fn try_encode_batch_i64(&self, texts: Vec<String>) -> TokenizerResult<Vec<Vec<i64>>> {
+        Ok(self
+            .try_encode_batch(texts)?
+            .into_iter()
+            .map(|ids| ids.into_iter().map(i64::from).collect())
+            .collect())
+    }
+
+    /// Batch encoding into one contiguous buffer plus CSR-style offsets:
+    /// text `i`'s tokens are `tokens[offsets[i]..offsets[i + 1]]`, and
+    /// `offsets` has `texts.len() + 1` entries starting at 0.
+    /// Panics like `encode`; see `try_encode_batch_flat`.
+    pub fn encode_batch_flat(&self, texts: Vec<String>) -> (Vec<u32>, Vec<usize>) {
+        or_panic(self.try_encode_batch_flat(texts))
+    }
+
+    /// `encode_batch_flat` that returns errors instead of panicking
+    pub fn try_encode_batch_flat(
+        &self,
+        texts: Vec<String>,
+    ) -> TokenizerResult<(Vec<u32>, Vec<usize>)> {
+        let encode_group = |group: &[String]| -> TokenizerResult<(Vec<u32>, Vec<usize>)> {
+            let mut tokens = Vec::with_capacity(group.iter().map(|t| t.len() / 4).sum());
+            let mut lens = Vec::with_capacity(group.len());
+            for text in group {
+                let before = tokens.len();
+                self.encode_into(text, &mut tokens)?;
+                lens.push(tokens.len() - before);
+            }
+            Ok((tokens, lens))
+        };
+
+        let groups: Vec<(Vec<u32>, Vec<usize>)> = if texts.len() < self.parallel_threshold {
+            vec![encode_group(&texts)?]
+        } else {
+            texts
+                .par_chunks(64)
+                .map(encode_group)
+                .collect::<TokenizerResult<_>>()?
+        };
+
+        let total = groups.iter().map(|(t, _)| t.len()).sum();
+        let mut tokens = Vec::with_capacity(total);
+        let mut offsets = Vec::with_capacity(texts.len() + 1);
+        offsets.push(0);
+        for (group_tokens, lens) in groups {
+            for len in lens {
+                offsets.push(offsets[offsets.len() - 1] + len);
+            }
+            tokens.extend_from_slice(&group_tokens);
+        }
+        Ok((tokens, offsets))
+    }
+
+    /// Construct with a named pattern preset: "gpt2", "gpt4", "llama",
+    /// "whitespace" or "ascii".
+    /// The preset's regex is stored as `pattern`, so it persists like any other.
+    pub fn with_preset(name: &str) -> TokenizerResult<Self> {
+        let pattern = PATTERN_PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .map(|&(_, pattern)| pattern)
+            .ok_or_else(|| {
+                let known: Vec<&str> = PATTERN_PRESETS.iter().map(|&(n, _)| n).collect();
+                TokenizerError::Value(format!(
+                    "Unknown pattern preset '{}' (expected one of: {})",
+                    name,
+                    known.join(", ")
+                ))
+            })?;
+
+        let mut tokenizer = Self::new()?;
+        tokenizer.replace_pattern(pattern)?;
+        Ok(tokenizer)
+    }
+
+    /// Construct a ready-to-use tokenizer in one step: merges, an optional
+    /// pattern (the default otherwise) and special tokens. Fails, rather
+    /// than returning a half-built tokenizer, if merge IDs are not
+    /// contiguous from 256, a merge uses a part not built before it, or a
+    /// special token's ID is a byte, a merge, or another special's ID.
+    pub fn from_parts(
+        merges: StdHashMap<(u32, u32), u32>,
+        pattern: Option<String>,
+        special_tokens: StdHashMap<String, u32>,
+    ) -> TokenizerResult<Self> {
+        check_merge_ids(&merges).map_err(TokenizerError::Value)?;
+        let mut tokenizer = Self::new()?;
+        if let Some(pattern) = pattern {
+            tokenizer.replace_pattern(&pattern)?;
+        }
+        tokenizer.merges = merges;
+        if let Some(&(pair, id)) = tokenizer.find_forward_references().first() {
+            return Err(TokenizerError::Value(format!(
+                "Merge {:?} -> {} uses a token not built before it",
+                pair, id
+            )));
+        }
+
+        tokenizer.check_special_ids(&special_tokens)?;
+        tokenizer.special_tokens = special_tokens;
+        tokenizer.clear_caches();
+        Ok(tokenizer)
+    }
+
+    /// Write only `special_tokens`, as a JSON object sorted by token, so a
+    /// changing set of control tokens can be kept apart from a stable vocab
+    pub fn save_special_tokens(&self, path: &str) -> TokenizerResult<()> {
+        let sorted: std::collections::BTreeMap<&String, u32> = self
+            .special_tokens
+            .iter()
+            .map(|(token, &id)| (token, id))
+            .collect();
+        let json = serde_json::to_string_pretty(&sorted).map_err(std::io::Error::from)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Replace `special_tokens` with a file written by `save_special_tokens`.
+    /// Fails, keeping the current ones, if an ID is a byte or merge of this
+    /// vocab or two tokens share an ID.
+    pub fn load_special_tokens(&mut self, path: &str) -> TokenizerResult<()> {
+        let json = std::fs::read_to_string(path)?;
+        let special_tokens: StdHashMap<String, u32> = serde_json::from_str(&json).map_err(|e| {
+            TokenizerError::Value(format!("{}: invalid special tokens file: {}", path, e))
+        })?;
+        self.check_special_ids(&special_tokens)?;
+        self.case_insensitive_specials
+            .retain(|token| special_tokens.contains_key(token));
+        self.special_tokens = special_tokens;
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Control how raw bytes map onto base token IDs. `alphabet[b]` is the ID
+    /// emitted for byte `b` and must be below 256; `None` restores identity.
+    ///
+    /// A permutation keeps encoding lossless. Mapping several bytes onto one
+    /// ID restricts the alphabet: encode falls back to that shared ID for any
+    /// byte not covered by a merge, and decode expands it to the lowest byte
+    /// mapped onto it. Base IDs no byte maps to decode to nothing. Set this
+    /// before training; existing merges are expressed in the old alphabet.
+    pub fn set_initial_alphabet(&mut self, alphabet: Option<Vec<u32>>) -> TokenizerResult<()> {
+        let byte_ids = match alphabet {
+            None => IDENTITY_ALPHABET,
+            Some(ids) => {
+                let ids: [u32; 256] = ids.try_into().map_err(|ids: Vec<u32>| {
+                    TokenizerError::Value(format!(
+                        "initial_alphabet must have 256 entries, got {}",
+                        ids.len()
+                    ))
+                })?;
+                if let Some(&bad) = ids.iter().find(|&&id| id >= 256) {
+                    return Err(TokenizerError::Value(format!(
+                        "initial_alphabet IDs must be below 256, got {}",
+                        bad
+                    )));
+                }
+                ids
+            }
+        };
+        self.byte_ids = byte_ids;
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Base token ID for each byte value (identity unless customized)
+    pub fn get_initial_alphabet(&self) -> Vec<u32> {
+        self.byte_ids.to_vec()
+    }
+
+    /// Byte ranges of `text` that no pattern match covers; empty for a pattern
+    /// that tiles its input. If the regex fails at runtime, everything from
+    /// the failure point on is reported as uncovered.
+    pub fn uncovered_spans(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut spans = Vec::new();
+        let mut covered_to = 0;
+        for m in self.compiled_pattern.find_iter(text) {
+            let Ok((start, chunk)) = m else { break };
+            if start > covered_to {
+                spans.push((covered_to, start));
+            }
+            covered_to = start + chunk.len();
+        }
+        if covered_to < text.len() {
+            spans.push((covered_to, text.len()));
+        }
+        spans
+    }
+
+    /// Add `marker` (e.g. `"▁"`) to the start of every pretokenized chunk, or
+    /// to its end with `at_end`, during both training and encoding, so word
+    /// boundaries become explicit in the vocab. Decoding removes every
+    /// occurrence of the marker. `None` turns it off. Set this before
+    /// training; existing merges were learned without it.
+    pub fn set_word_marker(&mut self, marker: Option<String>, at_end: bool) {
+        self.word_marker = marker
+            .filter(|m| !m.is_empty())
+            .map(|marker| WordMarker { marker, at_end });
+    }
+
+    /// The word marker and whether it is appended, if one is set
+    pub fn get_word_marker(&self) -> Option<(String, bool)> {
+        self.word_marker
+            .as_ref()
+            .map(|w| (w.marker.clone(), w.at_end))
+    }
+
+    /// Restrict the bytes encode may emit to `allowed`; any other byte
+    /// encodes as `unk_id`, typically a registered special token's ID. A
+    /// non-ASCII character is several bytes, so with an ASCII-only set it
+    /// becomes one `unk_id` per byte. `None` allows every byte. An `allowed`
+    /// set needs an explicit `unk_id`. Training ignores this.
+    pub fn set_allowed_bytes(
+        &mut self,
+        allowed: Option<[bool; 256]>,
+        unk_id: Option<u32>,
+    ) -> TokenizerResult<()> {
+        self.allowed_bytes = match (allowed, unk_id) {
+            (Some(allowed), Some(unk_id)) => Some((allowed, unk_id)),
+            (Some(_), None) => {
+                return Err(TokenizerError::Value(
+                    "allowed_bytes needs an unk_id for the bytes it excludes".to_string(),
+                ))
+            }
+            (None, _) => None,
+        };
+        Ok(())
+    }
+
+    /// The bytes encode may emit, if restricted
+    pub fn get_allowed_bytes(&self) -> Option<[bool; 256]> {
+        self.allowed_bytes.map(|(allowed, _)| allowed)
+    }
+
+    /// The ID excluded bytes encode as, if `allowed_bytes` is set
+    pub fn get_unk_id(&self) -> Option<u32> {
+        self.allowed_bytes.map(|(_, unk_id)| unk_id)
+    }
+
+    /// Regex source used for pretokenization
+    pub fn get_pattern(&self) -> String {
+        self.pattern.clone()
+    }
+
+    /// Replace the pretokenization regex, rejecting patterns that fail to compile.
+    /// Existing merges were learned under the old chunking, so call this before
+    /// training rather than on an already-trained tokenizer.
+    pub fn set_pattern(&mut self, pattern: String) -> TokenizerResult<()> {
+        self.replace_pattern(&pattern)
+    }
+
+    /// Choose the regex engine for the pretokenization pattern and recompile
+    /// it. Matches are the same either way, so this is a speed setting only
+    /// and is not saved with the tokenizer.
+    pub fn set_pattern_engine(&mut self, engine: PatternEngine) -> TokenizerResult<()> {
+        self.compiled_pattern = Arc::new(CompiledPattern::new(&self.pattern, engine)?);
+        self.pattern_engine = engine;
+        Ok(())
+    }
+
+    /// The regex engine setting `set_pattern_engine` last chose
+    pub fn get_pattern_engine(&self) -> PatternEngine {
+        self.pattern_engine
+    }
+
+    /// Rebuild a tokenizer from the output of `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> TokenizerResult<Self> {
+        let (&version, payload) = data
+            .split_first()
+            .ok_or_else(|| TokenizerError::Value("Empty tokenizer data".to_string()))?;
+        if version != FORMAT_VERSION {
+            return Err(TokenizerError::Value(format!(
+                "Unsupported tokenizer format version {} (expected {})",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let data: TokenizerData = bincode::deserialize(payload).map_err(|e| {
+            TokenizerError::Value(format!("Failed to decode tokenizer data: {}", e))
+        })?;
+        Self::from_data(data)
+    }
+
+    /// Write to `path` as newline-delimited JSON, one merge per line, so
+    /// nothing proportional to the whole file is buffered in memory:
+    ///
+    /// ```text
+    /// {"format_version":N,"merge_count":2,"tokenizer":{"pattern":...,"merges":[],...}}
+    /// [116,104,256]
+    /// [256,101,257]
+    /// ```
+    ///
+    /// The header holds everything but the merges, with `format_version` =
+    /// `FORMAT_VERSION` (`N` above); each following line is a
+    /// `[left, right, id]` merge, in ID order.
+    pub fn save(&self, path: &str) -> TokenizerResult<()> {
+        use std::io::Write;
+
+        let mut data = self.to_data();
+        let merges = std::mem::take(&mut data.merges);
+        let header = SaveHeader {
+            format_version: FORMAT_VERSION,
+            merge_count: merges.len(),
+            tokenizer: data,
+        };
+
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(&mut out, &header).map_err(std::io::Error::from)?;
+        writeln!(out)?;
+        for (a, b, id) in merges {
+            writeln!(out, "[{},{},{}]", a, b, id)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Read a file written by `save`, a line at a time
+    pub fn load(path: &str) -> TokenizerResult<Self> {
+        Self::load_with_limit(path, None)
+    }
+
+    /// `load` keeping only the first `max_merges` merges; see
+    /// `load_merges_limited`
+    pub fn load_limited(path: &str, max_merges: usize) -> TokenizerResult<Self> {
+        Self::load_with_limit(path, Some(max_merges))
+    }
+
+    fn load_with_limit(path: &str, max_merges: Option<usize>) -> TokenizerResult<Self> {
+        use std::io::BufRead;
+
+        let invalid = |line: usize, e: serde_json::Error| {
+            TokenizerError::Value(format!("{}:{}: invalid tokenizer file: {}", path, line, e))
+        };
+        let mut lines = std::io::BufReader::new(std::fs::File::open(path)?).lines();
+
+        let first = lines.next().transpose()?.unwrap_or_default();
+        let header: SaveHeader = serde_json::from_str(&first).map_err(|e| invalid(1, e))?;
+        if header.format_version != FORMAT_VERSION {
+            return Err(TokenizerError::Value(format!(
+                "Unsupported tokenizer format version {} (expected {})",
+                header.format_version, FORMAT_VERSION
+            )));
+        }
+
+        let mut data = header.tokenizer;
+        let kept = max_merges.map_or(header.merge_count, |max| max.min(header.merge_count));
+        data.merges = Vec::with_capacity(kept);
+        let mut found = 0;
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let merge: (u32, u32, u32) =
+                serde_json::from_str(&line).map_err(|e| invalid(i + 2, e))?;
+            found += 1;
+            if !merge_past_limit(merge.2, max_merges) {
+                data.merges.push(merge);
+            }
+        }
+        if found != header.merge_count {
+            return Err(TokenizerError::Value(format!(
+                "{}: expected {} merges, found {} (truncated file?)",
+                path, header.merge_count, found
+            )));
+        }
+        Self::from_data(data)
+    }
+
+    /// Number of tokens `encode` would produce for `text`
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// The token whose byte expansion is exactly `bytes`, if the vocab has one.
+    /// Unlike `as_single_token`, this ignores whether `encode` would produce it.
+    pub fn bytes_to_token(&self, bytes: &[u8]) -> Option<u32> {
+        self.id_by_bytes().0.get(bytes).copied()
+    }
+
+    /// The ID `encode(text)` yields if it is exactly one non-special token.
+    /// Texts that are not a vocab entry's exact bytes are rejected without encoding.
+    pub fn as_single_token(&self, text: &str) -> Option<u32> {
+        let id = match &self.word_marker {
+            Some(marker) => self.bytes_to_token(marker.apply(text).as_bytes())?,
+            None => self.bytes_to_token(text.as_bytes())?,
+        };
+        let ids = self.try_encode(text).ok()?;
+        (ids == [id]).then_some(id)
+    }
+
+    /// Whether `text` encodes to exactly one non-special token
+    pub fn is_single_token(&self, text: &str) -> bool {
+        self.as_single_token(text).is_some()
+    }
+
+    /// Input bytes per output token over a sample; higher means better compression
+    pub fn compression_ratio(&self, texts: Vec<String>) -> f64 {
+        let (bytes, tokens) = self.fold_texts(
+            &texts,
+            || (0, 0),
+            |(bytes, tokens), t| (bytes + t.len(), tokens + self.count_tokens(t)),
+            |a, b| (a.0 + b.0, a.1 + b.1),
+        );
+
+        if tokens == 0 {
+            return 0.0;
+        }
+        bytes as f64 / tokens as f64
+    }
+
+    /// How often each ordered token pair `(earlier, later)` occurs at most
+    /// `window` positions apart within one of `texts`, over their encodings;
+    /// pairs never span documents. Documents are encoded and counted in
+    /// parallel. Memory grows with distinct pairs, which `window` bounds.
+    /// Panics like `encode`; see `try_token_cooccurrence`.
+    pub fn token_cooccurrence(
+        &self,
+        texts: Vec<String>,
+        window: usize,
+    ) -> StdHashMap<(u32, u32), u64> {
+        or_panic(self.try_token_cooccurrence(texts, window))
+    }
+
+    /// `token_cooccurrence` that returns errors instead of panicking
+    pub fn try_token_cooccurrence(
+        &self,
+        texts: Vec<String>,
+        window: usize,
+    ) -> TokenizerResult<StdHashMap<(u32, u32), u64>> {
+        let counts = self.try_fold_texts(
+            &texts,
+            AHashMap::new,
+            |mut counts: AHashMap<Pair, u64>, text| {
+                let ids = self.try_encode(text)?;
+                for (i, &a) in ids.iter().enumerate() {
+                    for &b in ids.iter().skip(i + 1).take(window) {
+                        *counts.entry((a, b)).or_insert(0) += 1;
+                    }
+                }
+                Ok(counts)
+            },
+            |a, b| {
+                let (mut acc, rest) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+                for (pair, n) in rest {
+                    *acc.entry(pair).or_insert(0) += n;
+                }
+                acc
+            },
+        )?;
+        Ok(counts.into_iter().collect())
+    }
+
+    /// `(tokens per second, bytes per second)` for encoding `texts` one after
+    /// another on the calling thread, timed with `std::time::Instant` (which
+    /// is unavailable on `wasm32-unknown-unknown`). Output is discarded.
+    pub fn benchmark_encode(&self, texts: Vec<String>) -> (f64, f64) {
+        let start = std::time::Instant::now();
+        let mut tokens = 0usize;
+        for text in &texts {
+            tokens += std::hint::black_box(self.encode(text)).len();
+        }
+        let seconds = start.elapsed().as_secs_f64();
+
+        if seconds == 0.0 {
+            return (0.0, 0.0);
+        }
+        let bytes: usize = texts.iter().map(String::len).sum();
+        (tokens as f64 / seconds, bytes as f64 / seconds)
+    }
+
+    /// Decode token IDs to text, replacing invalid UTF-8 with U+FFFD
+    pub fn decode(&self, ids: Vec<u32>) -> String {
+        String::from_utf8_lossy(&self.decode_bytes(&ids)).into_owned()
+    }
+
+    /// `decode` that fails on tokens `try_decode_bytes` refuses
+    pub fn try_decode(&self, ids: &[u32]) -> TokenizerResult<String> {
+        Ok(String::from_utf8_lossy(&self.try_decode_bytes(ids)?).into_owned())
+    }
+
+    /// Whether `encode(text)` is a fixed point: decoding it and encoding the
+    /// result again gives the same tokens. A well-behaved byte-level BPE is
+    /// always stable, so `false` points at a chunking or decode bug.
+    /// Output that doesn't decode to valid UTF-8 counts as unstable.
+    pub fn is_stable(&self, text: &str) -> bool {
+        let ids = self.encode(text);
+        match String::from_utf8(self.decode_bytes(&ids)) {
+            Ok(decoded) => self.encode(&decoded) == ids,
+            Err(_) => false,
+        }
+    }
+
+    /// `encode(text)` as `(id, run length)` pairs, one per run of identical
+    /// consecutive tokens. Only the representation is compacted; expanding
+    /// each pair `length` times gives back exactly `encode(text)`.
+    pub fn encode_rle(&self, text: &str) -> Vec<(u32, u32)> {
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for id in self.encode(text) {
+            match runs.last_mut() {
+                Some((last, len)) if *last == id => *len += 1,
+                _ => runs.push((id, 1)),
+            }
+        }
+        runs
+    }
+
+    /// `(id, start index, run length)` for every run of at least `min_run`
+    /// identical consecutive tokens in `encode(text)`, in order; for catching
+    /// degenerate output such as long runs of one whitespace token.
+    /// A `min_run` of 0 is treated as 1.
+    pub fn repeated_token_runs(&self, text: &str, min_run: usize) -> Vec<(u32, usize, usize)> {
+        let ids = self.encode(text);
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for end in 1..=ids.len() {
+            if end == ids.len() || ids[end] != ids[start] {
+                if end - start >= min_run.max(1) {
+                    runs.push((ids[start], start, end - start));
+                }
+                start = end;
+            }
+        }
+        runs
+    }
+
+    /// How often each byte value occurs in the chunks training would count
+    /// from `texts` (after normalization; bytes the pattern skips are not
+    /// counted). Zeros are bytes the alphabet never needs; hits on `0x00` or
+    /// high-bit bytes in supposedly ASCII data point at corrupt input.
+    /// Texts are scanned in parallel. Panics like `encode` if the pattern
+    /// fails at runtime.
+    pub fn scan_byte_coverage(&self, texts: Vec<String>) -> [u64; 256] {
+        self.fold_texts(
+            &texts,
+            || [0u64; 256],
+            |mut coverage, text| {
+                for (_, chunk) in self.chunks(&self.normalize(text)) {
+                    for &b in chunk.as_bytes() {
+                        coverage[b as usize] += 1;
+                    }
+                }
+                coverage
+            },
+            |mut a, b| {
+                for (total, n) in a.iter_mut().zip(b) {
+                    *total += n;
+                }
+                a
+            },
+        )
+    }
+
+    /// How often each token ID occurs when encoding `texts`.
+    /// Texts are encoded and counted in parallel; only one text's tokens are
+    /// alive per worker at a time.
+    pub fn token_histogram(&self, texts: Vec<String>) -> StdHashMap<u32, u64> {
+        self.fold_texts(
+            &texts,
+            StdHashMap::new,
+            |mut hist, text| {
+                for id in self.encode(text) {
+                    *hist.entry(id).or_insert(0) += 1;
+                }
+                hist
+            },
+            |mut a, b| {
+                for (id, n) in b {
+                    *a.entry(id).or_insert(0) += n;
+                }
+                a
+            },
+        )
+    }
+
+    /// `token_histogram` as `(id, count)`, most frequent first (lower ID
+    /// first on ties), so position in the list is the token's rank. Tokens
+    /// that never occur are left out.
+    pub fn rank_tokens_by_frequency(&self, texts: Vec<String>) -> Vec<(u32, u64)> {
+        let mut ranked: Vec<(u32, u64)> = self.token_histogram(texts).into_iter().collect();
+        ranked.sort_unstable_by_key(|&(id, count)| (std::cmp::Reverse(count), id));
+        ranked
+    }
+
+    /// KL(a || b), in bits, between the token distributions of `corpus_a`
+    /// and `corpus_b` (see `token_histogram`): a drift signal that grows as
+    /// `corpus_b` stops resembling `corpus_a`. Counts get add-one smoothing
+    /// over the IDs seen in either corpus, so a token missing from one side
+    /// doesn't make it infinite. 0.0 if both are empty.
+    pub fn token_distribution_kl(&self, corpus_a: Vec<String>, corpus_b: Vec<String>) -> f64 {
+        let hist_a = self.token_histogram(corpus_a);
+        let hist_b = self.token_histogram(corpus_b);
+        // Sorted so the float sum doesn't depend on hash order
+        let mut support: Vec<u32> = hist_a.keys().chain(hist_b.keys()).copied().collect();
+        support.sort_unstable();
+        support.dedup();
+
+        let size = support.len() as f64;
+        let total_a = hist_a.values().sum::<u64>() as f64 + size;
+        let total_b = hist_b.values().sum::<u64>() as f64 + size;
+        support
+            .iter()
+            .map(|id| {
+                let p = (hist_a.get(id).copied().unwrap_or(0) + 1) as f64 / total_a;
+                let q = (hist_b.get(id).copied().unwrap_or(0) + 1) as f64 / total_b;
+                p * (p / q).log2()
+            })
+            .sum()
+    }
+
+    /// Vocab IDs (base bytes and merges, not specials) that never occur when
+    /// encoding `texts`, sorted ascending. Stops early once every ID is seen.
+    pub fn dead_tokens(&self, texts: Vec<String>) -> Vec<u32> {
+        let max_id = self.merges.values().copied().max().unwrap_or(255).max(255) as usize;
+        let mut in_vocab = vec![false; max_id + 1];
+        in_vocab[..256].fill(true);
+        for &id in self.merges.values() {
+            in_vocab[id as usize] = true;
+        }
+
+        let mut unseen = in_vocab.iter().filter(|&&v| v).count();
+        for text in &texts {
+            for id in self.encode(text) {
+                if let Some(slot) = in_vocab.get_mut(id as usize) {
+                    if *slot {
+                        *slot = false;
+                        unseen -= 1;
+                    }
+                }
+            }
+            if unseen == 0 {
+                break;
+            }
+        }
+
+        (0..=max_id as u32)
+            .filter(|&id| in_vocab[id as usize])
+            .collect()
+    }
+
+    /// Fraction of `texts` that `self` and `other` encode to identical token
+    /// sequences. An empty sample counts as full agreement.
+    pub fn agreement(&self, other: &Tokenizer, texts: Vec<String>) -> f64 {
+        if texts.is_empty() {
+            return 1.0;
+        }
+        let same = self.fold_texts(
+            &texts,
+            || 0,
+            |same, t| same + usize::from(self.encode(t) == other.encode(t)),
+            |a, b| a + b,
+        );
+        same as f64 / texts.len() as f64
+    }
+
+    /// Levenshtein distance between `self.encode(text)` and
+    /// `other.encode(text)`: the fewest token insertions, deletions and
+    /// substitutions turning one sequence into the other
+    pub fn token_edit_distance(&self, other: &Tokenizer, text: &str) -> usize {
+        let (a, b) = (self.encode(text), other.encode(text));
+        // One DP row at a time: `row[j]` is the distance from the prefix of
+        // `a` done so far to `b[..j]`
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, &x) in a.iter().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, &y) in b.iter().enumerate() {
+                let substitute = diagonal + usize::from(x != y);
+                diagonal = row[j + 1];
+                row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+            }
+        }
+        row[b.len()]
+    }
+
+    /// Token IDs that are the left part of at least one merge; a pair whose
+    /// left ID is not among them never merges
+    pub fn mergeable_lefts(&self) -> AHashSet<u32> {
+        self.merge_sides().0.clone()
+    }
+
+    /// Token IDs that are the right part of at least one merge
+    pub fn mergeable_rights(&self) -> AHashSet<u32> {
+        self.merge_sides().1.clone()
+    }
+
+    /// How `other`'s merges differ from these, as `(added, removed,
+    /// changed)`: `(pair, id)` merges only `other` has, `(pair, id)` merges
+    /// only `self` has, and `(pair, self id, other id)` for pairs both merge
+    /// under different IDs. Each list is sorted by (`self`'s) ID.
+    pub fn merge_diff(&self, other: &Tokenizer) -> MergeDiff {
+        let mut added: Vec<(Pair, u32)> = other
+            .merges
+            .iter()
+            .filter(|(pair, _)| !self.merges.contains_key(pair))
+            .map(|(&pair, &id)| (pair, id))
+            .collect();
+        let mut removed = Vec::new();
+        let mut changed = Vec::new();
+        for (&pair, &id) in &self.merges {
+            match other.merges.get(&pair) {
+                None => removed.push((pair, id)),
+                Some(&new_id) if new_id != id => changed.push((pair, id, new_id)),
+                Some(_) => {}
+            }
+        }
+        added.sort_unstable_by_key(|&(pair, id)| (id, pair));
+        removed.sort_unstable_by_key(|&(pair, id)| (id, pair));
+        changed.sort_unstable_by_key(|&(pair, id, _)| (id, pair));
+        (added, removed, changed)
+    }
+
+    /// A copy keeping only the merges `texts` need, for shrinking the
+    /// embedding table of a specialized model: every token their encodings
+    /// use, plus the parts those tokens are built from. Kept merges are
+    /// renumbered contiguously in their original order; base bytes, special
+    /// tokens, special patterns and literal tokens keep their IDs, and an ID
+    /// of theirs that the renumbered merges would reuse is an error. Also
+    /// returns the old -> new ID map for every kept token, for carrying
+    /// embedding rows over. Fails if `try_encode` fails on any of `texts`.
+    pub fn specialize(
+        &self,
+        texts: Vec<String>,
+    ) -> TokenizerResult<(Tokenizer, StdHashMap<u32, u32>)> {
+        let used = self.try_fold_texts(
+            &texts,
+            AHashSet::new,
+            |mut used, text| {
+                used.extend(self.try_encode(text)?);
+                Ok(used)
+            },
+            |mut a, b| {
+                a.extend(b);
+                a
+            },
+        )?;
+
+        // Close over parts: a kept merge needs both of its halves
+        let parts: AHashMap<u32, Pair> = self.merges.iter().map(|(&p, &id)| (id, p)).collect();
+        let mut kept: StdHashMap<(u32, u32), u32> = StdHashMap::new();
+        let mut stack: Vec<u32> = used.iter().copied().collect();
+        while let Some(id) = stack.pop() {
+            if let Some(&(a, b)) = parts.get(&id) {
+                if kept.insert((a, b), id).is_none() {
+                    stack.extend([a, b]);
+                }
+            }
+        }
+
+        let vocab_end = 256 + kept.len() as u32;
+        let fixed: AHashSet<u32> = self
+            .special_tokens
+            .values()
+            .chain(self.special_patterns.iter().map(|(_, id)| id))
+            .chain(self.literal_tokens.values())
+            .copied()
+            .collect();
+        if let Some(id) = fixed
+            .iter()
+            .copied()
+            .filter(|id| (256..vocab_end).contains(id))
+            .min()
+        {
+            return Err(TokenizerError::Value(format!(
+                "Special or literal token ID {} collides with the specialized merges (IDs 256..{})",
+                id, vocab_end
+            )));
+        }
+
+        let mut specialized = self.clone();
+        // Keyed by the old merge IDs
+        specialized.final_pair_counts.clear();
+        let mut remap = specialized.load_merges_remapped(kept)?;
+        remap.extend((0..256).map(|id| (id, id)));
+        remap.extend(fixed.into_iter().map(|id| (id, id)));
+        Ok((specialized, remap))
+    }
+
+    /// Merges as `(pair, id)` in ID order, i.e. the order training learned them
+    pub fn merges_ordered(&self) -> Vec<((u32, u32), u32)> {
+        let mut ordered: Vec<((u32, u32), u32)> =
+            self.merges.iter().map(|(&p, &id)| (p, id)).collect();
+        ordered.sort_unstable_by_key(|&(pair, id)| (id, pair));
+        ordered
+    }
+
+    /// Merges flattened to `[a0, b0, id0, a1, b1, id1, ...]` in ID order, the
+    /// `merges_ordered` triples packed for bulk transfer (e.g. to a GPU)
+    pub fn merges_array(&self) -> Vec<u32> {
+        self.merges_ordered()
+            .into_iter()
+            .flat_map(|((a, b), id)| [a, b, id])
+            .collect()
+    }
+
+    /// Load merges from the flat layout of `merges_array`. Fails, leaving the
+    /// merges unchanged, if the length is not a multiple of 3 or a pair
+    /// appears twice.
+    pub fn merges_from_array(&mut self, array: Vec<u32>) -> TokenizerResult<()> {
+        if !array.len().is_multiple_of(3) {
+            return Err(TokenizerError::Value(format!(
+                "Merge array length {} is not a multiple of 3",
+                array.len()
+            )));
+        }
+        let mut merges = StdHashMap::with_capacity(array.len() / 3);
+        for triple in array.chunks_exact(3) {
+            let (pair, id) = ((triple[0], triple[1]), triple[2]);
+            if merges.insert(pair, id).is_some() {
+                return Err(TokenizerError::Value(format!(
+                    "Duplicate merge pair ({}, {})",
+                    pair.0, pair.1
+                )));
+            }
+        }
+        self.load_merges(merges);
+        Ok(())
+    }
+
+    /// Export merges for serialization
+    pub fn get_merges(&self) -> StdHashMap<(u32, u32), u32> {
+        self.merges.clone()
+    }
+
+    /// Load pre-trained merges
+    pub fn load_merges(&mut self, merges: StdHashMap<(u32, u32), u32>) {
+        self.merges = merges;
+        self.clear_caches();
+    }
+
+    /// Load only the merges with IDs below `256 + max_merges`; since merges
+    /// only reference earlier IDs, that prefix is always a valid, smaller
+    /// vocabulary
+    pub fn load_merges_limited(
+        &mut self,
+        mut merges: StdHashMap<(u32, u32), u32>,
+        max_merges: usize,
+    ) {
+        merges.retain(|_, &mut id| !merge_past_limit(id, Some(max_merges)));
+        self.load_merges(merges);
+    }
+
+    /// Load merges whose IDs may have gaps (from an external table), renumbered
+    /// to `256, 257, ...` in ID order with their parts translated to match.
+    /// Returns the old -> new ID map for translating other data. Fails, leaving
+    /// the merges unchanged, on an ID below 256, a duplicate ID, or a part
+    /// that is neither a base byte nor a lower-ID merge.
+    pub fn load_merges_remapped(
+        &mut self,
+        merges: StdHashMap<(u32, u32), u32>,
+    ) -> TokenizerResult<StdHashMap<u32, u32>> {
+        let mut ordered: Vec<(u32, Pair)> = merges.into_iter().map(|(p, id)| (id, p)).collect();
+        ordered.sort_unstable();
+
+        let mut remap: StdHashMap<u32, u32> = StdHashMap::with_capacity(ordered.len());
+        let mut remapped = StdHashMap::with_capacity(ordered.len());
+        for (new_id, (old_id, (a, b))) in (256u32..).zip(ordered) {
+            if old_id < 256 {
+                return Err(TokenizerError::Value(format!(
+                    "Merge ID {} for ({}, {}) collides with the base bytes",
+                    old_id, a, b
+                )));
+            }
+            if remap.contains_key(&old_id) {
+                return Err(TokenizerError::Value(format!(
+                    "Duplicate merge ID {} (assigned to ({}, {}) and another pair)",
+                    old_id, a, b
+                )));
+            }
+            let part = |id: u32| match id {
+                0..=255 => Ok(id),
+                _ => remap.get(&id).copied().ok_or_else(|| {
+                    TokenizerError::Value(format!(
+                        "Merge {} uses {}, which is neither a base byte nor a lower-ID merge",
+                        old_id, id
+                    ))
+                }),
+            };
+            let pair = (part(a)?, part(b)?);
+            remap.insert(old_id, new_id);
+            remapped.insert(pair, new_id);
+        }
+
+        self.merges = remapped;
+        self.clear_caches();
+        Ok(remap)
+    }
+
+    /// Append the merge `(a, b)` with the next free ID and return that ID.
+    /// Both parts must already exist (a base byte or an earlier merge), and
+    /// the new ID must not already belong to a special or literal token.
+    pub fn add_merge(&mut self, a: u32, b: u32) -> TokenizerResult<u32> {
+        let new_id = self.vocab_size() as u32;
+        for id in [a, b] {
+            if id >= new_id {
+                return Err(TokenizerError::Value(format!(
+                    "Token {} is neither a base byte nor an existing merge",
+                    id
+                )));
+            }
+        }
+        if let Some(&existing) = self.merges.get(&(a, b)) {
+            return Err(TokenizerError::Value(format!(
+                "Pair ({}, {}) is already merged as {}",
+                a, b, existing
+            )));
+        }
+        if self
+            .special_tokens
+            .values()
+            .chain(self.special_patterns.iter().map(|(_, id)| id))
+            .chain(self.literal_tokens.values())
+            .any(|&taken| taken == new_id)
+        {
+            return Err(TokenizerError::Value(format!(
+                "Merge ID {} for ({}, {}) is already used by a special or literal token",
+                new_id, a, b
+            )));
+        }
+
+        self.merges.insert((a, b), new_id);
+        self.clear_caches();
+        Ok(new_id)
+    }
+
+    /// The pair one training step would merge next over `words` (token IDs,
+    /// with `counts[i]` occurrences of `words[i]`), and its count, without
+    /// applying it. Uses training's ordering: `tie_break` between equal
+    /// counts, and `split_digits` and `merge_filter` if set. `None` when no
+    /// pair can merge; fails unless there is one count per word.
+    pub fn next_merge(
+        &self,
+        words: &[Vec<u32>],
+        counts: &[i32],
+    ) -> TokenizerResult<Option<((u32, u32), u64)>> {
+        if words.len() != counts.len() {
+            return Err(TokenizerError::Value(format!(
+                "got {} words but {} counts",
+                words.len(),
+                counts.len()
+            )));
+        }
+        let words: Vec<Word> = words.iter().map(|ids| Word::new(ids.clone())).collect();
+        let (pair_counts, where_to_update) = Self::count_pairs_sequential(&words, counts);
+
+        let table = self.token_bytes();
+        let token_len = |id: u32| table.get(id as usize).map_or(0, Vec::len);
+        let has_digit = |id: u32| {
+            table
+                .get(id as usize)
+                .is_some_and(|b| b.iter().any(u8::is_ascii_digit))
+        };
+        let script = |id: u32| {
+            table
+                .get(id as usize)
+                .map_or(Script::Neutral, |b| Script::of_bytes(b))
+        };
+        let cross_script = |(a, b): Pair| {
+            self.merge_filter == MergeFilter::NoCrossScript && script(a).clashes(script(b))
+        };
+        let token_lens: Vec<usize> = if self.tie_break == TieBreak::ShorterToken {
+            (0..table.len() as u32).map(token_len).collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(where_to_update
+            .into_iter()
+            .filter_map(|(pair, pos)| {
+                let count = *pair_counts.get(&pair)?;
+                if count <= 0
+                    || (self.split_digits && has_digit(pair.0) && has_digit(pair.1))
+                    || cross_script(pair)
+                {
+                    return None;
+                }
+                Some(MergeJob {
+                    pair,
+                    count: count as u64,
+                    tie: tie_key(self.tie_break, &token_lens, pair, &pos),
+                    pos,
+                })
+            })
+            .max()
+            .map(|job| (job.pair, job.count)))
+    }
+
+    /// Pair frequencies remaining after the last training run.
+    /// Empty unless `retain_pair_counts` was set before training.
+    pub fn final_pair_counts(&self) -> StdHashMap<(u32, u32), i64> {
+        self.final_pair_counts.clone()
+    }
+
+    /// Like `load_merges`, but rejects tables with duplicate target IDs or IDs
+    /// that are not exactly the contiguous range `256..256 + len`.
+    pub fn try_load_merges(&mut self, merges: StdHashMap<(u32, u32), u32>) -> TokenizerResult<()> {
+        check_merge_ids(&merges).map_err(TokenizerError::Value)?;
+        self.merges = merges;
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Merges with a part whose ID is not below the merge's own ID, in ID
+    /// order. Such a part is not yet built when its merge is, so the merged
+    /// token decodes without it; decode expands tokens in ID order rather
+    /// than recursively, so cycles cannot make it loop.
+    pub fn find_forward_references(&self) -> Vec<((u32, u32), u32)> {
+        let mut found: Vec<((u32, u32), u32)> = self
+            .merges
+            .iter()
+            .filter(|&(&(a, b), &id)| a >= id || b >= id)
+            .map(|(&pair, &id)| (pair, id))
+            .collect();
+        found.sort_unstable_by_key(|&(pair, id)| (id, pair));
+        found
+    }
+
+    /// Rebuild merges from ranked byte sequences (rank = token ID), as found in
+    /// tiktoken-style vocab files. Each multi-byte token is re-encoded with the
+    /// lower-ranked tokens and must split into exactly two of them, which
+    /// become its merge pair. Multi-byte ranks must be exactly
+    /// `256..256 + count`, one each. Single-byte entries, if any, must cover
+    /// all 256 bytes with distinct ranks below 256 and define the initial
+    /// alphabet; otherwise the identity is used.
+    pub fn load_from_ranked_tokens(&mut self, tokens: Vec<(Vec<u8>, u32)>) -> TokenizerResult<()> {
+        let value_error = |msg: String| TokenizerError::Value(msg);
+
+        let (singles, mut multi): (Vec<_>, Vec<_>) =
+            tokens.into_iter().partition(|(bytes, _)| bytes.len() == 1);
+        if multi.iter().any(|(bytes, _)| bytes.is_empty()) {
+            return Err(value_error("Ranked tokens must not be empty".to_string()));
+        }
+
+        let alphabet = if singles.is_empty() {
+            None
+        } else {
+            let mut ids = [u32::MAX; 256];
+            for (bytes, rank) in &singles {
+                ids[bytes[0] as usize] = *rank;
+            }
+            if singles.len() != 256 || ids.contains(&u32::MAX) {
+                return Err(value_error(format!(
+                    "Single-byte tokens must cover all 256 bytes exactly once, got {}",
+                    singles.len()
+                )));
+            }
+            let mut seen = [false; 256];
+            for &rank in &ids {
+                if rank >= 256 || std::mem::replace(&mut seen[rank as usize], true) {
+                    return Err(value_error(format!(
+                        "Single-byte token ranks must be distinct and below 256, got {}",
+                        rank
+                    )));
+                }
+            }
+            Some(ids.to_vec())
+        };
+
+        let mut rank_of: AHashMap<Vec<u8>, u32> = AHashMap::with_capacity(256 + multi.len());
+        for b in 0..=255u8 {
+            let base = alphabet.as_ref().map_or(b as u32, |ids| ids[b as usize]);
+            rank_of.insert(vec![b], base);
+        }
+
+        multi.sort_unstable_by_key(|&(_, rank)| rank);
+        let mut merges = StdHashMap::with_capacity(multi.len());
+        for (expected, (bytes, rank)) in (256u32..).zip(multi) {
+            if rank != expected {
+                return Err(value_error(format!(
+                    "Multi-byte token {:?} has rank {}, expected {}: ranks must run from 256 without gaps or repeats",
+                    bytes, rank, expected
+                )));
+            }
+
+            // Merge adjacent pieces by lowest rank, using only earlier tokens
+            let mut parts: Vec<&[u8]> = bytes.chunks(1).collect();
+            while parts.len() > 2 {
+                let best = (0..parts.len() - 1)
+                    .filter_map(|i| {
+                        let joined = [parts[i], parts[i + 1]].concat();
+                        rank_of.get(&joined).filter(|&&r| r < rank).map(|&r| (r, i))
+                    })
+                    .min();
+                let Some((_, i)) = best else { break };
+                let end = parts[i].len() + parts[i + 1].len();
+                let start = parts[..i].iter().map(|p| p.len()).sum::<usize>();
+                parts[i] = &bytes[start..start + end];
+                parts.remove(i + 1);
+            }
+            if parts.len() != 2 {
+                return Err(value_error(format!(
+                    "Token {:?} (rank {}) cannot be built from two lower-ranked tokens",
+                    bytes, rank
+                )));
+            }
+
+            let pair = (rank_of[parts[0]], rank_of[parts[1]]);
+            if rank_of.insert(bytes.clone(), rank).is_some() {
+                return Err(value_error(format!("Duplicate ranked token {:?}", bytes)));
+            }
+            merges.insert(pair, rank);
+        }
+
+        self.set_initial_alphabet(alphabet)?;
+        self.merges = merges;
+        self.clear_caches();
+        Ok(())
+    }
+
+    /// Write the vocab as a tiktoken `.model` file: one `base64(bytes) rank`
+    /// line per token, rank being the token ID, in rank order. All 256 bytes
+    /// get a line, so `load_from_ranked_tokens` restores the alphabet too.
+    /// Special tokens are not written; tiktoken keeps them separately.
+    pub fn save_tiktoken(&self, path: &str) -> TokenizerResult<()> {
+        use std::io::Write;
+
+        if let Some(id) = self.oversized_tokens().first() {
+            return Err(TokenizerError::Value(format!(
+                "Token {} expands to more than {} bytes",
+                id, MAX_TOKEN_BYTES
+            )));
+        }
+        let table = self.token_bytes();
+        let singles: [u8; 256] = std::array::from_fn(|b| b as u8);
+        let mut ranked: Vec<(u32, &[u8])> = Vec::with_capacity(256 + self.merges.len());
+        for (b, byte) in singles.iter().enumerate() {
+            ranked.push((self.byte_ids[b], std::slice::from_ref(byte)));
+        }
+        ranked.extend(
+            self.merges
+                .values()
+                .map(|&id| (id, table[id as usize].as_slice())),
+        );
+        ranked.sort_unstable();
+
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for (rank, bytes) in ranked {
+            writeln!(out, "{} {}", base64_encode(bytes), rank)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// `(id, byte length, lossy string)` of the longest token in the vocab,
+    /// lowest ID first on ties. Without merges this is a single base byte.
+    pub fn longest_token(&self) -> (u32, usize, String) {
+        let (id, bytes) = self
+            .token_bytes()
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, bytes)| bytes.len())
+            .expect("the token table always holds the base bytes");
+        (
+            id as u32,
+            bytes.len(),
+            String::from_utf8_lossy(bytes).into_owned(),
+        )
+    }
+
+    /// `(id, lossy string)` of every vocab token whose bytes contain
+    /// `substring`, in ID order. Special tokens are not searched.
+    pub fn find_tokens(&self, substring: &str) -> Vec<(u32, String)> {
+        let needle = substring.as_bytes();
+        self.token_bytes()
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| {
+                !bytes.is_empty()
+                    && (needle.is_empty() || bytes.windows(needle.len()).any(|w| w == needle))
+            })
+            .map(|(id, bytes)| (id as u32, String::from_utf8_lossy(bytes).into_owned()))
+            .collect()
+    }
+
+    /// The merges that build token `id` from base bytes, in an order they
+    /// can be applied: each pair only uses bytes or tokens built earlier in
+    /// the list, and a part used twice is built once. Empty for base bytes
+    /// and for IDs that aren't merges.
+    pub fn merge_path(&self, id: u32) -> Vec<(u32, u32)> {
+        let parts: AHashMap<u32, Pair> = self.merges.iter().map(|(&p, &id)| (id, p)).collect();
+        let mut built = AHashSet::new();
+        let mut path = Vec::new();
+        // Post-order on an explicit stack, so deep merge chains can't
+        // overflow the call stack: `true` once the parts are queued
+        let mut stack = vec![(id, false)];
+        while let Some((id, parts_done)) = stack.pop() {
+            let Some(&(a, b)) = parts.get(&id) else {
+                continue;
+            };
+            if parts_done {
+                path.push((a, b));
+            } else if built.insert(id) {
+                stack.extend([(id, true), (b, false), (a, false)]);
+            }
+        }
+        path
+    }
+
+    /// Token counts by BPE tree depth: index 0 counts base tokens, 1 merges
+    /// of two base tokens, and in general a merge is one deeper than its
+    /// deeper part. A long tail means a few tokens need many merge steps.
+    pub fn merge_depth_histogram(&self) -> Vec<usize> {
+        let table = self.token_bytes();
+        let mut ordered: Vec<(u32, Pair)> = self.merges.iter().map(|(&p, &id)| (id, p)).collect();
+        ordered.sort_unstable();
+
+        let mut depth = vec![0usize; table.len()];
+        let mut histogram = vec![(0..256).filter(|&id| !table[id].is_empty()).count()];
+        for (id, (a, b)) in ordered {
+            let part = |t: u32| depth.get(t as usize).copied().unwrap_or(0);
+            let d = 1 + part(a).max(part(b));
+            depth[id as usize] = d;
+            if histogram.len() <= d {
+                histogram.resize(d + 1, 0);
+            }
+            histogram[d] += 1;
+        }
+        histogram
+    }
+
+    /// Build every lazily initialized lookup table now, so the first real
+    /// request doesn't pay for it: the ID -> bytes table (decode, vocab
+    /// queries), the bytes -> ID map (`LongestToken` encoding, single-token
+    /// lookups), the special ID -> string map (decode) and the special-token
+    /// matcher (encode). Editing merges or specials drops them again.
+    pub fn warmup(&self) {
+        self.token_bytes();
+        self.id_by_bytes();
+        self.special_by_id();
+        self.special_matcher();
+    }
+
+    /// Get vocabulary size
+    pub fn vocab_size(&self) -> usize {
+        256 + self.merges.len()
+    }
+
+    /// Size metrics for monitoring: `merges_count`, `special_count`,
+    /// `pattern_len` (bytes), and `merges_bytes`, the merge table's allocated
+    /// capacity times its entry size (a lower bound on its footprint)
+    pub fn stats(&self) -> StdHashMap<String, u64> {
+        let entry_size = std::mem::size_of::<(Pair, u32)>();
+        [
+            ("merges_count", self.merges.len()),
+            ("special_count", self.special_tokens.len()),
+            ("merges_bytes", self.merges.capacity() * entry_size),
+            ("pattern_len", self.pattern.len()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value as u64))
+        .collect()
+    }
+
+    /// Highest ID `encode` can emit, including special tokens.
+    /// Size embedding/output layers with `max_token_id() + 1`.
+    pub fn max_token_id(&self) -> u32 {
+        let max_merge = self.merges.values().copied().max().unwrap_or(255);
+        let max_special = self
+            .special_tokens
+            .values()
+            .chain(self.special_patterns.iter().map(|(_, id)| id))
+            .chain(self.literal_tokens.values())
+            .copied()
+            .max()
+            .unwrap_or(0);
+        max_merge.max(max_special).max(255)
+    }
+}
+
+/// Token window over a text that keeps growing, e.g. a chat context: `push`
+/// appends text and drops the oldest tokens past `max_window`. Only the
+/// previous last chunk is re-encoded together with the new text, since the
+/// two may form one chunk (say `" wor"` + `"ld"`); earlier chunk boundaries
+/// are assumed to stay put. Pass the same tokenizer to every `push`.
+#[derive(Clone, Debug, Default)]
+pub struct SlidingWindowEncoder {
+    max_window: usize,
+    tokens: VecDeque<u32>,
+    /// Text of the last chunk pushed so far
+    tail: String,
+    /// Tokens `tail` encoded to, including any already evicted
+    tail_tokens: usize,
+}
+
+impl SlidingWindowEncoder {
+    pub fn new(max_window: usize) -> Self {
+        Self {
+            max_window,
+            ..Self::default()
+        }
+    }
+
+    /// Encode `text` as a continuation of everything pushed so far.
+    /// Panics like `Tokenizer::encode`; see `try_push`.
+    pub fn push(&mut self, tokenizer: &Tokenizer, text: &str) {
+        or_panic(self.try_push(tokenizer, text))
+    }
+
+    /// `push` that returns errors instead of panicking; on error the window
+    /// is left as it was
+    pub fn try_push(&mut self, tokenizer: &Tokenizer, text: &str) -> TokenizerResult<()> {
+        let seam = self.tail.clone() + &tokenizer.normalize(text);
+
+        // (byte offset, token index) where the seam's last chunk starts
+        let mut last = (0, 0);
+        let mut ids = Vec::with_capacity(seam.len() / 4);
+        for segment in tokenizer.try_segments(&seam) {
+            match segment.map_err(special_regex_error)? {
+                Segment::Special(id, start, _) => {
+                    last = (start, ids.len());
+                    ids.push(id);
+                }
+                Segment::Text(base, piece) => {
+                    for m in tokenizer.try_chunks(piece) {
+                        let (start, chunk) = m.map_err(regex_error)?;
+                        last = (base + start, ids.len());
+                        tokenizer.encode_chunk_into(
+                            tokenizer.chunk_ids(chunk),
+                            base + start,
+                            &mut ids,
+                        )?;
+                    }
+                }
+            }
+        }
+
+        let kept = self.tokens.len().saturating_sub(self.tail_tokens);
+        self.tokens.truncate(kept);
+        self.tail = seam[last.0..].to_string();
+        self.tail_tokens = ids.len() - last.1;
+
+        self.tokens.extend(ids);
+        let excess = self.tokens.len().saturating_sub(self.max_window);
+        self.tokens.drain(..excess);
+        Ok(())
+    }
+
+    /// The current window, oldest token first
+    pub fn tokens(&self) -> Vec<u32> {
+        self.tokens.iter().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+/// Incremental detokenization for showing output token by token: `push`
+/// returns only complete characters and holds back the bytes of one split
+/// across tokens, so no U+FFFD appears mid-stream. Bytes that can never form
+/// a character still come out as U+FFFD. Tokens expand as in `decode`, word
+/// marker stripping and `unknown_id_policy` included, so the pieces (and
+/// `finish`) concatenate to `decode` of every ID pushed. Pass the same
+/// tokenizer to every `push`.
+#[derive(Clone, Debug, Default)]
+pub struct StreamDecoder {
+    /// Start of a character whose remaining bytes haven't arrived yet
+    pending: Vec<u8>,
+    /// Trailing bytes that may be the start of a word marker split across
+    /// tokens, not yet stripped or passed on to `pending`
+    marker_prefix: Vec<u8>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the next token, returning the text it completes (maybe empty).
+    /// Like `decode`, skips the IDs `try_push` refuses.
+    pub fn push(&mut self, tokenizer: &Tokenizer, id: u32) -> String {
+        let mut bytes = std::mem::take(&mut self.marker_prefix);
+        tokenizer.decode_into(&[id], &mut bytes);
+        match &tokenizer.word_marker {
+            Some(marker) => self.strip_marker(bytes, marker.marker.as_bytes()),
+            None => self.pending.extend_from_slice(&bytes),
+        }
+
+        let mut out = String::new();
+        let mut start = 0;
+        while start < self.pending.len() {
+            match std::str::from_utf8(&self.pending[start..]) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    start = self.pending.len();
+                }
+                Err(e) => {
+                    let valid_end = start + e.valid_up_to();
+                    out.push_str(&String::from_utf8_lossy(&self.pending[start..valid_end]));
+                    match e.error_len() {
+                        Some(invalid) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            start = valid_end + invalid;
+                        }
+                        // An incomplete character at the end: wait for more
+                        None => {
+                            start = valid_end;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending.drain(..start);
+        out
+    }
+
+    /// `push` that fails on the IDs `try_decode` refuses
+    pub fn try_push(&mut self, tokenizer: &Tokenizer, id: u32) -> TokenizerResult<String> {
+        tokenizer.check_decodable(&[id])?;
+        Ok(self.push(tokenizer, id))
+    }
+
+    /// Flush the held-back bytes at the end of the stream, as U+FFFD
+    pub fn finish(&mut self) -> String {
+        self.pending.append(&mut self.marker_prefix);
+        let out = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        out
+    }
+
+    /// `strip_marker` over `bytes` onto `pending`, keeping back a tail that
+    /// the next token could complete into a marker
+    fn strip_marker(&mut self, bytes: Vec<u8>, marker: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            if rest.starts_with(marker) {
+                i += marker.len();
+            } else if marker.starts_with(rest) {
+                self.marker_prefix.extend_from_slice(rest);
+                return;
+            } else {
+                self.pending.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// BPE training as a state machine that learns one merge per `step`, for
+/// driving training interactively (say, one merge at a time from Python)
+/// and inspecting it in between. `train` runs one to the end. The
+/// tokenizer's `split_digits`, `merge_filter`, `tie_break` and
+/// `pair_reduce` are read once at the start; pass the same tokenizer to
+/// every `step`, and don't change its merges in between (`step` refuses a
+/// tokenizer whose merge count doesn't match the session's).
+pub struct TrainingSession {
+    words: Vec<Word>,
+    counts: Vec<i32>,
+    pair_counts: AHashMap<Pair, i32>,
+    heap: OctonaryHeap<MergeJob>,
+    /// Pairs the current merge created, and where; reused across steps
+    local_updates: AHashMap<Pair, AHashSet<usize>>,
+    merges_done: u32,
+    num_merges: u32,
+    split_digits: bool,
+    /// Whether each token ID contains a digit, for `split_digits`
+    has_digit: Vec<bool>,
+    no_cross_script: bool,
+    /// Byte expansion and script of each token ID, for `NoCrossScript`
+    expansions: Vec<Vec<u8>>,
+    scripts: Vec<Script>,
+    tie_break: TieBreak,
+    /// Byte length of each token ID, for `TieBreak::ShorterToken`
+    token_lens: Vec<usize>,
+    #[cfg(feature = "trace")]
+    idle_pops: usize,
+}
+
+impl TrainingSession {
+    /// Count pairs and build the heap. `words` must already have the
+    /// tokenizer's merges applied; new merges are appended after them.
+    fn new(tokenizer: &mut Tokenizer, words: Vec<Word>, counts: Vec<i32>, vocab_size: u32) -> Self {
+        assert!(vocab_size >= 256, "vocab_size must be >= 256");
+        let num_merges = vocab_size - 256;
+
+        // Initial pair counting, keeping only the `max_heap_pairs` most
+        // frequent pairs if set
+        let (pair_counts, mut where_to_update) = match tokenizer.max_heap_pairs {
+            Some(cap) => Tokenizer::count_pairs_capped(&words, &counts, cap),
+            None => Tokenizer::count_pairs_parallel(&words, &counts, tokenizer.pair_reduce),
+        };
+
+        let split_digits = tokenizer.split_digits;
+        let has_digit: Vec<bool> = if split_digits {
+            tokenizer
+                .token_bytes()
+                .iter()
+                .map(|bytes| bytes.iter().any(u8::is_ascii_digit))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let no_cross_script = tokenizer.merge_filter == MergeFilter::NoCrossScript;
+        let (expansions, scripts): (Vec<Vec<u8>>, Vec<Script>) = if no_cross_script {
+            let table = tokenizer.token_bytes().to_vec();
+            let scripts = table.iter().map(|bytes| Script::of_bytes(bytes)).collect();
+            (table, scripts)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        let tie_break = tokenizer.tie_break;
+        let token_lens: Vec<usize> = if tie_break == TieBreak::ShorterToken {
+            tokenizer.token_bytes().iter().map(Vec::len).collect()
+        } else {
+            Vec::new()
+        };
+
+        // Build priority queue
+        let mut heap = OctonaryHeap::with_capacity(pair_counts.len());
+        for (pair, pos) in where_to_update.drain() {
+            if let Some(&c) = pair_counts.get(&pair) {
+                if c > 0 {
+                    heap.push(MergeJob {
+                        pair,
+                        count: c as u64,
+                        tie: tie_key(tie_break, &token_lens, pair, &pos),
+                        pos,
+                    });
+                }
+            }
+        }
+
+        // Pre-allocate merge storage
+        let merges_done = tokenizer.merges.len() as u32;
+        tokenizer
+            .merges
+            .reserve(num_merges.saturating_sub(merges_done) as usize);
+
+        Self {
+            words,
+            counts,
+            pair_counts,
+            heap,
+            local_updates: AHashMap::with_capacity(1000),
+            merges_done,
+            num_merges,
+            split_digits,
+            has_digit,
+            no_cross_script,
+            expansions,
+            scripts,
+            tie_break,
+            token_lens,
+            #[cfg(feature = "trace")]
+            idle_pops: 0,
+        }
+    }
+
+    /// Learn the next merge, add it to `tokenizer` and return it as
+    /// `(pair, id)`; `None` once `vocab_size` is reached or no pair is left.
+    /// Fails if `tokenizer` doesn't hold exactly the merges this session has
+    /// produced so far, i.e. it isn't the one the session started from or
+    /// was modified since.
+    pub fn step(
+        &mut self,
+        tokenizer: &mut Tokenizer,
+    ) -> TokenizerResult<Option<((u32, u32), u32)>> {
+        if tokenizer.merges.len() as u32 != self.merges_done {
+            return Err(TokenizerError::Value(format!(
+                "Tokenizer has {} merges but the training session expects {}",
+                tokenizer.merges.len(),
+                self.merges_done
+            )));
+        }
+        Ok(self.advance(tokenizer))
+    }
+
+    /// `step` without the check, for `train_core`, which owns the session
+    fn advance(&mut self, tokenizer: &mut Tokenizer) -> Option<((u32, u32), u32)> {
+        while self.merges_done < self.num_merges {
+            let Some(mut top) = self.heap.pop() else {
+                #[cfg(feature = "trace")]
+                trace_heap(
+                    &self.heap,
+                    self.merges_done,
+                    "heap exhausted before vocab_size",
+                );
+                return None;
+            };
+
+            #[cfg(feature = "trace")]
+            {
+                self.idle_pops += 1;
+                if self.idle_pops == TRACE_STALL_POPS {
+                    trace_heap(
+                        &self.heap,
+                        self.merges_done,
+                        "no merge in TRACE_STALL_POPS pops",
+                    );
+                    self.idle_pops = 0;
+                }
+            }
+
+            // Lazy staleness check
+            let current = self.pair_counts.get(&top.pair).copied().unwrap_or(0);
+            if top.count != current as u64 {
+                if current > 0 {
+                    top.count = current as u64;
+                    self.heap.push(top);
+                }
+                continue;
+            }
+
+            // Digits merge with anything but another digit-bearing token,
+            // so no token ever holds more than one digit
+            let (a, b) = (top.pair.0 as usize, top.pair.1 as usize);
+            if self.split_digits && self.has_digit[a] && self.has_digit[b] {
+                continue;
+            }
+            if self.no_cross_script && self.scripts[a].clashes(self.scripts[b]) {
+                continue;
+            }
+
+            // Record merge
+            let new_id = 256 + self.merges_done;
+            tokenizer.merges.insert(top.pair, new_id);
+            tokenizer.clear_caches();
+            #[cfg(feature = "trace")]
+            {
+                self.idle_pops = 0;
+            }
+            if self.split_digits {
+                let digit = self.has_digit[a] || self.has_digit[b];
+                self.has_digit.resize(new_id as usize + 1, false);
+                self.has_digit[new_id as usize] = digit;
+            }
+            if self.no_cross_script {
+                let bytes = [self.expansions[a].as_slice(), &self.expansions[b]].concat();
+                self.scripts.resize(new_id as usize + 1, Script::Neutral);
+                self.scripts[new_id as usize] = Script::of_bytes(&bytes);
+                self.expansions.resize(new_id as usize + 1, Vec::new());
+                self.expansions[new_id as usize] = bytes;
+            }
+            if self.tie_break == TieBreak::ShorterToken {
+                let len = self.token_lens[a] + self.token_lens[b];
+                self.token_lens.resize(new_id as usize + 1, 0);
+                self.token_lens[new_id as usize] = len;
+            }
+
+            // Clear and reuse local_updates buffer
+            self.local_updates.clear();
+
+            // Update affected words
+            for &word_idx in &top.pos {
+                let changes = self.words[word_idx].merge_pair(top.pair, new_id);
+                let word_count = self.counts[word_idx];
+
+                for (pair, delta) in changes {
+                    let total_change = delta * word_count;
+                    // A pair missing here was dropped by `max_heap_pairs`
+                    // (it stays dropped) unless it holds `new_id`, which
+                    // only ever gains
+                    match self.pair_counts.get_mut(&pair) {
+                        Some(count) => *count += total_change,
+                        None if delta > 0 => {
+                            self.pair_counts.insert(pair, total_change);
+                        }
+                        None => {}
+                    }
+
+                    if delta > 0 {
+                        self.local_updates.entry(pair).or_default().insert(word_idx);
+                    }
+                }
+            }
+
+            // Push updated pairs to heap
+            for (pair, pos) in self.local_updates.drain() {
+                if let Some(&cnt) = self.pair_counts.get(&pair) {
+                    if cnt > 0 {
+                        self.heap.push(MergeJob {
+                            pair,
+                            count: cnt as u64,
+                            tie: tie_key(self.tie_break, &self.token_lens, pair, &pos),
+                            pos,
+                        });
+                    }
+                }
+            }
+
+            self.merges_done += 1;
+            return Some((top.pair, new_id));
+        }
+        None
+    }
+
+    /// Merges the tokenizer holds so far, including any it started with
+    pub fn merges_done(&self) -> u32 {
+        self.merges_done
+    }
+
+    /// Current weighted count of every pair that still occurs
+    pub fn pair_counts(&self) -> StdHashMap<(u32, u32), i64> {
+        self.pair_counts
+            .iter()
+            .filter(|&(_, &c)| c > 0)
+            .map(|(&pair, &c)| (pair, c as i64))
+            .collect()
+    }
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new().unwrap()
+    }
+}
+// Rust unit tests are in `src/tests.rs`; with the `python` feature they
+// train through Python, otherwise through the same counting in Rust
+#[cfg(test)]
+mod tests;
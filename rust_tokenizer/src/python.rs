@@ -0,0 +1,1664 @@
+//! Python bindings. `PyTokenizer` is exported as `Tokenizer` and forwards to
+//! the Rust `Tokenizer`; training from Python iterables lives here because
+//! it needs the GIL.
+
+// pyo3 0.22's #[pymethods] expansion trips this lint on every `PyResult` return
+#![allow(clippy::useless_conversion)]
+
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use super::*;
+
+impl From<TokenizerError> for PyErr {
+    fn from(e: TokenizerError) -> Self {
+        match e {
+            TokenizerError::Value(msg) => PyValueError::new_err(msg),
+            TokenizerError::Io(e) => e.into(),
+        }
+    }
+}
+
+/// Runs of non-UTF-8 bytes from `bytes` training items, counted like
+/// chunks; they can't collide with text chunks, which are always UTF-8
+type RawChunkCounts = AHashMap<Vec<u8>, ChunkStat>;
+
+/// One training item: text, or `bytes` that are not valid UTF-8
+enum TrainingItem {
+    Text(String),
+    Raw(Vec<u8>),
+}
+
+/// A training item from `str` or `bytes`; `bytes` holding UTF-8 are text.
+/// Anything else is a `TypeError` naming the item.
+fn extract_item(item: &Bound<'_, PyAny>, index: u64) -> PyResult<TrainingItem> {
+    if let Ok(text) = item.extract::<String>() {
+        return Ok(TrainingItem::Text(text));
+    }
+    if let Ok(bytes) = item.downcast::<PyBytes>() {
+        return Ok(match String::from_utf8(bytes.as_bytes().to_vec()) {
+            Ok(text) => TrainingItem::Text(text),
+            Err(e) => TrainingItem::Raw(e.into_bytes()),
+        });
+    }
+    let type_name = item
+        .get_type()
+        .name()
+        .map_or_else(|_| "<unknown>".to_string(), |name| name.to_string());
+    Err(PyTypeError::new_err(format!(
+        "Training items must be str or bytes, but item {} is {}",
+        index, type_name
+    )))
+}
+
+impl Tokenizer {
+    /// Drain Python iterators of strings or bytes, in turn, into one set of
+    /// pretokenized chunk counts plus the counts of non-UTF-8 byte runs
+    fn count_from_iterators(
+        &self,
+        py: Python<'_>,
+        iterators: &[&Bound<'_, PyAny>],
+        buffer_size: usize,
+    ) -> PyResult<(ChunkCounts, RawChunkCounts)> {
+        // Pre-sized from the capacity hints
+        let hints = self.capacity_hints;
+        let mut global_counts = ChunkCounts::with_capacity(hints.unique_chunks);
+        let mut raw_counts = RawChunkCounts::default();
+        let mut texts_seen = 0u64;
+
+        for iterator in iterators {
+            texts_seen = self.count_into(
+                py,
+                iterator,
+                buffer_size,
+                (&mut global_counts, &mut raw_counts),
+                texts_seen,
+            )?;
+        }
+        Ok((global_counts, raw_counts))
+    }
+
+    /// `words_from_counts` over both halves of `count_from_iterators`
+    fn words_from_item_counts(
+        &self,
+        counts: &ChunkCounts,
+        raw_counts: &RawChunkCounts,
+    ) -> (Vec<Word>, Vec<i32>) {
+        self.words_from_chunks(
+            counts
+                .iter()
+                .map(|(chunk, &stat)| (chunk.as_bytes(), stat))
+                .chain(raw_counts.iter().map(|(run, &stat)| (run.as_slice(), stat)))
+                .collect(),
+        )
+    }
+
+    /// Count one non-UTF-8 `bytes` item: its valid stretches are pretokenized
+    /// like text, and each run of invalid bytes between them is one chunk
+    fn count_raw_item(
+        &self,
+        bytes: &[u8],
+        text_idx: u64,
+    ) -> Result<(ChunkCounts, RawChunkCounts), Box<fancy_regex::Error>> {
+        let mut counts = ChunkCounts::default();
+        let mut raw_counts = RawChunkCounts::default();
+        let mut chunk_idx = 0u32;
+        let mut next_stat = || {
+            chunk_idx += 1;
+            ChunkStat {
+                count: 1,
+                first_seen: (text_idx, chunk_idx - 1),
+            }
+        };
+
+        let mut run = Vec::new();
+        for piece in bytes.utf8_chunks() {
+            if !piece.valid().is_empty() && !run.is_empty() {
+                let stat = next_stat();
+                raw_counts
+                    .entry(std::mem::take(&mut run))
+                    .and_modify(|s| s.absorb(stat))
+                    .or_insert(stat);
+            }
+            let text = self.normalize(piece.valid());
+            let chunks = Chunks::new(
+                &self.compiled_pattern,
+                &text,
+                self.whitespace_chunk_width,
+                self.split_leading_space,
+            );
+            for m in chunks {
+                let (_, chunk) = m?;
+                let key = self
+                    .word_marker
+                    .as_ref()
+                    .map_or_else(|| CompactString::from(chunk), |w| w.apply(chunk));
+                let stat = next_stat();
+                counts
+                    .entry(key)
+                    .and_modify(|s| s.absorb(stat))
+                    .or_insert(stat);
+            }
+            run.extend_from_slice(piece.invalid());
+        }
+        if !run.is_empty() {
+            let stat = next_stat();
+            raw_counts
+                .entry(run)
+                .and_modify(|s| s.absorb(stat))
+                .or_insert(stat);
+        }
+        Ok((counts, raw_counts))
+    }
+
+    /// Count one iterator into `global_counts`, numbering its texts from
+    /// `texts_seen`; returns the updated text count
+    fn count_into(
+        &self,
+        py: Python<'_>,
+        iterator: &Bound<'_, PyAny>,
+        buffer_size: usize,
+        (global_counts, raw_counts): (&mut ChunkCounts, &mut RawChunkCounts),
+        mut texts_seen: u64,
+    ) -> PyResult<u64> {
+        let mut py_iter = iterator.iter()?;
+        let mut buffer = Vec::with_capacity(buffer_size);
+        // Non-UTF-8 items, by index; each holds an empty slot in `buffer`
+        let mut raw_items = Vec::new();
+
+        loop {
+            buffer.clear();
+            raw_items.clear();
+
+            // Fill buffer (holds GIL)
+            let mut exhausted = false;
+            for _ in 0..buffer_size {
+                match py_iter.next() {
+                    Some(Ok(item)) => {
+                        let index = texts_seen + buffer.len() as u64;
+                        match extract_item(&item, index)? {
+                            TrainingItem::Text(text) => buffer.push(text),
+                            TrainingItem::Raw(bytes) => {
+                                raw_items.push((index, bytes));
+                                buffer.push(String::new());
+                            }
+                        }
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        exhausted = true;
+                        break;
+                    }
+                }
+            }
+
+            if buffer.is_empty() {
+                break;
+            }
+
+            // Process batch in parallel (releases GIL)
+            let batch_counts = py
+                .allow_threads(|| self.count_batch(&self.compiled_pattern, &buffer, texts_seen))
+                .map_err(regex_error)?;
+            texts_seen += buffer.len() as u64;
+
+            // Merge into global counts
+            for (k, v) in batch_counts {
+                global_counts
+                    .entry(k)
+                    .and_modify(|s| s.absorb(v))
+                    .or_insert(v);
+            }
+            for (index, bytes) in &raw_items {
+                let (counts, raw) = self.count_raw_item(bytes, *index).map_err(regex_error)?;
+                for (k, v) in counts {
+                    global_counts
+                        .entry(k)
+                        .and_modify(|s| s.absorb(v))
+                        .or_insert(v);
+                }
+                for (k, v) in raw {
+                    raw_counts.entry(k).and_modify(|s| s.absorb(v)).or_insert(v);
+                }
+            }
+
+            if exhausted {
+                break;
+            }
+        }
+
+        Ok(texts_seen)
+    }
+
+    /// Main training entry point - heavily optimized for throughput.
+    /// Discards any existing merges; see `resume_training` to continue instead.
+    pub fn train_from_iterator(
+        &mut self,
+        py: Python<'_>,
+        iterator: &Bound<'_, PyAny>,
+        vocab_size: u32,
+        buffer_size: usize,
+    ) -> PyResult<()> {
+        check_vocab_size(vocab_size).map_err(PyValueError::new_err)?;
+        let (global_counts, raw_counts) =
+            self.count_from_iterators(py, &[iterator], buffer_size)?;
+        self.last_train_unique_chunks = global_counts.len() + raw_counts.len();
+        let (words, counts) = self.words_from_item_counts(&global_counts, &raw_counts);
+        check_vocab_reachable(&words, 0, vocab_size).map_err(PyValueError::new_err)?;
+
+        // Run BPE
+        self.merges.clear();
+        self.train_core(words, counts, vocab_size)?;
+        Ok(())
+    }
+
+    /// Like `train_from_iterator`, but counts several iterators (e.g. corpus
+    /// shards) into one vocabulary, draining each in turn
+    pub fn train_from_iterators(
+        &mut self,
+        py: Python<'_>,
+        iterators: Vec<Bound<'_, PyAny>>,
+        vocab_size: u32,
+        buffer_size: usize,
+    ) -> PyResult<()> {
+        check_vocab_size(vocab_size).map_err(PyValueError::new_err)?;
+        let iterators: Vec<&Bound<'_, PyAny>> = iterators.iter().collect();
+        let (global_counts, raw_counts) = self.count_from_iterators(py, &iterators, buffer_size)?;
+        self.last_train_unique_chunks = global_counts.len() + raw_counts.len();
+        let (words, counts) = self.words_from_item_counts(&global_counts, &raw_counts);
+        check_vocab_reachable(&words, 0, vocab_size).map_err(PyValueError::new_err)?;
+
+        self.merges.clear();
+        self.train_core(words, counts, vocab_size)?;
+        Ok(())
+    }
+
+    /// Continue training on top of the merges already loaded (typically from
+    /// a checkpoint written during an interrupted run).
+    ///
+    /// Contract: existing merge IDs must be contiguous from 256, and the corpus
+    /// must be the one the partial merges were trained on. The corpus is
+    /// re-counted, existing merges are replayed onto every chunk, and training
+    /// proceeds until `vocab_size`. The result equals an uninterrupted run.
+    ///
+    /// With `frozen_until_id`, merges with IDs below it are kept byte-for-byte,
+    /// any later ones are discarded, and new merges are numbered from
+    /// `frozen_until_id`, so the result extends the frozen vocabulary.
+    pub fn resume_training(
+        &mut self,
+        py: Python<'_>,
+        iterator: &Bound<'_, PyAny>,
+        vocab_size: u32,
+        buffer_size: usize,
+        frozen_until_id: Option<u32>,
+    ) -> PyResult<()> {
+        check_vocab_size(vocab_size).map_err(PyValueError::new_err)?;
+        let frozen = frozen_until_id
+            .map(|id| self.frozen_merges(id))
+            .transpose()?;
+
+        let (global_counts, raw_counts) =
+            self.count_from_iterators(py, &[iterator], buffer_size)?;
+        let unique_chunks = global_counts.len() + raw_counts.len();
+        let (mut words, counts) = self.words_from_item_counts(&global_counts, &raw_counts);
+
+        // Re-derive each chunk's state after the merges that are kept
+        let kept = frozen.as_ref().unwrap_or(&self.merges);
+        words
+            .par_iter_mut()
+            .for_each(|w| apply_merges_of(kept, &mut w.ids, |_, _| {}));
+        check_vocab_reachable(&words, kept.len(), vocab_size).map_err(PyValueError::new_err)?;
+
+        // Only drop merges once nothing can fail before training starts
+        self.last_train_unique_chunks = unique_chunks;
+        if let Some(frozen) = frozen {
+            self.merges = frozen;
+            self.clear_caches();
+        }
+        self.train_core(words, counts, vocab_size)?;
+        Ok(())
+    }
+
+    /// Encode `text` straight into `buffer`, any writable C-contiguous
+    /// `uint32` buffer (a NumPy array, `array.array("I")`, ...), skipping the
+    /// intermediate list. Tokens fill it from the start and the number
+    /// written is returned. Tokens past its end are dropped, or with
+    /// `error_on_overflow` raise `ValueError` with the buffer left untouched.
+    pub fn encode_into_buffer(
+        &self,
+        py: Python<'_>,
+        text: &str,
+        buffer: &Bound<'_, PyAny>,
+        error_on_overflow: bool,
+    ) -> PyResult<usize> {
+        let buffer = PyBuffer::<u32>::get_bound(buffer)?;
+        let cells = buffer
+            .as_mut_slice(py)
+            .ok_or_else(|| PyValueError::new_err("buffer must be writable and C-contiguous"))?;
+        let ids = self.try_encode(text)?;
+        if error_on_overflow && ids.len() > cells.len() {
+            return Err(PyValueError::new_err(format!(
+                "{} tokens do not fit in a buffer of {}",
+                ids.len(),
+                cells.len()
+            )));
+        }
+        // zip stops at the shorter side, so nothing is written past the end
+        for (cell, &id) in cells.iter().zip(&ids) {
+            cell.set(id);
+        }
+        Ok(ids.len().min(cells.len()))
+    }
+}
+
+#[pyclass(name = "Tokenizer")]
+pub struct PyTokenizer {
+    inner: Tokenizer,
+}
+
+impl From<Tokenizer> for PyTokenizer {
+    fn from(inner: Tokenizer) -> Self {
+        Self { inner }
+    }
+}
+
+#[pymethods]
+impl PyTokenizer {
+    /// Python constructor; omitted hints fall back to `CapacityHints::default()`
+    #[new]
+    #[pyo3(signature = (expected_vocab_size=None, expected_unique_chunks=None))]
+    fn new(
+        expected_vocab_size: Option<usize>,
+        expected_unique_chunks: Option<usize>,
+    ) -> PyResult<Self> {
+        let mut hints = CapacityHints::default();
+        if let Some(vocab) = expected_vocab_size {
+            hints.merges = vocab.saturating_sub(256);
+        }
+        if let Some(chunks) = expected_unique_chunks {
+            hints.unique_chunks = chunks;
+        }
+        Ok(Tokenizer::with_capacity_hints(hints)?.into())
+    }
+
+    /// Held-out texts encoded after each training run; see
+    /// `last_train_validation_ratio`
+    #[getter]
+    fn validation_texts(&self) -> Vec<String> {
+        self.inner.validation_texts.clone()
+    }
+
+    #[setter]
+    fn set_validation_texts(&mut self, value: Vec<String>) {
+        self.inner.validation_texts = value;
+    }
+
+    /// Keep the pair-frequency map left over at the end of training
+    #[getter]
+    fn retain_pair_counts(&self) -> bool {
+        self.inner.retain_pair_counts
+    }
+
+    #[setter]
+    fn set_retain_pair_counts(&mut self, value: bool) {
+        self.inner.retain_pair_counts = value;
+    }
+
+    /// When set, training periodically writes a `to_bytes` snapshot here
+    #[getter]
+    fn checkpoint_path(&self) -> Option<String> {
+        self.inner.checkpoint_path.clone()
+    }
+
+    #[setter]
+    fn set_checkpoint_path(&mut self, value: Option<String>) {
+        self.inner.checkpoint_path = value;
+    }
+
+    /// Merges between checkpoints (0 disables checkpointing)
+    #[getter]
+    fn checkpoint_every(&self) -> u32 {
+        self.inner.checkpoint_every
+    }
+
+    #[setter]
+    fn set_checkpoint_every(&mut self, value: u32) {
+        self.inner.checkpoint_every = value;
+    }
+
+    /// Ceiling on training speed for shared hardware: every 64 merges,
+    /// training sleeps until it is back under this rate, trading wall-clock
+    /// time for lower CPU pressure. `None` (or a non-positive rate) trains
+    /// unthrottled.
+    #[getter]
+    fn max_merges_per_second(&self) -> Option<f64> {
+        self.inner.max_merges_per_second
+    }
+
+    #[setter]
+    fn set_max_merges_per_second(&mut self, value: Option<f64>) {
+        self.inner.max_merges_per_second = value;
+    }
+
+    /// Bound on training memory: only this many of the most frequent pairs
+    /// are tracked, plus pairs later merges create; dropped pairs never get
+    /// word-position sets. Approximate, since a dropped pair is never
+    /// learned; `None` keeps all.
+    #[getter]
+    fn max_heap_pairs(&self) -> Option<usize> {
+        self.inner.max_heap_pairs
+    }
+
+    #[setter]
+    fn set_max_heap_pairs(&mut self, value: Option<usize>) {
+        self.inner.max_heap_pairs = value;
+    }
+
+    /// Cut whitespace-only chunks into pieces of at most this many chars;
+    /// 0 leaves them whole
+    #[getter]
+    fn whitespace_chunk_width(&self) -> usize {
+        self.inner.whitespace_chunk_width
+    }
+
+    #[setter]
+    fn set_whitespace_chunk_width(&mut self, value: usize) {
+        self.inner.whitespace_chunk_width = value;
+    }
+
+    /// Emit the space before a word as its own `' '` chunk, so `" hello"`
+    /// and `"hello"` share one token
+    #[getter]
+    fn split_leading_space(&self) -> bool {
+        self.inner.split_leading_space
+    }
+
+    #[setter]
+    fn set_split_leading_space(&mut self, value: bool) {
+        self.inner.split_leading_space = value;
+    }
+
+    /// Encode to base byte IDs (and specials) only, skipping the merge loop
+    /// even if merges are loaded: a fast character-level baseline with the
+    /// same API. Training ignores this.
+    #[getter]
+    fn byte_level_only(&self) -> bool {
+        self.inner.byte_level_only
+    }
+
+    #[setter]
+    fn set_byte_level_only(&mut self, value: bool) {
+        self.inner.byte_level_only = value;
+    }
+
+    /// Drop a U+FEFF byte order mark at the start of each text before
+    /// training or encoding; decode cannot bring it back
+    #[getter]
+    fn strip_bom(&self) -> bool {
+        self.inner.strip_bom
+    }
+
+    #[setter]
+    fn set_strip_bom(&mut self, value: bool) {
+        self.inner.strip_bom = value;
+    }
+
+    /// Drop zero-width characters (U+200B, U+200C, U+200D, U+FEFF) anywhere
+    /// in a text before training or encoding; decode cannot bring them back
+    #[getter]
+    fn strip_zero_width(&self) -> bool {
+        self.inner.strip_zero_width
+    }
+
+    #[setter]
+    fn set_strip_zero_width(&mut self, value: bool) {
+        self.inner.strip_zero_width = value;
+    }
+
+    /// Training skips merges that would put two ASCII digits in one token
+    #[getter]
+    fn split_digits(&self) -> bool {
+        self.inner.split_digits
+    }
+
+    #[setter]
+    fn set_split_digits(&mut self, value: bool) {
+        self.inner.split_digits = value;
+    }
+
+    /// How training picks among equally frequent pairs
+    #[getter]
+    fn tie_break(&self) -> TieBreak {
+        self.inner.tie_break
+    }
+
+    #[setter]
+    fn set_tie_break(&mut self, value: TieBreak) {
+        self.inner.tie_break = value;
+    }
+
+    /// Which merges training skips
+    #[getter]
+    fn merge_filter(&self) -> MergeFilter {
+        self.inner.merge_filter
+    }
+
+    #[setter]
+    fn set_merge_filter(&mut self, value: MergeFilter) {
+        self.inner.merge_filter = value;
+    }
+
+    /// How training merges parallel pair counts; a performance knob only
+    #[getter]
+    fn pair_reduce(&self) -> PairReduce {
+        self.inner.pair_reduce
+    }
+
+    #[setter]
+    fn set_pair_reduce(&mut self, value: PairReduce) {
+        self.inner.pair_reduce = value;
+    }
+
+    /// Batch sizes from which the methods taking many texts (`encode_batch`,
+    /// `token_histogram`, `specialize` and the like) run in parallel
+    /// (default 100); results are the same either way
+    #[getter]
+    fn parallel_threshold(&self) -> usize {
+        self.inner.parallel_threshold
+    }
+
+    #[setter]
+    fn set_parallel_threshold(&mut self, value: usize) {
+        self.inner.parallel_threshold = value;
+    }
+
+    /// What `encode` does with chunks longer than `max_chunk_bytes`: merge
+    /// them whole, merge each window separately, or raise `ValueError`
+    #[getter]
+    fn overlong_chunk_policy(&self) -> OverlongChunkPolicy {
+        self.inner.overlong_chunk_policy
+    }
+
+    #[setter]
+    fn set_overlong_chunk_policy(&mut self, value: OverlongChunkPolicy) {
+        self.inner.overlong_chunk_policy = value;
+    }
+
+    /// Chunk length in bytes (word marker included) above which
+    /// `overlong_chunk_policy` applies, and its window size
+    #[getter]
+    fn max_chunk_bytes(&self) -> usize {
+        self.inner.max_chunk_bytes
+    }
+
+    #[setter]
+    fn set_max_chunk_bytes(&mut self, value: usize) {
+        self.inner.max_chunk_bytes = value;
+    }
+
+    /// What decoding does with IDs this tokenizer doesn't know: skip them,
+    /// emit `<unk_special:ID>`, or raise `ValueError`
+    #[getter]
+    fn unknown_id_policy(&self) -> UnknownIdPolicy {
+        self.inner.unknown_id_policy
+    }
+
+    #[setter]
+    fn set_unknown_id_policy(&mut self, value: UnknownIdPolicy) {
+        self.inner.unknown_id_policy = value;
+    }
+
+    /// Main training entry point; discards any existing merges
+    #[pyo3(signature = (iterator, vocab_size, buffer_size=10_000))]
+    fn train_from_iterator(
+        &mut self,
+        py: Python<'_>,
+        iterator: &Bound<'_, PyAny>,
+        vocab_size: u32,
+        buffer_size: usize,
+    ) -> PyResult<()> {
+        self.inner
+            .train_from_iterator(py, iterator, vocab_size, buffer_size)
+    }
+
+    /// Like `train_from_iterator`, but counts several iterators (e.g. corpus
+    /// shards) into one vocabulary, draining each in turn
+    #[pyo3(signature = (iterators, vocab_size, buffer_size=10_000))]
+    fn train_from_iterators(
+        &mut self,
+        py: Python<'_>,
+        iterators: Vec<Bound<'_, PyAny>>,
+        vocab_size: u32,
+        buffer_size: usize,
+    ) -> PyResult<()> {
+        self.inner
+            .train_from_iterators(py, iterators, vocab_size, buffer_size)
+    }
+
+    /// Train from scratch on a precomputed `{chunk: count}` table, skipping
+    /// the corpus scan. Keys are taken as already pretokenized chunks (not
+    /// normalized or split by the pattern); discards any existing merges.
+    fn train_from_counts(
+        &mut self,
+        py: Python<'_>,
+        counts: StdHashMap<String, u32>,
+        vocab_size: u32,
+    ) -> PyResult<()> {
+        let inner = &mut self.inner;
+        Ok(py.allow_threads(|| inner.train_from_counts(counts, vocab_size))?)
+    }
+
+    /// Train from scratch on text files, one text per line; each file is
+    /// memory-mapped rather than read into memory
+    #[cfg(not(target_arch = "wasm32"))]
+    #[pyo3(signature = (paths, vocab_size, buffer_size=10_000))]
+    fn train_from_files(
+        &mut self,
+        py: Python<'_>,
+        paths: Vec<String>,
+        vocab_size: u32,
+        buffer_size: usize,
+    ) -> PyResult<()> {
+        let inner = &mut self.inner;
+        Ok(py.allow_threads(|| inner.train_from_files(&paths, vocab_size, buffer_size))?)
+    }
+
+    /// Continue training on top of the merges already loaded (typically from
+    /// a checkpoint written during an interrupted run).
+    #[pyo3(signature = (iterator, vocab_size, buffer_size=10_000, frozen_until_id=None))]
+    fn resume_training(
+        &mut self,
+        py: Python<'_>,
+        iterator: &Bound<'_, PyAny>,
+        vocab_size: u32,
+        buffer_size: usize,
+        frozen_until_id: Option<u32>,
+    ) -> PyResult<()> {
+        self.inner
+            .resume_training(py, iterator, vocab_size, buffer_size, frozen_until_id)
+    }
+
+    /// Encode, raising `ValueError` if the pattern fails at runtime
+    fn encode(&self, text: &str) -> PyResult<Vec<u32>> {
+        Ok(self.inner.try_encode(text)?)
+    }
+
+    /// Whether `a` and `b` encode to the same tokens. Both are encoded a
+    /// chunk at a time in lockstep, stopping at the first difference, so
+    /// inputs that diverge early cost little.
+    fn same_tokens(&self, a: &str, b: &str) -> bool {
+        self.inner.same_tokens(a, b)
+    }
+
+    /// Encode `text` straight into `buffer`, any writable C-contiguous
+    /// `uint32` buffer (a NumPy array, `array.array("I")`, ...), skipping the
+    /// intermediate list. Tokens fill it from the start and the number
+    /// written is returned. Tokens past its end are dropped, or with
+    /// `error_on_overflow` raise `ValueError` with the buffer left untouched.
+    #[pyo3(signature = (text, buffer, error_on_overflow = false))]
+    fn encode_into_buffer(
+        &self,
+        py: Python<'_>,
+        text: &str,
+        buffer: &Bound<'_, PyAny>,
+        error_on_overflow: bool,
+    ) -> PyResult<usize> {
+        self.inner
+            .encode_into_buffer(py, text, buffer, error_on_overflow)
+    }
+
+    /// Serialize to `bytes`; see `from_bytes`
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.inner.to_bytes())
+    }
+
+    /// Decode token IDs to raw bytes (returned as `bytes`); raises on tokens
+    /// expanding past 1 MiB and on output past 1 GiB
+    fn decode_bytes<'py>(&self, py: Python<'py>, ids: Vec<u32>) -> PyResult<Bound<'py, PyBytes>> {
+        Ok(PyBytes::new_bound(py, &self.inner.try_decode_bytes(&ids)?))
+    }
+
+    /// Decoded text made safe for single-line logs: control bytes, invalid
+    /// UTF-8 and (in `EscapeMode.Strict`) non-ASCII text are escaped,
+    /// ordinary text is left intact. Unlike `debug_tokens`, tokens are not
+    /// marked off.
+    #[pyo3(signature = (ids, mode = EscapeMode::Display))]
+    fn decode_escaped(&self, ids: Vec<u32>, mode: EscapeMode) -> String {
+        self.inner.decode_escaped(&ids, mode)
+    }
+
+    /// Readable one-line rendering of token IDs, e.g. `[the]·[ ]·[<|eot|>]`
+    fn debug_tokens(&self, ids: Vec<u32>) -> String {
+        self.inner.debug_tokens(&ids)
+    }
+
+    fn register_special_token(&mut self, token: String, id: u32) {
+        self.inner.register_special_token(token, id)
+    }
+
+    /// The special token `encode` emits as `id`, if any. Protected literal
+    /// tokens are not special and give `None`.
+    fn special_token_for_id(&self, id: u32) -> Option<String> {
+        self.inner.special_token_for_id(id)
+    }
+
+    /// Each special token in `text` with its byte offset, in order, found
+    /// with the same leftmost-longest, non-overlapping matching `encode`
+    /// uses, but without encoding anything else. Registered specials are
+    /// reported by their registered string (even if matched case
+    /// insensitively), special patterns by the matched text; protected
+    /// literal tokens are not special and are left out.
+    fn scan_specials(&self, text: &str) -> Vec<(String, usize)> {
+        self.inner.scan_specials(text)
+    }
+
+    /// Whether `id` is emitted for special tokens, by a registered string
+    /// or by a special pattern
+    fn is_special_id(&self, id: u32) -> bool {
+        self.inner.is_special_id(id)
+    }
+
+    /// `register_special_token` with `policy` deciding what happens when
+    /// `token` is already registered with another ID
+    #[pyo3(signature = (token, id, policy = SpecialConflict::Overwrite))]
+    fn register_special_token_with_policy(
+        &mut self,
+        token: String,
+        id: u32,
+        policy: SpecialConflict,
+    ) -> PyResult<()> {
+        Ok(self
+            .inner
+            .register_special_token_with_policy(token, id, policy)?)
+    }
+
+    /// Register several special tokens in order, resolving clashes with
+    /// existing or earlier strings by `policy`. With `SpecialConflict::Error`
+    /// all of them are checked first, so a clash registers none. The special
+    /// matcher is rebuilt once, on the next encode, not per token.
+    #[pyo3(signature = (tokens, policy = SpecialConflict::Overwrite))]
+    fn register_special_tokens(
+        &mut self,
+        tokens: Vec<(String, u32)>,
+        policy: SpecialConflict,
+    ) -> PyResult<()> {
+        Ok(self.inner.register_special_tokens(tokens, policy)?)
+    }
+
+    /// Register a special token that also matches any casing of `token`
+    /// (e.g. `<|EOT|>` for `<|eot|>`), still emitting `id`
+    fn register_special_token_case_insensitive(&mut self, token: String, id: u32) {
+        self.inner
+            .register_special_token_case_insensitive(token, id)
+    }
+
+    /// Protect `token` so encode always emits it as the single ID `id`, even
+    /// where BPE would split it. Protected literals are found in the raw text
+    /// like specials but lose to a special token starting at the same
+    /// position, and they also apply in `encode_ordinary`. `id` must not
+    /// already name a byte, a merge, a special token or another literal, so
+    /// decode can expand it to `token` unambiguously.
+    fn register_literal_token(&mut self, token: String, id: u32) -> PyResult<()> {
+        Ok(self.inner.register_literal_token(token, id)?)
+    }
+
+    /// Register a special token family: any non-empty match of `regex` in the
+    /// input encodes to `id`. Precedence during special splitting: the match
+    /// starting earliest wins; at the same start a literal special beats any
+    /// pattern, and earlier-registered patterns beat later ones. Pattern IDs
+    /// decode to nothing unless also registered as a literal special.
+    fn register_special_pattern(&mut self, regex: String, id: u32) -> PyResult<()> {
+        Ok(self.inner.register_special_pattern(regex, id)?)
+    }
+
+    /// Forget all training so the tokenizer can be retrained from scratch.
+    /// Clears the merges and per-run statistics and restores the default
+    /// capacity hints; the pattern, alphabet and encode options are kept, so
+    /// the regex is not recompiled. With `clear_specials`, special tokens,
+    /// special patterns and literal tokens are dropped too.
+    #[pyo3(signature = (clear_specials=false))]
+    fn reset(&mut self, clear_specials: bool) {
+        self.inner.reset(clear_specials)
+    }
+
+    /// Distinct pretokenized chunks counted by the last training run.
+    /// Much larger than expected usually means the regex is over-splitting.
+    fn last_train_unique_chunks(&self) -> usize {
+        self.inner.last_train_unique_chunks()
+    }
+
+    /// Whether the last training run reached its `vocab_size`. Training
+    /// stops early, without an error, once no mergeable pair is left; false
+    /// before any training.
+    fn last_train_reached_target(&self) -> bool {
+        self.inner.last_train_reached_target()
+    }
+
+    /// `compression_ratio` of `validation_texts` under the vocab the last
+    /// training run produced; `None` if no validation texts were set then
+    fn last_train_validation_ratio(&self) -> Option<f64> {
+        self.inner.last_train_validation_ratio()
+    }
+
+    /// Rough peak memory in bytes for training on `num_unique_chunks` distinct
+    /// chunks of `avg_chunk_len` bytes each. Assumptions:
+    /// - every chunk contributes `avg_chunk_len - 1` pair positions, and the
+    ///   distinct initial pairs are capped at 256 * 256 byte pairs;
+    /// - hash tables cost about twice (entry + control byte) per entry;
+    /// - position sets are held twice over: once when seeding the heap and
+    ///   again as merges re-push sets for newly formed pairs;
+    /// - every merge leaves one new heap entry and pair-count entry behind.
+    #[staticmethod]
+    fn estimate_training_memory(
+        num_unique_chunks: usize,
+        avg_chunk_len: usize,
+        vocab_size: u32,
+    ) -> usize {
+        Tokenizer::estimate_training_memory(num_unique_chunks, avg_chunk_len, vocab_size)
+    }
+
+    /// Load a checkpoint file written during training (see `checkpoint_path`)
+    #[staticmethod]
+    fn load_checkpoint(path: &str) -> PyResult<Self> {
+        Ok(Tokenizer::load_checkpoint(path)?.into())
+    }
+
+    /// Encode using an explicit segmentation strategy. `EarliestMerge` is
+    /// identical to `encode`; `LongestToken` is offered for comparison.
+    #[pyo3(signature = (text, strategy=EncodeStrategy::EarliestMerge))]
+    fn encode_with_strategy(&self, text: &str, strategy: EncodeStrategy) -> PyResult<Vec<u32>> {
+        Ok(self.inner.try_encode_with_strategy(text, strategy)?)
+    }
+
+    /// Encode without looking up special tokens; for pre-sanitized input.
+    /// Protected `literal_tokens` still apply.
+    fn encode_ordinary(&self, text: &str) -> PyResult<Vec<u32>> {
+        Ok(self.inner.try_encode_ordinary(text)?)
+    }
+
+    /// `encode` with every merge ignored: each chunk stays its base byte
+    /// IDs (word marker included), while specials and protected literals
+    /// still map to their IDs. A no-compression baseline for `encode`.
+    fn encode_bytes_only(&self, text: &str) -> Vec<u32> {
+        self.inner.encode_bytes_only(text)
+    }
+
+    /// Last token of `encode(text)`, running BPE on the final chunk only.
+    fn last_token(&self, text: &str) -> Option<u32> {
+        self.inner.last_token(text)
+    }
+
+    /// Segmentation of `text` as one string per token; see `encode_pieces_compact`
+    fn encode_to_pieces(&self, text: &str) -> Vec<String> {
+        self.inner.encode_to_pieces(text)
+    }
+
+    /// Encode and return the byte span `(start, end)` of each token in `text`.
+    /// With `trim_offsets`, leading whitespace is excluded from word tokens'
+    /// spans (whitespace-only tokens keep their full span). Spans index the
+    /// text as given; characters `strip_bom` and `strip_zero_width` drop
+    /// between two tokens fall outside both spans.
+    #[pyo3(signature = (text, trim_offsets=false))]
+    fn encode_with_offsets(&self, text: &str, trim_offsets: bool) -> PyResult<TokenOffsets> {
+        Ok(self.inner.try_encode_with_offsets(text, trim_offsets)?)
+    }
+
+    /// The last `max_len` tokens of `encode(text)`. Pretokenization still
+    /// scans the whole text, but merging runs back to front over the chunks
+    /// and stops once `max_len` tokens are collected, so the discarded head
+    /// is never merged. Chunks merge independently, so the result is exact.
+    fn encode_truncate_left(&self, text: &str, max_len: usize) -> PyResult<Vec<u32>> {
+        Ok(self.inner.try_encode_truncate_left(text, max_len)?)
+    }
+
+    /// Encode chunks in order until `budget` tokens are produced, returning
+    /// them with the number of input bytes consumed. Only whole chunks are
+    /// taken, so encoding `&text[consumed..]` next continues where this left
+    /// off; the one exception is a first chunk alone longer than the budget,
+    /// which is cut after the last whole character within `budget` tokens so
+    /// that progress is always made. If even one character takes more tokens
+    /// than `budget` (e.g. 1 for a 2-byte character without a merge), that
+    /// character is returned whole, over the budget; a budget of 0 always
+    /// consumes nothing.
+    fn encode_budget(&self, text: &str, budget: usize) -> PyResult<(Vec<u32>, usize)> {
+        Ok(self.inner.try_encode_budget(text, budget)?)
+    }
+
+    /// Input bytes `encode_budget(text, token_budget)` consumes, for cutting
+    /// a document into pieces of about `token_budget` tokens: each piece ends
+    /// at a chunk boundary (or, inside an overlong chunk, a character
+    /// boundary), so `&text[n..]` continues with the next one. Nonzero for a
+    /// nonempty `text` unless `token_budget` is 0.
+    fn bytes_for_token_budget(&self, text: &str, token_budget: usize) -> PyResult<usize> {
+        Ok(self.inner.try_encode_budget(text, token_budget)?.1)
+    }
+
+    /// Encode a sequence pair as `a`, `sep_id`, `b`, with BERT-style type IDs:
+    /// 0 for the tokens of `a` and the separator, 1 for the tokens of `b`
+    fn encode_pair(&self, a: &str, b: &str, sep_id: u32) -> (Vec<u32>, Vec<u8>) {
+        self.inner.encode_pair(a, b, sep_id)
+    }
+
+    /// Up to `k` distinct segmentations of `text`. The first is always
+    /// `encode(text)`; the rest apply merges in orders other than
+    /// lowest-rank-first, found by a beam search of width `k` over each
+    /// chunk, and are ranked by the total rank of the merges they apply,
+    /// lowest first.
+    fn encode_nbest(&self, text: &str, k: usize) -> PyResult<Vec<Vec<u32>>> {
+        Ok(self.inner.try_encode_nbest(text, k)?)
+    }
+
+    /// A randomized segmentation of `text` for subword regularization. Each
+    /// step lists the merges that apply, lowest rank first (leftmost first
+    /// on ties), and picks the `k`-th with weight `exp(-k / temperature)`,
+    /// so `k = 0` is the merge `encode` would apply. Merging continues until
+    /// none applies. A `temperature` of 0 (or below) gives `encode(text)`;
+    /// the same `seed` always gives the same result.
+    #[pyo3(signature = (text, temperature, seed = 0))]
+    fn encode_sampled(&self, text: &str, temperature: f64, seed: u64) -> PyResult<Vec<u32>> {
+        Ok(self.inner.try_encode_sampled(text, temperature, seed)?)
+    }
+
+    /// `encode(text)` with every token outside `allowed_ids` replaced by
+    /// `rare_id`, e.g. to see how much survives with only the frequent core
+    /// of `rank_tokens_by_frequency`. Specials count like any other ID.
+    fn encode_collapse_rare(
+        &self,
+        text: &str,
+        allowed_ids: StdHashSet<u32>,
+        rare_id: u32,
+    ) -> Vec<u32> {
+        self.inner.encode_collapse_rare(text, allowed_ids, rare_id)
+    }
+
+    /// `encode(text)` with each token replaced by `mask_id` with probability
+    /// `mask_prob`, for building masked-input eval sets. Unlike
+    /// [`encode_sampled`](Self::encode_sampled) the segmentation is fixed;
+    /// only tokens are masked. A `mask_prob` of 0 (or below) gives
+    /// `encode(text)`; the same `seed` always gives the same result.
+    #[pyo3(signature = (text, mask_id, mask_prob, seed = 0))]
+    fn encode_with_mask(&self, text: &str, mask_id: u32, mask_prob: f64, seed: u64) -> Vec<u32> {
+        self.inner.encode_with_mask(text, mask_id, mask_prob, seed)
+    }
+
+    /// Where `encode(text)` first differs from `reference`, as `(token
+    /// index, our ID, reference ID)`, an ID being `None` where that sequence
+    /// already ended; `None` if they are identical. `encode_with_offsets`
+    /// maps the index back to text and `encode_trace` shows the merges that
+    /// built the token.
+    fn first_divergence(
+        &self,
+        text: &str,
+        reference: Vec<u32>,
+    ) -> Option<(usize, Option<u32>, Option<u32>)> {
+        self.inner.first_divergence(text, reference)
+    }
+
+    /// Every merge `encode(text)` applies, in order, as `(byte offset of the
+    /// merged token in text, merge ID)`; for comparing step by step against
+    /// a reference encoder. Special tokens involve no merges.
+    fn encode_trace(&self, text: &str) -> PyResult<Vec<(usize, u32)>> {
+        Ok(self.inner.try_encode_trace(text)?)
+    }
+
+    /// The `n` merges `encode` applies most often over `texts`, as
+    /// `(pair, times applied)`, most frequent first (lower merge ID first on
+    /// ties); shows whether a few merges dominate the encoder's hot path.
+    /// Texts are encoded in parallel.
+    fn hot_merges(&self, texts: Vec<String>, n: usize) -> PyResult<Vec<((u32, u32), u64)>> {
+        Ok(self.inner.try_hot_merges(texts, n)?)
+    }
+
+    /// Shannon entropy, in bits, of the adjacent base-byte pairs in the
+    /// pretokenized `texts` (merges are not applied; special tokens are
+    /// skipped). A low value suggests few merges capture most of the corpus,
+    /// a high one that a larger `vocab_size` pays off. Texts are counted in
+    /// parallel; 0.0 when there are no pairs.
+    fn pair_entropy(&self, texts: Vec<String>) -> f64 {
+        self.inner.pair_entropy(texts)
+    }
+
+    /// The chunks `encode(text)` runs BPE on, in order: special tokens are
+    /// split out first and kept as chunks of their own, and the rest is cut
+    /// by the pretokenization pattern. The chunks concatenate to `text` (after
+    /// `strip_bom` / `strip_zero_width`). Raises `ValueError` if the pattern
+    /// fails at runtime.
+    fn pretokenize(&self, text: &str) -> PyResult<Vec<String>> {
+        Ok(self.inner.try_pretokenize(text)?)
+    }
+
+    /// How disruptive switching to `new_pattern` would be: the fraction of
+    /// the chunks `pretokenize` gives for `texts` today that the new pattern
+    /// would not produce (same text at the same offset). 0 means no change;
+    /// special tokens split out the same either way. Fails on an invalid
+    /// pattern or a runtime regex error.
+    fn pattern_impact(&self, new_pattern: &str, texts: Vec<String>) -> PyResult<f64> {
+        Ok(self.inner.pattern_impact(new_pattern, texts)?)
+    }
+
+    /// `encode(text)` plus the pretokenized chunk index of each token
+    fn encode_with_word_ids(&self, text: &str) -> PyResult<(Vec<u32>, Vec<u32>)> {
+        Ok(self.inner.try_encode_with_word_ids(text)?)
+    }
+
+    /// `encode(text)` plus how many tokens each pretokenized chunk produced,
+    /// in chunk order, for word-level perplexity normalization. The counts
+    /// sum to the token count; a special token counts as a word of one.
+    fn encode_with_word_token_counts(&self, text: &str) -> PyResult<(Vec<u32>, Vec<usize>)> {
+        Ok(self.inner.try_encode_with_word_token_counts(text)?)
+    }
+
+    /// Batch encoding for better throughput
+    fn encode_batch(&self, texts: Vec<String>) -> PyResult<Vec<Vec<u32>>> {
+        Ok(self.inner.try_encode_batch(texts)?)
+    }
+
+    /// `encode` with signed 64-bit IDs, the dtype PyTorch embeddings index
+    /// with; every `u32` ID fits, so the values are unchanged
+    fn encode_i64(&self, text: &str) -> PyResult<Vec<i64>> {
+        Ok(self.inner.try_encode_i64(text)?)
+    }
+
+    /// `encode_batch` with signed 64-bit IDs; see `encode_i64`
+    fn encode_batch_i64(&self, texts: Vec<String>) -> PyResult<Vec<Vec<i64>>> {
+        Ok(self.inner.try_encode_batch_i64(texts)?)
+    }
+
+    /// Batch encoding into one contiguous buffer plus CSR-style offsets:
+    /// text `i`'s tokens are `tokens[offsets[i]..offsets[i + 1]]`, and
+    /// `offsets` has `texts.len() + 1` entries starting at 0.
+    fn encode_batch_flat(&self, texts: Vec<String>) -> PyResult<(Vec<u32>, Vec<usize>)> {
+        Ok(self.inner.try_encode_batch_flat(texts)?)
+    }
+
+    /// Construct with a named pattern preset: "gpt2", "gpt4", "llama",
+    /// "whitespace" or "ascii".
+    /// The preset's regex is stored as `pattern`, so it persists like any other.
+    #[staticmethod]
+    fn with_preset(name: &str) -> PyResult<Self> {
+        Ok(Tokenizer::with_preset(name)?.into())
+    }
+
+    /// Construct a ready-to-use tokenizer in one step: merges, an optional
+    /// pattern (the default otherwise) and special tokens. Fails, rather
+    /// than returning a half-built tokenizer, if merge IDs are not
+    /// contiguous from 256, a merge uses a part not built before it, or a
+    /// special token's ID is a byte, a merge, or another special's ID.
+    #[staticmethod]
+    #[pyo3(signature = (merges, pattern=None, special_tokens=StdHashMap::new()))]
+    fn from_parts(
+        merges: StdHashMap<(u32, u32), u32>,
+        pattern: Option<String>,
+        special_tokens: StdHashMap<String, u32>,
+    ) -> PyResult<Self> {
+        Ok(Tokenizer::from_parts(merges, pattern, special_tokens)?.into())
+    }
+
+    /// Write only `special_tokens`, as a JSON object sorted by token, so a
+    /// changing set of control tokens can be kept apart from a stable vocab
+    fn save_special_tokens(&self, path: &str) -> PyResult<()> {
+        Ok(self.inner.save_special_tokens(path)?)
+    }
+
+    /// Replace `special_tokens` with a file written by `save_special_tokens`.
+    /// Fails, keeping the current ones, if an ID is a byte or merge of this
+    /// vocab or two tokens share an ID.
+    fn load_special_tokens(&mut self, path: &str) -> PyResult<()> {
+        Ok(self.inner.load_special_tokens(path)?)
+    }
+
+    /// Control how raw bytes map onto base token IDs. `alphabet[b]` is the ID
+    /// emitted for byte `b` and must be below 256; `None` restores identity.
+    #[pyo3(signature = (alphabet=None))]
+    fn set_initial_alphabet(&mut self, alphabet: Option<Vec<u32>>) -> PyResult<()> {
+        Ok(self.inner.set_initial_alphabet(alphabet)?)
+    }
+
+    /// Base token ID for each byte value (identity unless customized)
+    fn get_initial_alphabet(&self) -> Vec<u32> {
+        self.inner.get_initial_alphabet()
+    }
+
+    /// Byte ranges of `text` that no pattern match covers; empty for a pattern
+    /// that tiles its input. If the regex fails at runtime, everything from
+    /// the failure point on is reported as uncovered.
+    fn uncovered_spans(&self, text: &str) -> Vec<(usize, usize)> {
+        self.inner.uncovered_spans(text)
+    }
+
+    /// Add `marker` (e.g. `"▁"`) to the start of every pretokenized chunk, or
+    /// to its end with `at_end`, during both training and encoding, so word
+    /// boundaries become explicit in the vocab. Decoding removes every
+    /// occurrence of the marker. `None` turns it off. Set this before
+    /// training; existing merges were learned without it.
+    #[pyo3(signature = (marker=None, at_end=false))]
+    fn set_word_marker(&mut self, marker: Option<String>, at_end: bool) {
+        self.inner.set_word_marker(marker, at_end)
+    }
+
+    /// The word marker and whether it is appended, if one is set
+    fn get_word_marker(&self) -> Option<(String, bool)> {
+        self.inner.get_word_marker()
+    }
+
+    /// Restrict the bytes encode may emit to `allowed`; any other byte
+    /// encodes as `unk_id`, typically a registered special token's ID. A
+    /// non-ASCII character is several bytes, so with an ASCII-only set it
+    /// becomes one `unk_id` per byte. `None` allows every byte. An `allowed`
+    /// set needs an explicit `unk_id`. Training ignores this.
+    #[pyo3(signature = (allowed, unk_id=None))]
+    fn set_allowed_bytes(
+        &mut self,
+        allowed: Option<[bool; 256]>,
+        unk_id: Option<u32>,
+    ) -> PyResult<()> {
+        Ok(self.inner.set_allowed_bytes(allowed, unk_id)?)
+    }
+
+    /// The bytes encode may emit, if restricted
+    fn get_allowed_bytes(&self) -> Option<[bool; 256]> {
+        self.inner.get_allowed_bytes()
+    }
+
+    /// The ID excluded bytes encode as, if `allowed_bytes` is set
+    fn get_unk_id(&self) -> Option<u32> {
+        self.inner.get_unk_id()
+    }
+
+    /// Regex source used for pretokenization
+    fn get_pattern(&self) -> String {
+        self.inner.get_pattern()
+    }
+
+    /// Replace the pretokenization regex, rejecting patterns that fail to compile.
+    /// Existing merges were learned under the old chunking, so call this before
+    /// training rather than on an already-trained tokenizer.
+    fn set_pattern(&mut self, pattern: String) -> PyResult<()> {
+        Ok(self.inner.set_pattern(pattern)?)
+    }
+
+    /// Choose the regex engine for the pretokenization pattern and recompile
+    /// it. Matches are the same either way, so this is a speed setting only
+    /// and is not saved with the tokenizer.
+    fn set_pattern_engine(&mut self, engine: PatternEngine) -> PyResult<()> {
+        Ok(self.inner.set_pattern_engine(engine)?)
+    }
+
+    /// The regex engine setting `set_pattern_engine` last chose
+    fn get_pattern_engine(&self) -> PatternEngine {
+        self.inner.get_pattern_engine()
+    }
+
+    /// Rebuild a tokenizer from the output of `to_bytes`
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        Ok(Tokenizer::from_bytes(data)?.into())
+    }
+
+    /// Write to `path` as newline-delimited JSON, one merge per line; see
+    /// `Tokenizer::save` for the format
+    fn save(&self, path: &str) -> PyResult<()> {
+        Ok(self.inner.save(path)?)
+    }
+
+    /// Read a file written by `save`, keeping only the first `max_merges`
+    /// merges if given; see `load_merges`
+    #[staticmethod]
+    #[pyo3(signature = (path, max_merges=None))]
+    fn load(path: &str, max_merges: Option<usize>) -> PyResult<Self> {
+        let tokenizer = match max_merges {
+            Some(max_merges) => Tokenizer::load_limited(path, max_merges)?,
+            None => Tokenizer::load(path)?,
+        };
+        Ok(tokenizer.into())
+    }
+
+    /// Number of tokens `encode` would produce for `text`
+    fn count_tokens(&self, text: &str) -> usize {
+        self.inner.count_tokens(text)
+    }
+
+    /// The token whose byte expansion is exactly `bytes`, if the vocab has one.
+    /// Unlike `as_single_token`, this ignores whether `encode` would produce it.
+    fn bytes_to_token(&self, bytes: &[u8]) -> Option<u32> {
+        self.inner.bytes_to_token(bytes)
+    }
+
+    /// The ID `encode(text)` yields if it is exactly one non-special token.
+    /// Texts that are not a vocab entry's exact bytes are rejected without encoding.
+    fn as_single_token(&self, text: &str) -> Option<u32> {
+        self.inner.as_single_token(text)
+    }
+
+    /// Whether `text` encodes to exactly one non-special token
+    fn is_single_token(&self, text: &str) -> bool {
+        self.inner.is_single_token(text)
+    }
+
+    /// Input bytes per output token over a sample; higher means better compression
+    fn compression_ratio(&self, texts: Vec<String>) -> f64 {
+        self.inner.compression_ratio(texts)
+    }
+
+    /// How often each ordered token pair `(earlier, later)` occurs at most
+    /// `window` positions apart within one of `texts`, over their encodings;
+    /// pairs never span documents. Documents are encoded and counted in
+    /// parallel. Memory grows with distinct pairs, which `window` bounds.
+    fn token_cooccurrence(
+        &self,
+        texts: Vec<String>,
+        window: usize,
+    ) -> PyResult<StdHashMap<(u32, u32), u64>> {
+        Ok(self.inner.try_token_cooccurrence(texts, window)?)
+    }
+
+    /// `(tokens per second, bytes per second)` for encoding `texts` one after
+    /// another on the calling thread
+    fn benchmark_encode(&self, texts: Vec<String>) -> (f64, f64) {
+        self.inner.benchmark_encode(texts)
+    }
+
+    /// Decode token IDs to text, replacing invalid UTF-8 with U+FFFD;
+    /// raises on tokens expanding past 1 MiB and on output past 1 GiB
+    fn decode(&self, ids: Vec<u32>) -> PyResult<String> {
+        Ok(self.inner.try_decode(&ids)?)
+    }
+
+    /// Whether `encode(text)` is a fixed point: decoding it and encoding the
+    /// result again gives the same tokens. A well-behaved byte-level BPE is
+    /// always stable, so `false` points at a chunking or decode bug.
+    /// Output that doesn't decode to valid UTF-8 counts as unstable.
+    fn is_stable(&self, text: &str) -> bool {
+        self.inner.is_stable(text)
+    }
+
+    /// `encode(text)` as `(id, run length)` pairs, one per run of identical
+    /// consecutive tokens. Only the representation is compacted; expanding
+    /// each pair `length` times gives back exactly `encode(text)`.
+    fn encode_rle(&self, text: &str) -> Vec<(u32, u32)> {
+        self.inner.encode_rle(text)
+    }
+
+    /// `(id, start index, run length)` for every run of at least `min_run`
+    /// identical consecutive tokens in `encode(text)`, in order; for catching
+    /// degenerate output such as long runs of one whitespace token.
+    /// A `min_run` of 0 is treated as 1.
+    fn repeated_token_runs(&self, text: &str, min_run: usize) -> Vec<(u32, usize, usize)> {
+        self.inner.repeated_token_runs(text, min_run)
+    }
+
+    /// How often each byte value occurs in the chunks training would count
+    /// from `texts` (after normalization; bytes the pattern skips are not
+    /// counted). Zeros are bytes the alphabet never needs; hits on `0x00` or
+    /// high-bit bytes in supposedly ASCII data point at corrupt input.
+    /// Texts are scanned in parallel. Panics like `encode` if the pattern
+    /// fails at runtime.
+    fn scan_byte_coverage(&self, texts: Vec<String>) -> [u64; 256] {
+        self.inner.scan_byte_coverage(texts)
+    }
+
+    /// How often each token ID occurs when encoding `texts`.
+    /// Texts are encoded and counted in parallel; only one text's tokens are
+    /// alive per worker at a time.
+    fn token_histogram(&self, texts: Vec<String>) -> StdHashMap<u32, u64> {
+        self.inner.token_histogram(texts)
+    }
+
+    /// `token_histogram` as `(id, count)`, most frequent first (lower ID
+    /// first on ties), so position in the list is the token's rank. Tokens
+    /// that never occur are left out.
+    fn rank_tokens_by_frequency(&self, texts: Vec<String>) -> Vec<(u32, u64)> {
+        self.inner.rank_tokens_by_frequency(texts)
+    }
+
+    /// KL(a || b), in bits, between the token distributions of `corpus_a`
+    /// and `corpus_b` (see `token_histogram`): a drift signal that grows as
+    /// `corpus_b` stops resembling `corpus_a`. Counts get add-one smoothing
+    /// over the IDs seen in either corpus, so a token missing from one side
+    /// doesn't make it infinite. 0.0 if both are empty.
+    fn token_distribution_kl(&self, corpus_a: Vec<String>, corpus_b: Vec<String>) -> f64 {
+        self.inner.token_distribution_kl(corpus_a, corpus_b)
+    }
+
+    /// Vocab IDs (base bytes and merges, not specials) that never occur when
+    /// encoding `texts`, sorted ascending. Stops early once every ID is seen.
+    fn dead_tokens(&self, texts: Vec<String>) -> Vec<u32> {
+        self.inner.dead_tokens(texts)
+    }
+
+    /// Fraction of `texts` that `self` and `other` encode to identical token
+    /// sequences. An empty sample counts as full agreement.
+    fn agreement(&self, other: PyRef<'_, PyTokenizer>, texts: Vec<String>) -> f64 {
+        self.inner.agreement(&other.inner, texts)
+    }
+
+    /// Levenshtein distance between `self.encode(text)` and
+    /// `other.encode(text)`: the fewest token insertions, deletions and
+    /// substitutions turning one sequence into the other
+    fn token_edit_distance(&self, other: PyRef<'_, PyTokenizer>, text: &str) -> usize {
+        self.inner.token_edit_distance(&other.inner, text)
+    }
+
+    /// Token IDs that are the left part of at least one merge; a pair whose
+    /// left ID is not among them never merges
+    fn mergeable_lefts(&self) -> StdHashSet<u32> {
+        self.inner.mergeable_lefts().into_iter().collect()
+    }
+
+    /// Token IDs that are the right part of at least one merge
+    fn mergeable_rights(&self) -> StdHashSet<u32> {
+        self.inner.mergeable_rights().into_iter().collect()
+    }
+
+    /// How `other`'s merges differ from these, as `(added, removed,
+    /// changed)`: `(pair, id)` merges only `other` has, `(pair, id)` merges
+    /// only `self` has, and `(pair, self id, other id)` for pairs both merge
+    /// under different IDs. Each list is sorted by (`self`'s) ID.
+    fn merge_diff(&self, other: PyRef<'_, PyTokenizer>) -> MergeDiff {
+        self.inner.merge_diff(&other.inner)
+    }
+
+    /// A copy keeping only the merges `texts` need, for shrinking the
+    /// embedding table of a specialized model: every token their encodings
+    /// use, plus the parts those tokens are built from. Kept merges are
+    /// renumbered contiguously in their original order; base bytes, special
+    /// tokens, special patterns and literal tokens keep their IDs, and an ID
+    /// of theirs that the renumbered merges would reuse is an error. Also
+    /// returns the old -> new ID map for every kept token, for carrying
+    /// embedding rows over. Fails if encoding any of `texts` fails.
+    fn specialize(&self, texts: Vec<String>) -> PyResult<(PyTokenizer, StdHashMap<u32, u32>)> {
+        let (specialized, remap) = self.inner.specialize(texts)?;
+        Ok((specialized.into(), remap))
+    }
+
+    /// Merges as `(pair, id)` in ID order, i.e. the order training learned them
+    fn merges_ordered(&self) -> Vec<((u32, u32), u32)> {
+        self.inner.merges_ordered()
+    }
+
+    /// Merges flattened to `[a0, b0, id0, a1, b1, id1, ...]` in ID order, the
+    /// `merges_ordered` triples packed for bulk transfer (e.g. to a GPU)
+    fn merges_array(&self) -> Vec<u32> {
+        self.inner.merges_array()
+    }
+
+    /// Load merges from the flat layout of `merges_array`. Fails, leaving the
+    /// merges unchanged, if the length is not a multiple of 3 or a pair
+    /// appears twice.
+    fn merges_from_array(&mut self, array: Vec<u32>) -> PyResult<()> {
+        Ok(self.inner.merges_from_array(array)?)
+    }
+
+    /// Export merges for serialization
+    fn get_merges(&self) -> StdHashMap<(u32, u32), u32> {
+        self.inner.get_merges()
+    }
+
+    /// Load pre-trained merges. With `max_merges`, only merges with IDs below
+    /// `256 + max_merges` are kept; since merges only reference earlier IDs,
+    /// that prefix is always a valid, smaller vocabulary.
+    #[pyo3(signature = (merges, max_merges=None))]
+    fn load_merges(&mut self, merges: StdHashMap<(u32, u32), u32>, max_merges: Option<usize>) {
+        match max_merges {
+            Some(max_merges) => self.inner.load_merges_limited(merges, max_merges),
+            None => self.inner.load_merges(merges),
+        }
+    }
+
+    /// Load merges whose IDs may have gaps (from an external table), renumbered
+    /// to `256, 257, ...` in ID order with their parts translated to match.
+    /// Returns the old -> new ID map for translating other data. Fails, leaving
+    /// the merges unchanged, on an ID below 256, a duplicate ID, or a part
+    /// that is neither a base byte nor a lower-ID merge.
+    fn load_merges_remapped(
+        &mut self,
+        merges: StdHashMap<(u32, u32), u32>,
+    ) -> PyResult<StdHashMap<u32, u32>> {
+        Ok(self.inner.load_merges_remapped(merges)?)
+    }
+
+    /// Append the merge `(a, b)` with the next free ID and return that ID.
+    /// Both parts must already exist (a base byte or an earlier merge), and
+    /// the new ID must not already belong to a special or literal token.
+    fn add_merge(&mut self, a: u32, b: u32) -> PyResult<u32> {
+        Ok(self.inner.add_merge(a, b)?)
+    }
+
+    /// The pair training would merge next over `words` (byte strings, each
+    /// occurring `counts[i]` times) after applying the current merges, and
+    /// its count; pair with `add_merge` to step through training
+    fn next_merge(
+        &self,
+        words: Vec<Vec<u8>>,
+        counts: Vec<i32>,
+    ) -> PyResult<Option<((u32, u32), u64)>> {
+        let words: Vec<Vec<u32>> = words
+            .iter()
+            .map(|bytes| {
+                let mut ids = self.inner.base_ids(bytes);
+                self.inner.apply_merges(&mut ids, |_, _| {});
+                ids
+            })
+            .collect();
+        Ok(self.inner.next_merge(&words, &counts)?)
+    }
+
+    /// Pair frequencies remaining after the last training run.
+    /// Empty unless `retain_pair_counts` was set before training.
+    fn final_pair_counts(&self) -> StdHashMap<(u32, u32), i64> {
+        self.inner.final_pair_counts()
+    }
+
+    /// Like `load_merges`, but rejects tables with duplicate target IDs or IDs
+    /// that are not exactly the contiguous range `256..256 + len`.
+    fn try_load_merges(&mut self, merges: StdHashMap<(u32, u32), u32>) -> PyResult<()> {
+        Ok(self.inner.try_load_merges(merges)?)
+    }
+
+    /// Merges with a part whose ID is not below the merge's own ID, in ID order
+    fn find_forward_references(&self) -> Vec<((u32, u32), u32)> {
+        self.inner.find_forward_references()
+    }
+
+    /// Rebuild merges from ranked byte sequences (rank = token ID), as found in
+    /// tiktoken-style vocab files. Each multi-byte token is re-encoded with the
+    /// lower-ranked tokens and must split into exactly two of them, which
+    /// become its merge pair. Multi-byte ranks must be exactly
+    /// `256..256 + count`, one each. Single-byte entries, if any, must cover
+    /// all 256 bytes with distinct ranks below 256 and define the initial
+    /// alphabet; otherwise the identity is used.
+    fn load_from_ranked_tokens(&mut self, tokens: Vec<(Vec<u8>, u32)>) -> PyResult<()> {
+        Ok(self.inner.load_from_ranked_tokens(tokens)?)
+    }
+
+    /// Write the vocab as a tiktoken `.model` file: one `base64(bytes) rank`
+    /// line per token, rank being the token ID, in rank order.
+    fn save_tiktoken(&self, path: &str) -> PyResult<()> {
+        Ok(self.inner.save_tiktoken(path)?)
+    }
+
+    /// `(id, byte length, lossy string)` of the longest token in the vocab,
+    /// lowest ID first on ties. Without merges this is a single base byte.
+    fn longest_token(&self) -> (u32, usize, String) {
+        self.inner.longest_token()
+    }
+
+    /// `(id, lossy string)` of every vocab token whose bytes contain
+    /// `substring`, in ID order. Special tokens are not searched.
+    fn find_tokens(&self, substring: &str) -> Vec<(u32, String)> {
+        self.inner.find_tokens(substring)
+    }
+
+    /// The merges that build token `id` from base bytes, in an order they
+    /// can be applied: each pair only uses bytes or tokens built earlier in
+    /// the list, and a part used twice is built once. Empty for base bytes
+    /// and for IDs that aren't merges.
+    fn merge_path(&self, id: u32) -> Vec<(u32, u32)> {
+        self.inner.merge_path(id)
+    }
+
+    /// Token counts by BPE tree depth: index 0 counts base tokens, 1 merges
+    /// of two base tokens, and in general a merge is one deeper than its
+    /// deeper part. A long tail means a few tokens need many merge steps.
+    fn merge_depth_histogram(&self) -> Vec<usize> {
+        self.inner.merge_depth_histogram()
+    }
+
+    /// Build every lazily initialized lookup table now, so the first real
+    /// request doesn't pay for it: the ID -> bytes table (decode, vocab
+    /// queries), the bytes -> ID map (`LongestToken` encoding, single-token
+    /// lookups), the special ID -> string map (decode) and the special-token
+    /// matcher (encode). Editing merges or specials drops them again.
+    fn warmup(&self) {
+        self.inner.warmup()
+    }
+
+    /// Get vocabulary size
+    fn vocab_size(&self) -> usize {
+        self.inner.vocab_size()
+    }
+
+    /// Size metrics for monitoring: `merges_count`, `special_count`,
+    /// `pattern_len` (bytes), and `merges_bytes`, the merge table's allocated
+    /// capacity times its entry size (a lower bound on its footprint)
+    fn stats(&self) -> StdHashMap<String, u64> {
+        self.inner.stats()
+    }
+
+    /// Highest ID `encode` can emit, including special tokens.
+    /// Size embedding/output layers with `max_token_id() + 1`.
+    fn max_token_id(&self) -> u32 {
+        self.inner.max_token_id()
+    }
+}
+
+/// Token window over a text that keeps growing: `push` appends text and
+/// drops the oldest tokens past `max_window`, re-encoding only the seam.
+#[pyclass(name = "SlidingWindowEncoder")]
+pub struct PySlidingWindowEncoder {
+    tokenizer: Py<PyTokenizer>,
+    inner: SlidingWindowEncoder,
+}
+
+#[pymethods]
+impl PySlidingWindowEncoder {
+    #[new]
+    fn new(tokenizer: Py<PyTokenizer>, max_window: usize) -> Self {
+        Self {
+            tokenizer,
+            inner: SlidingWindowEncoder::new(max_window),
+        }
+    }
+
+    /// Encode `text` as a continuation of everything pushed so far
+    fn push(&mut self, py: Python<'_>, text: &str) -> PyResult<()> {
+        let tokenizer = self.tokenizer.borrow(py);
+        Ok(self.inner.try_push(&tokenizer.inner, text)?)
+    }
+
+    /// The current window, oldest token first
+    fn tokens(&self) -> Vec<u32> {
+        self.inner.tokens()
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+/// Incremental detokenization: `push` returns only complete characters,
+/// holding back the bytes of one split across tokens until it completes.
+/// Tokens expand as in `decode`, so the pieces concatenate to its output.
+#[pyclass(name = "StreamDecoder")]
+pub struct PyStreamDecoder {
+    tokenizer: Py<PyTokenizer>,
+    inner: StreamDecoder,
+}
+
+#[pymethods]
+impl PyStreamDecoder {
+    #[new]
+    fn new(tokenizer: Py<PyTokenizer>) -> Self {
+        Self {
+            tokenizer,
+            inner: StreamDecoder::new(),
+        }
+    }
+
+    /// Decode the next token, returning the text it completes (maybe empty).
+    /// Fails on the IDs `decode` refuses.
+    fn push(&mut self, py: Python<'_>, id: u32) -> PyResult<String> {
+        let tokenizer = self.tokenizer.borrow(py);
+        Ok(self.inner.try_push(&tokenizer.inner, id)?)
+    }
+
+    /// Flush the held-back bytes at the end of the stream, as U+FFFD
+    fn finish(&mut self) -> String {
+        self.inner.finish()
+    }
+}
+
+/// BPE training one merge per `step`, for driving training interactively
+/// and inspecting it in between. Counts `corpus`, a list of `(text, count)`
+/// pairs, up front and discards the tokenizer's merges.
+#[pyclass(name = "TrainingSession")]
+pub struct PyTrainingSession {
+    tokenizer: Py<PyTokenizer>,
+    inner: TrainingSession,
+}
+
+#[pymethods]
+impl PyTrainingSession {
+    #[new]
+    fn new(
+        py: Python<'_>,
+        tokenizer: Py<PyTokenizer>,
+        corpus: Vec<(String, u32)>,
+        vocab_size: u32,
+    ) -> PyResult<Self> {
+        let inner = tokenizer
+            .borrow_mut(py)
+            .inner
+            .start_training(&corpus, vocab_size)?;
+        Ok(Self { tokenizer, inner })
+    }
+
+    /// Learn the next merge, add it to the tokenizer and return it as
+    /// `(pair, id)`; `None` once `vocab_size` is reached or no pair is left.
+    /// Raises `ValueError` if the tokenizer's merges were changed meanwhile.
+    fn step(&mut self, py: Python<'_>) -> PyResult<Option<((u32, u32), u32)>> {
+        let mut tokenizer = self.tokenizer.borrow_mut(py);
+        Ok(self.inner.step(&mut tokenizer.inner)?)
+    }
+
+    /// Merges the tokenizer holds so far
+    #[getter]
+    fn merges_done(&self) -> u32 {
+        self.inner.merges_done()
+    }
+
+    /// Current weighted count of every pair that still occurs
+    fn pair_counts(&self) -> StdHashMap<(u32, u32), i64> {
+        self.inner.pair_counts()
+    }
+}
+
+#[pymodule]
+fn rust_tokenizer(_: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTokenizer>()?;
+    m.add_class::<EncodeStrategy>()?;
+    m.add_class::<TieBreak>()?;
+    m.add_class::<SpecialConflict>()?;
+    m.add_class::<MergeFilter>()?;
+    m.add_class::<PairReduce>()?;
+    m.add_class::<UnknownIdPolicy>()?;
+    m.add_class::<EscapeMode>()?;
+    m.add_class::<PatternEngine>()?;
+    m.add_class::<OverlongChunkPolicy>()?;
+    m.add_class::<PySlidingWindowEncoder>()?;
+    m.add_class::<PyStreamDecoder>()?;
+    m.add_class::<PyTrainingSession>()?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Sequential stand-ins for the rayon calls in `lib.rs`, used when the
+//! `parallel` feature is off (for example on `wasm32-unknown-unknown`).
+//! `Seq` mirrors the rayon adaptor signatures the crate relies on, so the
+//! call sites compile unchanged and simply run on the calling thread.
+
+use std::iter::{Enumerate, Map, Once};
+
+pub fn current_num_threads() -> usize {
+    1
+}
+
+/// An ordinary iterator wearing rayon's `ParallelIterator` method names
+pub struct Seq<I>(I);
+
+impl<I: Iterator> Seq<I> {
+    pub fn map<B, F: FnMut(I::Item) -> B>(self, f: F) -> Seq<Map<I, F>> {
+        Seq(self.0.map(f))
+    }
+
+    pub fn enumerate(self) -> Seq<Enumerate<I>> {
+        Seq(self.0.enumerate())
+    }
+
+    /// Like rayon's `fold`: one accumulator, yielded as a single item
+    pub fn fold<T, ID, F>(self, identity: ID, f: F) -> Seq<Once<T>>
+    where
+        ID: Fn() -> T,
+        F: FnMut(T, I::Item) -> T,
+    {
+        Seq(std::iter::once(self.0.fold(identity(), f)))
+    }
+
+    /// Like rayon's `try_fold`: one accumulator, or the first error
+    pub fn try_fold<T, E, ID, F>(mut self, identity: ID, f: F) -> Seq<Once<Result<T, E>>>
+    where
+        ID: Fn() -> T,
+        F: FnMut(T, I::Item) -> Result<T, E>,
+    {
+        Seq(std::iter::once(self.0.try_fold(identity(), f)))
+    }
+
+    pub fn reduce<ID, F>(self, identity: ID, op: F) -> I::Item
+    where
+        ID: Fn() -> I::Item,
+        F: FnMut(I::Item, I::Item) -> I::Item,
+    {
+        self.0.fold(identity(), op)
+    }
+
+    pub fn try_reduce<T, E, ID, F>(self, identity: ID, mut op: F) -> Result<T, E>
+    where
+        I: Iterator<Item = Result<T, E>>,
+        ID: Fn() -> T,
+        F: FnMut(T, T) -> Result<T, E>,
+    {
+        let mut acc = identity();
+        for item in self.0 {
+            acc = op(acc, item?)?;
+        }
+        Ok(acc)
+    }
+}
+
+impl<I: Iterator> Iterator for Seq<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.0.next()
+    }
+}
+
+/// `par_iter`, `par_iter_mut` and `par_chunks` on slices
+pub trait SeqSlice<T> {
+    fn par_iter(&self) -> Seq<std::slice::Iter<'_, T>>;
+    #[cfg(feature = "python")]
+    fn par_iter_mut(&mut self) -> Seq<std::slice::IterMut<'_, T>>;
+    fn par_chunks(&self, size: usize) -> Seq<std::slice::Chunks<'_, T>>;
+}
+
+impl<T> SeqSlice<T> for [T] {
+    fn par_iter(&self) -> Seq<std::slice::Iter<'_, T>> {
+        Seq(self.iter())
+    }
+
+    #[cfg(feature = "python")]
+    fn par_iter_mut(&mut self) -> Seq<std::slice::IterMut<'_, T>> {
+        Seq(self.iter_mut())
+    }
+
+    fn par_chunks(&self, size: usize) -> Seq<std::slice::Chunks<'_, T>> {
+        Seq(self.chunks(size))
+    }
+}
+
+/// `into_par_iter` on anything iterable
+pub trait IntoSeq: IntoIterator + Sized {
+    fn into_par_iter(self) -> Seq<Self::IntoIter> {
+        Seq(self.into_iter())
+    }
+}
+
+impl<I: IntoIterator> IntoSeq for I {}
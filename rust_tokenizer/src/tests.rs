@@ -1,6 +1,8 @@
 // acsess private crates
 
 use super::*;
+#[cfg(feature = "python")]
+use pyo3::types::PyBytes;
 
 #[test]
 fn encode_returns_some_tokens() {
@@ -44,7 +46,7 @@ fn train_core_increases_vocab_size() {
     let counts = vec![10, 8, 5];
 
     // Request a vocab larger than the base 256 bytes to force merges
-    tokenizer.train_core(words, counts, 300);
+    tokenizer.train_core(words, counts, 300).unwrap();
 
     let new_vocab = tokenizer.vocab_size();
     assert!(
@@ -54,3 +56,2870 @@ fn train_core_increases_vocab_size() {
         new_vocab
     );
 }
+
+#[test]
+fn final_pair_counts_retained_only_when_enabled() {
+    let words = || {
+        vec![
+            Word::new("hello".bytes().map(|b| b as u32).collect()),
+            Word::new("world".bytes().map(|b| b as u32).collect()),
+        ]
+    };
+
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.train_core(words(), vec![3, 2], 258).unwrap();
+    assert!(tokenizer.final_pair_counts().is_empty());
+
+    let mut tokenizer = Tokenizer::new().unwrap();
+    tokenizer.retain_pair_counts = true;
+    tokenizer.train_core(words(), vec![3, 2], 258).unwrap();
+
+    let remaining = tokenizer.final_pair_counts();
+    assert!(!remaining.is_empty(), "unmerged pairs should be retained");
+    assert!(remaining.values().all(|&c| c > 0));
+    for pair in tokenizer.merges.keys() {
+        assert!(
+            !remaining.contains_key(pair),
+            "fully merged pair {:?} should not survive",
+            pair
+        );
+    }
+}
+
+#[test]
+fn to_bytes_round_trips() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((104, 101), 256), ((256, 108), 257)].into_iter().collect());
+    tokenizer.register_special_token("<|eot|>".to_string(), 1000);
+
+    let data = tokenizer.to_bytes();
+    assert_eq!(data[0], FORMAT_VERSION);
+
+    let restored = Tokenizer::from_bytes(&data).unwrap();
+    assert_eq!(restored.merges, tokenizer.merges);
+    assert_eq!(restored.special_tokens, tokenizer.special_tokens);
+    assert_eq!(restored.pattern, tokenizer.pattern);
+    assert_eq!(restored.encode("hello"), tokenizer.encode("hello"));
+}
+
+#[test]
+fn from_bytes_rejects_unknown_version() {
+    let mut data = Tokenizer::default().to_bytes();
+    data[0] = FORMAT_VERSION + 1;
+    assert!(Tokenizer::from_bytes(&data).is_err());
+    assert!(Tokenizer::from_bytes(&[]).is_err());
+}
+
+#[test]
+fn parallel_counts_match_sequential_for_each_reduce() {
+    // Enough words to take the parallel path
+    let words: Vec<Word> = (0..(MIN_PARALLEL_WORK * 3))
+        .map(|i| {
+            Word::new(
+                format!("w{}x{}", i % 97, i % 13)
+                    .bytes()
+                    .map(|b| b as u32)
+                    .collect(),
+            )
+        })
+        .collect();
+    let counts: Vec<i32> = (0..words.len()).map(|i| (i % 5) as i32).collect();
+
+    let (seq_pc, seq_wtu) = Tokenizer::count_pairs_sequential(&words, &counts);
+    for reduce in [PairReduce::Sharded, PairReduce::Tree] {
+        let (par_pc, par_wtu) = Tokenizer::count_pairs_parallel(&words, &counts, reduce);
+        assert_eq!(par_pc, seq_pc, "{reduce:?}");
+        assert_eq!(par_wtu, seq_wtu, "{reduce:?}");
+    }
+}
+
+#[test]
+fn compression_ratio_is_bytes_per_token() {
+    let mut tokenizer = Tokenizer::default();
+    let texts = vec!["hello".to_string(), "world".to_string()];
+
+    // No merges: one token per byte
+    assert_eq!(tokenizer.compression_ratio(texts.clone()), 1.0);
+
+    tokenizer.load_merges([((108, 108), 256)].into_iter().collect()); // "ll"
+    assert_eq!(tokenizer.compression_ratio(texts), 10.0 / 9.0);
+    assert_eq!(tokenizer.compression_ratio(Vec::new()), 0.0);
+}
+
+#[test]
+fn capacity_hints_default_to_previous_sizes() {
+    let hints = CapacityHints::default();
+    assert_eq!(hints.merges, 50_000);
+    assert_eq!(hints.unique_chunks, 200_000);
+    assert_eq!(hints.batch_chunks, 2048);
+
+    let small = CapacityHints {
+        merges: 16,
+        ..CapacityHints::default()
+    };
+    let tokenizer = Tokenizer::with_capacity_hints(small).unwrap();
+    assert_eq!(tokenizer.capacity_hints, small);
+    assert!(tokenizer.merges.capacity() < 50_000);
+}
+
+#[test]
+fn encode_with_offsets_spans_and_trimming() {
+    let mut tokenizer = Tokenizer::with_preset("gpt4").unwrap();
+    tokenizer.load_merges([((32, 116), 256)].into_iter().collect()); // " t"
+    let text = "hi  there";
+
+    let (tokens, offsets) = tokenizer.encode_with_offsets(text, false);
+    assert_eq!(tokens, tokenizer.encode(text));
+    assert_eq!(offsets.len(), tokens.len());
+    assert_eq!(offsets.first().unwrap().0, 0);
+    assert_eq!(offsets.last().unwrap().1, text.len());
+
+    // Chunks are "hi", " ", " there"; the merged " t" token spans bytes 3..5
+    assert_eq!(tokens[3], 256);
+    assert_eq!(offsets[3], (3, 5));
+
+    let (_, trimmed) = tokenizer.encode_with_offsets(text, true);
+    assert_eq!(trimmed[3], (4, 5));
+    // Whitespace-only tokens keep their span
+    assert_eq!(trimmed[2], offsets[2]);
+}
+
+#[test]
+fn encode_ordinary_ignores_special_tokens() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("hello".to_string(), 5000);
+
+    assert_eq!(tokenizer.encode("hello"), vec![5000]);
+    assert_eq!(
+        tokenizer.encode_ordinary("hello"),
+        "hello".bytes().map(|b| b as u32).collect::<Vec<_>>()
+    );
+    assert_eq!(tokenizer.encode_ordinary("a b"), tokenizer.encode("a b"));
+}
+
+fn sample_words() -> (Vec<Word>, Vec<i32>) {
+    let chunks = [
+        ("hello", 10),
+        (" world", 8),
+        (" hello", 7),
+        ("tokenizer", 5),
+        (" token", 4),
+        ("aaaa", 3),
+    ];
+    let words = chunks
+        .iter()
+        .map(|(c, _)| Word::new(c.bytes().map(|b| b as u32).collect()))
+        .collect();
+    let counts = chunks.iter().map(|&(_, n)| n).collect();
+    (words, counts)
+}
+
+#[test]
+fn resume_then_finish_matches_uninterrupted_run() {
+    let (words, counts) = sample_words();
+    let mut full = Tokenizer::default();
+    full.train_core(words, counts, 280).unwrap();
+
+    let checkpoint = std::env::temp_dir().join(format!("tok_ckpt_{}.bin", std::process::id()));
+    let checkpoint = checkpoint.to_str().unwrap().to_string();
+
+    let (words, counts) = sample_words();
+    let mut partial = Tokenizer::new().unwrap();
+    partial.checkpoint_path = Some(checkpoint.clone());
+    partial.checkpoint_every = 5;
+    partial.train_core(words, counts, 266).unwrap();
+
+    // Resume from the file, replaying the partial merges onto fresh words
+    let mut resumed = Tokenizer::load_checkpoint(&checkpoint).unwrap();
+    std::fs::remove_file(&checkpoint).unwrap();
+    assert_eq!(resumed.merges, partial.merges);
+
+    let (mut words, counts) = sample_words();
+    for w in &mut words {
+        resumed.merge_chunk(&mut w.ids, |_, _| {});
+    }
+    resumed.train_core(words, counts, 280).unwrap();
+
+    assert_eq!(resumed.merges, full.merges);
+}
+
+#[test]
+fn pattern_presets_compile_and_cover_input() {
+    let text = "Hello, world!  It's 2024\n\tdone";
+    for &(name, pattern) in PATTERN_PRESETS {
+        let tokenizer = Tokenizer::with_preset(name).unwrap();
+        assert_eq!(tokenizer.pattern, pattern);
+
+        let covered: usize = tokenizer
+            .compiled_pattern
+            .find_iter(text)
+            .map(|m| m.unwrap().1.len())
+            .sum();
+        assert_eq!(covered, text.len(), "preset {} dropped input", name);
+    }
+
+    assert!(Tokenizer::with_preset("bogus").is_err());
+}
+
+#[test]
+fn max_token_id_accounts_for_sparse_specials() {
+    let mut tokenizer = Tokenizer::default();
+    assert_eq!(tokenizer.max_token_id(), 255);
+
+    tokenizer.load_merges([((104, 105), 256), ((256, 33), 257)].into_iter().collect());
+    assert_eq!(tokenizer.max_token_id(), 257);
+
+    tokenizer.register_special_token("<|eot|>".to_string(), 100_000);
+    assert_eq!(tokenizer.max_token_id(), 100_000);
+    assert!(tokenizer.vocab_size() < 100_000);
+}
+
+#[test]
+fn try_load_merges_rejects_duplicate_and_gapped_ids() {
+    let mut tokenizer = Tokenizer::default();
+
+    let ok: StdHashMap<Pair, u32> = [((104, 105), 256), ((256, 33), 257)].into_iter().collect();
+    assert!(tokenizer.try_load_merges(ok.clone()).is_ok());
+    assert_eq!(tokenizer.merges, ok);
+
+    let duplicate = [((104, 105), 256), ((97, 98), 256)].into_iter().collect();
+    let err = check_merge_ids(&duplicate).unwrap_err();
+    assert!(err.contains("Duplicate"), "{}", err);
+    assert!(tokenizer.try_load_merges(duplicate).is_err());
+
+    let gapped = [((104, 105), 256), ((97, 98), 258)].into_iter().collect();
+    let err = check_merge_ids(&gapped).unwrap_err();
+    assert!(err.contains("contiguous"), "{}", err);
+
+    // Failed loads leave the previous table intact
+    assert_eq!(tokenizer.merges, ok);
+}
+
+#[test]
+fn decode_bytes_expands_merges_and_specials() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((104, 105), 256), ((256, 33), 257)].into_iter().collect());
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    assert_eq!(tokenizer.decode_bytes(&[257, 5000]), b"hi!<|eot|>");
+    assert_eq!(tokenizer.decode(vec![256, 32, 257]), "hi hi!");
+
+    // The cache is rebuilt after the merge table changes
+    tokenizer.load_merges([((104, 105), 256)].into_iter().collect());
+    assert_eq!(tokenizer.decode_bytes(&[256, 257]), b"hi");
+}
+
+#[test]
+fn set_pattern_recompiles_and_rejects_invalid() {
+    let mut tokenizer = Tokenizer::default();
+    assert_eq!(tokenizer.get_pattern(), DEFAULT_PATTERN);
+
+    tokenizer.set_pattern(r"\S+|\s+".to_string()).unwrap();
+    assert_eq!(tokenizer.get_pattern(), r"\S+|\s+");
+    assert_eq!(tokenizer.encode("a,b").len(), 3);
+
+    assert!(tokenizer.set_pattern("(unclosed".to_string()).is_err());
+    assert_eq!(tokenizer.get_pattern(), r"\S+|\s+");
+}
+
+/// Run `train_from_iterator` over an in-memory Python list
+#[cfg(feature = "python")]
+fn train_on(tokenizer: &mut Tokenizer, texts: &[&str], vocab_size: u32) -> PyResult<()> {
+    Python::with_gil(|py| {
+        let corpus = pyo3::types::PyList::new_bound(py, texts);
+        tokenizer.train_from_iterator(py, corpus.as_any(), vocab_size, 2)
+    })
+}
+
+/// `train_from_iterator`'s counting and training without Python: `texts`
+/// are counted two at a time, like the Python `train_on`
+#[cfg(not(feature = "python"))]
+fn train_on(tokenizer: &mut Tokenizer, texts: &[&str], vocab_size: u32) -> TokenizerResult<()> {
+    check_vocab_size(vocab_size).map_err(TokenizerError::Value)?;
+    let mut global_counts = ChunkCounts::default();
+    for (i, batch) in texts.chunks(2).enumerate() {
+        let batch_counts = tokenizer
+            .count_batch(&tokenizer.compiled_pattern, batch, 2 * i as u64)
+            .map_err(regex_error)?;
+        global_counts = merge_chunk_counts(global_counts, batch_counts);
+    }
+    tokenizer.last_train_unique_chunks = global_counts.len();
+    let (words, counts) = tokenizer.words_from_counts(global_counts);
+    check_vocab_reachable(&words, 0, vocab_size).map_err(TokenizerError::Value)?;
+    tokenizer.merges.clear();
+    tokenizer.train_core(words, counts, vocab_size)?;
+    Ok(())
+}
+
+#[test]
+fn last_train_unique_chunks_counts_distinct_chunks() {
+    let mut tokenizer = Tokenizer::default();
+    assert_eq!(tokenizer.last_train_unique_chunks(), 0);
+
+    // Chunks: "hello", " world", " hello", "world" -> 4 distinct
+    train_on(
+        &mut tokenizer,
+        &["hello world", "hello hello", "world"],
+        260,
+    )
+    .unwrap();
+    assert_eq!(tokenizer.last_train_unique_chunks(), 4);
+}
+
+#[test]
+fn longest_token_strategy_differs_from_earliest_merge() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges(
+        [((97, 98), 256), ((98, 99), 257), ((97, 257), 258)] // "ab", "bc", "abc"
+            .into_iter()
+            .collect(),
+    );
+
+    let earliest = tokenizer.encode_with_strategy("abc", EncodeStrategy::EarliestMerge);
+    assert_eq!(earliest, tokenizer.encode("abc"));
+    assert_eq!(earliest, vec![256, 99]);
+
+    let longest = tokenizer.encode_with_strategy("abc", EncodeStrategy::LongestToken);
+    assert_eq!(longest, vec![258]);
+    assert_eq!(tokenizer.decode_bytes(&longest), b"abc");
+}
+
+#[test]
+fn token_histogram_counts_every_occurrence() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((108, 108), 256)].into_iter().collect()); // "ll"
+
+    let hist = tokenizer.token_histogram(vec!["hello".to_string(), "all".to_string()]);
+    assert_eq!(hist[&256], 2);
+    assert_eq!(hist[&(b'h' as u32)], 1);
+    assert!(!hist.contains_key(&(b'l' as u32)));
+    assert_eq!(hist.values().sum::<u64>(), 6);
+}
+
+// Nested quantifier behind a lookahead forces fancy-regex's backtracking VM,
+// which gives up once its backtrack limit is exceeded.
+const CATASTROPHIC_PATTERN: &str = r"(a+)+(?=b)|\s+|a";
+
+#[test]
+fn regex_runtime_errors_surface_in_encode_and_training() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer
+        .set_pattern(CATASTROPHIC_PATTERN.to_string())
+        .unwrap();
+    let text = "a".repeat(64);
+
+    assert!(tokenizer.try_encode(&text).is_err());
+    assert!(tokenizer.try_encode(" ").is_ok());
+
+    let err = train_on(&mut tokenizer, &[&text], 260).unwrap_err();
+    assert!(err.to_string().contains("regex"), "{}", err);
+}
+
+#[test]
+fn special_pattern_runtime_errors_propagate() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer
+        .register_special_pattern(r"(a+)+(?=b)".to_string(), 300)
+        .unwrap();
+    let text = "a".repeat(64);
+
+    let err = tokenizer.try_encode(&text).unwrap_err();
+    assert!(err.to_string().contains("special token regex"), "{}", err);
+    assert!(tokenizer.try_pretokenize(&format!("x {}", text)).is_err());
+    assert_eq!(tokenizer.try_encode("aab").unwrap(), vec![300, b'b' as u32]);
+}
+
+#[test]
+fn try_encoders_return_regex_runtime_errors() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer
+        .set_pattern(CATASTROPHIC_PATTERN.to_string())
+        .unwrap();
+    let text = "a".repeat(64);
+    let texts = vec![" ".to_string(), text.clone()];
+
+    assert!(tokenizer.try_encode_with_offsets(&text, false).is_err());
+    assert!(tokenizer.try_encode_budget(&text, 4).is_err());
+    assert!(tokenizer.try_pretokenize(&text).is_err());
+    assert!(tokenizer.try_encode_i64(&text).is_err());
+    assert!(tokenizer.try_encode_batch(texts.clone()).is_err());
+    assert!(tokenizer.try_encode_batch_flat(texts.clone()).is_err());
+    assert!(tokenizer.try_hot_merges(texts.clone(), 4).is_err());
+    assert!(tokenizer.try_token_cooccurrence(texts, 2).is_err());
+    assert_eq!(
+        tokenizer.try_encode_batch(vec![" ".to_string()]).unwrap(),
+        vec![vec![32]]
+    );
+}
+
+#[test]
+#[should_panic(expected = "pretokenization regex failed")]
+fn encode_panics_instead_of_dropping_text_on_regex_error() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer
+        .set_pattern(CATASTROPHIC_PATTERN.to_string())
+        .unwrap();
+    tokenizer.encode(&"a".repeat(64));
+}
+
+#[test]
+fn agreement_is_fraction_of_identical_encodings() {
+    let base = Tokenizer::default();
+    let mut retrained = Tokenizer::default();
+    retrained.load_merges([((108, 108), 256)].into_iter().collect()); // "ll"
+
+    let texts = vec![
+        "hello".to_string(),
+        "world".to_string(),
+        "all".to_string(),
+        "xyz".to_string(),
+    ];
+    assert_eq!(base.agreement(&base, texts.clone()), 1.0);
+    assert_eq!(base.agreement(&retrained, texts), 0.5);
+    assert_eq!(base.agreement(&retrained, Vec::new()), 1.0);
+}
+
+#[test]
+fn initial_alphabet_controls_base_ids() {
+    let mut tokenizer = Tokenizer::default();
+
+    // Swap 'a' and 'b': still lossless
+    let mut swapped = IDENTITY_ALPHABET.to_vec();
+    swapped.swap(b'a' as usize, b'b' as usize);
+    tokenizer.set_initial_alphabet(Some(swapped)).unwrap();
+    assert_eq!(tokenizer.encode("ab"), vec![b'b' as u32, b'a' as u32]);
+    assert_eq!(tokenizer.decode(tokenizer.encode("abc")), "abc");
+
+    // Collapse every non-ASCII byte onto 0x80: decode is lossy by design
+    let restricted: Vec<u32> = (0..256u32).map(|b| b.min(0x80)).collect();
+    tokenizer.set_initial_alphabet(Some(restricted)).unwrap();
+    let ids = tokenizer.encode("é");
+    assert_eq!(ids, vec![0x80, 0x80]);
+    assert_eq!(tokenizer.decode_bytes(&ids), vec![0x80, 0x80]);
+
+    let data = tokenizer.to_bytes();
+    let restored = Tokenizer::from_bytes(&data).unwrap();
+    assert_eq!(
+        restored.get_initial_alphabet(),
+        tokenizer.get_initial_alphabet()
+    );
+
+    assert!(tokenizer.set_initial_alphabet(Some(vec![0; 10])).is_err());
+    assert!(tokenizer
+        .set_initial_alphabet(Some(vec![300; 256]))
+        .is_err());
+    tokenizer.set_initial_alphabet(None).unwrap();
+    assert_eq!(tokenizer.get_initial_alphabet(), IDENTITY_ALPHABET.to_vec());
+}
+
+#[test]
+fn parallel_decode_matches_sequential() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((104, 105), 256), ((256, 33), 257)].into_iter().collect());
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let ids: Vec<u32> = (0..(PARALLEL_DECODE_THRESHOLD * 3 + 17))
+        .map(|i| [257, 32, 5000, 0xC3, 0xA9, 256][i % 6])
+        .collect();
+
+    let mut sequential = Vec::new();
+    tokenizer.decode_into(&ids, &mut sequential);
+    assert_eq!(tokenizer.decode_bytes(&ids), sequential);
+}
+
+#[test]
+fn dead_tokens_lists_unused_vocab_ids() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((104, 105), 256), ((120, 121), 257)].into_iter().collect());
+    tokenizer.register_special_token("hi".to_string(), 5000);
+
+    let dead = tokenizer.dead_tokens(vec!["xy ".to_string(), "hi".to_string()]);
+    assert!(dead.contains(&256), "'hi' is always emitted as the special");
+    assert!(!dead.contains(&257));
+    assert!(!dead.contains(&32));
+    assert!(dead.contains(&(b'z' as u32)));
+    assert!(!dead.contains(&5000), "specials are not vocab entries");
+    assert_eq!(dead.len(), 256 + 2 - 2);
+}
+
+#[test]
+fn encode_batch_flat_matches_encode_batch() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((108, 108), 256)].into_iter().collect());
+
+    for n in [0, 3, 250] {
+        let texts: Vec<String> = (0..n)
+            .map(|i| format!("hello {}", "x".repeat(i % 7)))
+            .collect();
+        let nested = tokenizer.encode_batch(texts.clone());
+        let (flat, offsets) = tokenizer.encode_batch_flat(texts);
+
+        assert_eq!(offsets.len(), n + 1);
+        assert_eq!(offsets[0], 0);
+        assert_eq!(*offsets.last().unwrap(), flat.len());
+        for (i, expected) in nested.iter().enumerate() {
+            assert_eq!(&flat[offsets[i]..offsets[i + 1]], expected.as_slice());
+        }
+    }
+}
+
+#[test]
+fn special_tokens_match_across_chunks_and_fold_case() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    tokenizer.register_special_token_case_insensitive("<|sys|>".to_string(), 5001);
+
+    // The pattern alone would split "<|eot|>" into several chunks
+    let text = "hi<|eot|><|SyS|> <|EOT|>";
+    let (ids, offsets) = tokenizer.encode_with_offsets(text, false);
+    assert_eq!(ids, tokenizer.encode(text));
+    assert_eq!(&ids[..4], &[104, 105, 5000, 5001]);
+    assert_eq!(offsets[2], (2, 9));
+    assert_eq!(offsets[3], (9, 16));
+    assert!(!ids[4..].contains(&5000), "case-sensitive by default");
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert_eq!(restored.encode(text), ids);
+
+    tokenizer.register_special_token("<|sys|>".to_string(), 5001);
+    assert!(!tokenizer.encode("<|SYS|>").contains(&5001));
+}
+
+#[test]
+fn training_memory_estimate_scales_with_inputs() {
+    let base = Tokenizer::estimate_training_memory(100_000, 6, 50_000);
+    assert!(base > 0);
+    assert!(Tokenizer::estimate_training_memory(200_000, 6, 50_000) > base);
+    assert!(Tokenizer::estimate_training_memory(100_000, 12, 50_000) > base);
+    assert!(Tokenizer::estimate_training_memory(100_000, 6, 100_000) > base);
+    // Words alone need at least a u32 per byte
+    assert!(base > 100_000 * 6 * 4);
+    assert_eq!(
+        Tokenizer::estimate_training_memory(0, 0, 256),
+        Tokenizer::estimate_training_memory(0, 0, 100)
+    );
+}
+
+#[test]
+#[cfg(feature = "python")]
+fn frozen_merges_survive_continued_training() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(
+        &mut tokenizer,
+        &["hello world", "hello there", "world peace"],
+        270,
+    )
+    .unwrap();
+    let original = tokenizer.merges.clone();
+    let frozen: StdHashMap<Pair, u32> = original
+        .iter()
+        .filter(|&(_, &id)| id < 264)
+        .map(|(&p, &id)| (p, id))
+        .collect();
+
+    Python::with_gil(|py| {
+        let texts =
+            pyo3::types::PyList::new_bound(py, ["hello hello", "peace peace", "wonderful weather"]);
+        tokenizer
+            .resume_training(py, texts.as_any(), 275, 2, Some(264))
+            .unwrap();
+    });
+
+    for (pair, id) in &frozen {
+        assert_eq!(tokenizer.merges.get(pair), Some(id));
+    }
+    assert_eq!(tokenizer.merges.values().filter(|&&id| id < 264).count(), 8);
+    assert!(tokenizer.merges.values().all(|&id| id < 275));
+    assert!(check_merge_ids(&tokenizer.merges).is_ok());
+
+    Python::with_gil(|py| {
+        let texts = pyo3::types::PyList::new_bound(py, ["x"]);
+        assert!(tokenizer
+            .resume_training(py, texts.as_any(), 300, 2, Some(1000))
+            .is_err());
+
+        // A run that fails before training keeps every merge, frozen or not
+        let before = tokenizer.merges.clone();
+        assert!(tokenizer
+            .resume_training(py, texts.as_any(), 300, 2, Some(260))
+            .is_err());
+        let bad = pyo3::types::PyList::new_bound(py, [1, 2]);
+        assert!(tokenizer
+            .resume_training(py, bad.as_any(), 280, 2, Some(260))
+            .is_err());
+        assert_eq!(tokenizer.merges, before);
+    });
+}
+
+#[test]
+fn rust_train_matches_python_entry_point() {
+    let texts = ["hello world", "hello there", "world peace"];
+    let mut from_python = Tokenizer::default();
+    train_on(&mut from_python, &texts, 270).unwrap();
+
+    let corpus: Vec<(String, u32)> = texts.iter().map(|t| (t.to_string(), 1)).collect();
+    let mut from_rust = Tokenizer::default();
+    from_rust.train(&corpus, 270).unwrap();
+    assert_eq!(from_rust.merges, from_python.merges);
+
+    // Pre-counted chunks weigh the same as repeated texts
+    let mut weighted = Tokenizer::default();
+    weighted
+        .train(&[("hello".to_string(), 3), (" there".to_string(), 1)], 260)
+        .unwrap();
+    let mut repeated = Tokenizer::default();
+    train_on(&mut repeated, &["hello", "hello", "hello", " there"], 260).unwrap();
+    assert_eq!(weighted.merges, repeated.merges);
+}
+
+#[test]
+fn split_digits_keeps_digits_in_separate_tokens() {
+    let texts = [
+        "year 2024 2024 2024",
+        "call 555 1234 5550",
+        "x1 x2 x3 x1 x1",
+    ];
+    let mut unrestricted = Tokenizer::default();
+    train_on(&mut unrestricted, &texts, 265).unwrap();
+    let multi_digit = |t: &Tokenizer| {
+        t.token_bytes()
+            .iter()
+            .any(|bytes| bytes.iter().filter(|b| b.is_ascii_digit()).count() > 1)
+    };
+    assert!(multi_digit(&unrestricted));
+
+    let mut tokenizer = Tokenizer::new().unwrap();
+    tokenizer.split_digits = true;
+    train_on(&mut tokenizer, &texts, 265).unwrap();
+    assert!(!multi_digit(&tokenizer));
+    assert!(check_merge_ids(&tokenizer.merges).is_ok());
+}
+
+#[test]
+fn add_merge_appends_sequential_ids() {
+    let mut tokenizer = Tokenizer::default();
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let hel = tokenizer.add_merge(he, b'l' as u32).unwrap();
+    assert_eq!((he, hel), (256, 257));
+    assert_eq!(tokenizer.encode("hel"), vec![257]);
+
+    assert!(tokenizer.add_merge(b'h' as u32, b'e' as u32).is_err());
+    assert!(tokenizer.add_merge(300, b'l' as u32).is_err());
+    assert_eq!(tokenizer.merges.len(), 2);
+
+    tokenizer.register_special_token("<eot>".to_string(), 258);
+    assert!(tokenizer.add_merge(b'h' as u32, b'i' as u32).is_err());
+    assert_eq!(tokenizer.encode("<eot>"), vec![258]);
+    assert_eq!(tokenizer.merges.len(), 2);
+}
+
+#[test]
+fn single_token_detection_requires_one_vocab_token() {
+    let mut tokenizer = Tokenizer::default();
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    assert_eq!(tokenizer.as_single_token("he"), Some(he));
+    assert_eq!(tokenizer.as_single_token("x"), Some(b'x' as u32));
+    assert_eq!(tokenizer.as_single_token("hex"), None);
+    assert_eq!(tokenizer.as_single_token(""), None);
+    assert!(!tokenizer.is_single_token("<|eot|>"));
+    assert!(tokenizer.is_single_token("he"));
+}
+
+#[test]
+fn tie_break_selects_among_equal_counts() {
+    // After "zz" (count 3) merges first, (a, zz) and (b, c) tie at 2
+    let run = |tie_break: TieBreak, corpus: &[(&str, u32)]| {
+        let mut tokenizer = Tokenizer::new().unwrap();
+        tokenizer.tie_break = tie_break;
+        let corpus: Vec<(String, u32)> = corpus.iter().map(|&(t, c)| (t.to_string(), c)).collect();
+        tokenizer.train(&corpus, 258).unwrap();
+        assert_eq!(tokenizer.merges[&(b'z' as u32, b'z' as u32)], 256);
+        tokenizer
+            .merges
+            .iter()
+            .find(|&(_, &id)| id == 257)
+            .map(|(&p, _)| p)
+            .unwrap()
+    };
+    let azz = (b'a' as u32, 256);
+    let bc = (b'b' as u32, b'c' as u32);
+    let corpus = [("azz", 2), ("bc", 2), ("zz", 1)];
+    let reordered = [("bc", 2), ("azz", 2), ("zz", 1)];
+
+    assert_eq!(run(TieBreak::SmallerPair, &corpus), azz);
+    assert_eq!(run(TieBreak::SmallerPair, &reordered), azz);
+    assert_eq!(run(TieBreak::ShorterToken, &corpus), bc);
+    assert_eq!(run(TieBreak::ByFirstOccurrence, &corpus), azz);
+    assert_eq!(run(TieBreak::ByFirstOccurrence, &reordered), bc);
+}
+
+#[test]
+fn encode_truncate_left_keeps_the_tail() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges(
+        [((108, 108), 256), ((b'o' as u32, b' ' as u32), 257)]
+            .into_iter()
+            .collect(),
+    );
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let text = "hello world<|eot|> all well, hello";
+    let full = tokenizer.encode(text);
+    for max_len in [0, 1, 3, 7, full.len(), full.len() + 5] {
+        let tail = tokenizer.encode_truncate_left(text, max_len);
+        assert_eq!(tail, full[full.len().saturating_sub(max_len)..]);
+    }
+}
+
+#[test]
+fn ranked_tokens_rebuild_merges() {
+    let mut trained = Tokenizer::default();
+    train_on(
+        &mut trained,
+        &["hello world", "hello there", "world peace"],
+        275,
+    )
+    .unwrap();
+    let ranked: Vec<(Vec<u8>, u32)> = trained
+        .token_bytes()
+        .iter()
+        .enumerate()
+        .map(|(id, bytes)| (bytes.clone(), id as u32))
+        .collect();
+
+    let mut rebuilt = Tokenizer::default();
+    rebuilt.load_from_ranked_tokens(ranked.clone()).unwrap();
+    assert_eq!(rebuilt.merges, trained.merges);
+
+    let multi_only: Vec<_> = ranked.into_iter().filter(|(b, _)| b.len() > 1).collect();
+    let mut identity = Tokenizer::default();
+    identity.load_from_ranked_tokens(multi_only).unwrap();
+    assert_eq!(identity.merges, trained.merges);
+
+    // "abc" cannot be split into two known tokens without "ab" or "bc"
+    let mut broken = Tokenizer::default();
+    assert!(broken
+        .load_from_ranked_tokens(vec![(b"abc".to_vec(), 256)])
+        .is_err());
+    assert!(broken.merges.is_empty());
+
+    // Multi-byte ranks must be contiguous from 256 and unique
+    for ranks in [[256, 256], [256, 258], [255, 256]] {
+        let tokens = vec![(b"ab".to_vec(), ranks[0]), (b"cd".to_vec(), ranks[1])];
+        assert!(broken.load_from_ranked_tokens(tokens).is_err());
+    }
+    // Single-byte ranks must be distinct and below 256
+    let mut singles: Vec<(Vec<u8>, u32)> = (0..=255u8).map(|b| (vec![b], b as u32)).collect();
+    singles[1].1 = 0;
+    assert!(broken.load_from_ranked_tokens(singles.clone()).is_err());
+    singles[1].1 = 300;
+    assert!(broken.load_from_ranked_tokens(singles).is_err());
+    assert!(broken.merges.is_empty());
+}
+
+#[test]
+fn uncovered_spans_reveal_pattern_gaps() {
+    let mut tokenizer = Tokenizer::default();
+    assert!(tokenizer
+        .uncovered_spans("Hello, world!  It's 2024\n")
+        .is_empty());
+
+    tokenizer.set_pattern(r"\p{L}+".to_string()).unwrap();
+    assert_eq!(tokenizer.uncovered_spans("ab, cd!"), vec![(2, 4), (6, 7)]);
+    assert_eq!(tokenizer.uncovered_spans("12"), vec![(0, 2)]);
+}
+
+#[test]
+#[cfg(feature = "python")]
+fn train_from_iterators_matches_one_concatenated_iterator() {
+    let shards = [
+        vec!["hello world", "hello there"],
+        vec![],
+        vec!["world peace", "hello"],
+    ];
+    let all: Vec<&str> = shards.iter().flatten().copied().collect();
+    let mut combined = Tokenizer::default();
+    train_on(&mut combined, &all, 270).unwrap();
+
+    let mut sharded = Tokenizer::default();
+    Python::with_gil(|py| {
+        let iterators: Vec<Bound<'_, PyAny>> = shards
+            .iter()
+            .map(|s| pyo3::types::PyList::new_bound(py, s).into_any())
+            .collect();
+        sharded.train_from_iterators(py, iterators, 270, 2).unwrap();
+    });
+    assert_eq!(sharded.merges, combined.merges);
+    assert_eq!(
+        sharded.last_train_unique_chunks(),
+        combined.last_train_unique_chunks()
+    );
+}
+
+#[test]
+fn longest_token_reports_the_widest_merge() {
+    let mut tokenizer = Tokenizer::default();
+    assert_eq!(tokenizer.longest_token(), (0, 1, "\0".to_string()));
+
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let ll = tokenizer.add_merge(b'l' as u32, b'l' as u32).unwrap();
+    let hell = tokenizer.add_merge(he, ll).unwrap();
+    tokenizer.add_merge(b'o' as u32, b'k' as u32).unwrap();
+    assert_eq!(tokenizer.longest_token(), (hell, 4, "hell".to_string()));
+}
+
+#[test]
+fn encode_budget_stops_at_chunk_boundaries() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((108, 108), 256)].into_iter().collect());
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let text = "hello world<|eot|> again";
+    let full = tokenizer.encode(text);
+    let (all, consumed) = tokenizer.encode_budget(text, 100);
+    assert_eq!((all, consumed), (full.clone(), text.len()));
+
+    // "hello" is 4 tokens and " world" 6, so a budget of 7 stops after "hello"
+    let (head, consumed) = tokenizer.encode_budget(text, 7);
+    assert_eq!((head.as_slice(), consumed), (&full[..4], 5));
+    let (rest, _) = tokenizer.encode_budget(&text[consumed..], 100);
+    assert_eq!([head, rest].concat(), full);
+
+    // A first chunk wider than the budget is cut rather than stalling
+    let (cut, consumed) = tokenizer.encode_budget(text, 3);
+    assert_eq!((cut.as_slice(), consumed), (&full[..3], 4));
+    assert_eq!(tokenizer.encode_budget(text, 0), (vec![], 0));
+}
+
+#[test]
+fn warmup_populates_lazy_caches() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((108, 108), 256)].into_iter().collect());
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    assert!(tokenizer.cache.bytes.get().is_none());
+
+    tokenizer.warmup();
+    assert!(tokenizer.cache.bytes.get().is_some());
+    assert!(tokenizer.cache.id_by_bytes.get().is_some());
+    assert!(tokenizer.cache.special_by_id.get().is_some());
+    assert!(tokenizer
+        .cache
+        .special_matcher
+        .get()
+        .is_some_and(Option::is_some));
+}
+
+#[test]
+fn special_patterns_match_token_families() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    tokenizer
+        .register_special_pattern(r"<\|[a-z_]+\|>".to_string(), 5001)
+        .unwrap();
+    tokenizer
+        .register_special_pattern(r"<\|\w+\|>".to_string(), 5002)
+        .unwrap();
+    assert!(tokenizer
+        .register_special_pattern("(".to_string(), 1)
+        .is_err());
+
+    let (ids, offsets) = tokenizer.encode_with_offsets("a<|eot|><|tool_call|>b<|X1|>", false);
+    assert_eq!(ids, vec![b'a' as u32, 5000, 5001, b'b' as u32, 5002]);
+    assert_eq!(offsets[2], (8, 21));
+    assert_eq!(tokenizer.max_token_id(), 5002);
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert_eq!(restored.encode("<|im_start|>"), vec![5001]);
+}
+
+#[test]
+fn bytes_to_token_inverts_the_byte_table() {
+    let mut tokenizer = Tokenizer::default();
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let hel = tokenizer.add_merge(he, b'l' as u32).unwrap();
+
+    assert_eq!(tokenizer.bytes_to_token(b"hel"), Some(hel));
+    assert_eq!(tokenizer.bytes_to_token(&[0xff]), Some(0xff));
+    assert_eq!(tokenizer.bytes_to_token(b"el"), None);
+    assert_eq!(tokenizer.bytes_to_token(b""), None);
+    for (id, bytes) in tokenizer.token_bytes().iter().enumerate() {
+        assert_eq!(tokenizer.bytes_to_token(bytes), Some(id as u32));
+    }
+}
+
+#[test]
+fn word_marker_shapes_training_encoding_and_decoding() {
+    let texts = ["low lower lowest", "low slow"];
+    let mut tokenizer = Tokenizer::new().unwrap();
+    tokenizer.set_word_marker(Some("▁".to_string()), false);
+    train_on(&mut tokenizer, &texts, 270).unwrap();
+
+    // Every chunk starts with the marker, so merges learn it as a prefix
+    let marker = "▁".as_bytes();
+    assert!(tokenizer
+        .token_bytes()
+        .iter()
+        .any(|bytes| bytes.len() > marker.len() && bytes.starts_with(marker)));
+
+    let text = "low slower";
+    let (ids, offsets) = tokenizer.encode_with_offsets(text, false);
+    assert_eq!(ids, tokenizer.encode(text));
+    assert_eq!(tokenizer.decode(ids), text);
+    assert_eq!(offsets.last().unwrap().1, text.len());
+    assert!(tokenizer.is_single_token("low"));
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert_eq!(restored.get_word_marker(), Some(("▁".to_string(), false)));
+    assert_eq!(restored.encode(text), tokenizer.encode(text));
+
+    tokenizer.set_word_marker(Some("</w>".to_string()), true);
+    let tail = tokenizer.encode_with_offsets("ab", false);
+    assert_eq!(tail.0.len(), 6);
+    assert_eq!(tail.1[2..], [(2, 2), (2, 2), (2, 2), (2, 2)]);
+}
+
+#[test]
+#[cfg(feature = "python")]
+fn training_items_are_checked_and_bytes_accepted() {
+    Python::with_gil(|py| {
+        let mut tokenizer = Tokenizer::default();
+        let items = pyo3::types::PyList::new_bound(py, ["hello", "hello"]);
+        items.append(PyBytes::new_bound(py, b"hello")).unwrap();
+        tokenizer
+            .train_from_iterator(py, items.as_any(), 258, 2)
+            .unwrap();
+        assert_eq!(tokenizer.merges.len(), 2);
+
+        items.append(3).unwrap();
+        let err = tokenizer
+            .train_from_iterator(py, items.as_any(), 258, 2)
+            .unwrap_err();
+        assert!(err.is_instance_of::<pyo3::exceptions::PyTypeError>(py));
+        let message = err.to_string();
+        assert!(message.contains("item 3 is int"), "{}", message);
+
+        // Bytes that are not UTF-8 are counted as raw bytes, not rejected
+        let raw = pyo3::types::PyList::new_bound(
+            py,
+            [
+                PyBytes::new_bound(py, b"ab\xff\xfeab\xff\xfe"),
+                PyBytes::new_bound(py, b"\xff\xfe"),
+            ],
+        );
+        tokenizer
+            .train_from_iterator(py, raw.as_any(), 257, 2)
+            .unwrap();
+        assert_eq!(
+            tokenizer.merges,
+            [((0xff, 0xfe), 256)].into_iter().collect()
+        );
+        assert_eq!(tokenizer.last_train_unique_chunks(), 2);
+    });
+}
+
+#[test]
+fn encode_pieces_show_each_token_text() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges(
+        [((108, 108), 256), ((256, b'o' as u32), 257)]
+            .into_iter()
+            .collect(),
+    );
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let pieces = tokenizer.encode_pieces_compact("hello<|eot|>é");
+    // Without a merge, "é" is two byte tokens that each end mid-character
+    assert_eq!(pieces, ["h", "e", "llo", "<|eot|>", "\u{FFFD}", "\u{FFFD}"]);
+    assert!(pieces.iter().all(|p| !p.is_heap_allocated()));
+    assert_eq!(tokenizer.encode_to_pieces("hello"), vec!["h", "e", "llo"]);
+}
+
+#[test]
+fn training_rejects_impossible_vocab_sizes() {
+    let mut tokenizer = Tokenizer::default();
+    let err = train_on(&mut tokenizer, &["hello"], 200).unwrap_err();
+    assert!(err.to_string().contains("at least 256"));
+
+    // "hello" has 4 adjacent pairs, so at most 4 merges
+    train_on(&mut tokenizer, &["hello"], 260).unwrap();
+    let err = train_on(&mut tokenizer, &["hello"], 261).unwrap_err();
+    assert!(err.to_string().contains("at most 4 more merges"), "{}", err);
+    assert!(tokenizer.train(&[("hello".to_string(), 1)], 100).is_err());
+}
+
+#[test]
+fn whitespace_chunk_width_splits_indentation() {
+    let mut tokenizer = Tokenizer::with_preset("gpt4").unwrap();
+    let text = "if x:\n          return y";
+    let chunks = |t: &Tokenizer| {
+        t.chunks(text)
+            .map(|(_, c)| c.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert!(chunks(&tokenizer).contains(&"         ".to_string()));
+
+    tokenizer.whitespace_chunk_width = 4;
+    let split = chunks(&tokenizer);
+    assert!(split
+        .iter()
+        .all(|c| c.trim().is_empty() || !c.starts_with("  ")));
+    assert_eq!(split.iter().filter(|c| c.as_str() == "    ").count(), 2);
+    assert_eq!(split.concat(), text);
+
+    let (ids, offsets) = tokenizer.encode_with_offsets(text, false);
+    assert_eq!(ids, tokenizer.encode(text));
+    assert_eq!(offsets.last().unwrap().1, text.len());
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert_eq!(restored.whitespace_chunk_width, 4);
+}
+
+#[test]
+fn encode_trace_records_each_merge_step() {
+    let mut tokenizer = Tokenizer::default();
+    let ll = tokenizer.add_merge(108, 108).unwrap();
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let hell = tokenizer.add_merge(he, ll).unwrap();
+
+    assert_eq!(
+        tokenizer.encode_trace("hello hell"),
+        vec![(2, ll), (0, he), (0, hell), (8, ll), (6, he), (6, hell)]
+    );
+    assert!(tokenizer.encode_trace("xyz").is_empty());
+}
+
+#[test]
+fn load_merges_keeps_only_a_prefix_when_limited() {
+    let mut trained = Tokenizer::default();
+    train_on(
+        &mut trained,
+        &["hello world", "hello there", "world peace"],
+        270,
+    )
+    .unwrap();
+
+    let mut small = Tokenizer::default();
+    small.load_merges_limited(trained.merges.clone(), 5);
+    assert_eq!(small.merges.len(), 5);
+    assert!(small.merges.values().all(|&id| id < 261));
+    assert!(check_merge_ids(&small.merges).is_ok());
+    assert_eq!(small.decode(small.encode("hello world")), "hello world");
+
+    small.load_merges_limited(trained.merges.clone(), 1000);
+    assert_eq!(small.merges, trained.merges);
+
+    let path = std::env::temp_dir().join(format!("tok_prefix_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    trained.save(path).unwrap();
+    let loaded = Tokenizer::load_limited(path, 5);
+    let full = Tokenizer::load(path);
+    std::fs::remove_file(path).unwrap();
+    let mut prefix = Tokenizer::default();
+    prefix.load_merges_limited(trained.merges.clone(), 5);
+    assert_eq!(loaded.unwrap().merges, prefix.merges);
+    assert_eq!(full.unwrap().merges, trained.merges);
+}
+
+#[test]
+fn debug_tokens_renders_pieces_specials_and_escapes() {
+    let mut tokenizer = Tokenizer::default();
+    let th = tokenizer.add_merge(b't' as u32, b'h' as u32).unwrap();
+    let the = tokenizer.add_merge(th, b'e' as u32).unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let ids = [the, b' ' as u32, 5000, b'\n' as u32, 0xc3, 9999];
+    assert_eq!(
+        tokenizer.debug_tokens(&ids),
+        r"[the]·[ ]·[<|eot|>]·[\x0a]·[\xc3]·[#9999]"
+    );
+    assert_eq!(tokenizer.debug_tokens(&[]), "");
+}
+
+#[test]
+fn merge_depth_histogram_reflects_tree_shape() {
+    let mut tokenizer = Tokenizer::default();
+    assert_eq!(tokenizer.merge_depth_histogram(), vec![256]);
+
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let ll = tokenizer.add_merge(b'l' as u32, b'l' as u32).unwrap();
+    let hell = tokenizer.add_merge(he, ll).unwrap();
+    tokenizer.add_merge(hell, b'o' as u32).unwrap();
+    tokenizer.add_merge(b'o' as u32, b'k' as u32).unwrap();
+    assert_eq!(tokenizer.merge_depth_histogram(), vec![256, 3, 1, 1]);
+}
+
+#[test]
+fn literal_tokens_are_protected_and_lose_ties_to_specials() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer
+        .register_literal_token("user_id".to_string(), 6000)
+        .unwrap();
+    tokenizer
+        .register_literal_token("<|eot".to_string(), 6001)
+        .unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    // Matched anywhere, even inside what the pattern would split apart
+    assert_eq!(
+        tokenizer.encode("get_user_id"),
+        [b'g', b'e', b't', b'_']
+            .iter()
+            .map(|&b| b as u32)
+            .chain([6000])
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(tokenizer.encode("<|eot|>"), vec![5000]);
+    assert_eq!(tokenizer.encode_ordinary("user_id"), vec![6000]);
+    assert_eq!(tokenizer.decode_bytes(&[6000, 5000]), b"user_id<|eot|>");
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert_eq!(restored.encode("x user_id"), tokenizer.encode("x user_id"));
+
+    // IDs that already decode to something else are refused
+    let ab = tokenizer.add_merge(b'a' as u32, b'b' as u32).unwrap();
+    for id in [b'x' as u32, ab, 5000, 6000] {
+        assert!(tokenizer
+            .register_literal_token("clash".to_string(), id)
+            .is_err());
+    }
+    assert!(!tokenizer.literal_tokens.contains_key("clash"));
+}
+
+#[test]
+fn reset_allows_retraining_from_scratch() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    train_on(&mut tokenizer, &["aaaa bbbb aaaa"], 260).unwrap();
+    assert!(!tokenizer.merges.is_empty());
+
+    tokenizer.reset(false);
+    assert!(tokenizer.merges.is_empty());
+    assert_eq!(tokenizer.last_train_unique_chunks(), 0);
+    assert_eq!(tokenizer.encode("aa<|eot|>"), vec![97, 97, 5000]);
+
+    let mut fresh = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello hello world"], 262).unwrap();
+    train_on(&mut fresh, &["hello hello world"], 262).unwrap();
+    assert_eq!(tokenizer.merges, fresh.merges);
+
+    tokenizer.reset(true);
+    assert!(tokenizer.special_tokens.is_empty());
+}
+
+#[test]
+fn encode_pair_marks_second_sequence_with_type_one() {
+    let tokenizer = Tokenizer::default();
+    let (tokens, type_ids) = tokenizer.encode_pair("ab", "c", 5000);
+    assert_eq!(tokens, vec![97, 98, 5000, 99]);
+    assert_eq!(type_ids, vec![0, 0, 0, 1]);
+
+    let (tokens, type_ids) = tokenizer.encode_pair("", "", 5000);
+    assert_eq!(tokens, vec![5000]);
+    assert_eq!(type_ids, vec![0]);
+}
+
+#[test]
+fn find_tokens_matches_substrings_in_id_order() {
+    let mut tokenizer = Tokenizer::default();
+    let ti = tokenizer.add_merge(b't' as u32, b'i' as u32).unwrap();
+    let on = tokenizer.add_merge(b'o' as u32, b'n' as u32).unwrap();
+    let tion = tokenizer.add_merge(ti, on).unwrap();
+
+    assert_eq!(
+        tokenizer.find_tokens("tion"),
+        vec![(tion, "tion".to_string())]
+    );
+    assert_eq!(
+        tokenizer.find_tokens("on"),
+        vec![(on, "on".to_string()), (tion, "tion".to_string())]
+    );
+    assert!(tokenizer.find_tokens("xyz").is_empty());
+}
+
+#[test]
+fn encode_nbest_starts_with_greedy_and_lists_alternatives() {
+    let mut tokenizer = Tokenizer::default();
+    let ab = tokenizer.add_merge(b'a' as u32, b'b' as u32).unwrap();
+    let bc = tokenizer.add_merge(b'b' as u32, b'c' as u32).unwrap();
+
+    let nbest = tokenizer.encode_nbest("abc abc", 4);
+    assert_eq!(nbest[0], tokenizer.encode("abc abc"));
+    assert_eq!(nbest[0], vec![ab, 99, 32, ab, 99]);
+    assert_eq!(nbest.len(), 4);
+    assert!(nbest.contains(&vec![97, bc, 32, ab, 99]));
+    assert_eq!(nbest[3], vec![97, bc, 32, 97, bc]);
+
+    assert_eq!(tokenizer.encode_nbest("abc", 1), vec![vec![ab, 99]]);
+    assert!(tokenizer.encode_nbest("abc", 0).is_empty());
+
+    // Merge IDs below 256 from an unchecked table don't underflow the rank
+    tokenizer.load_merges([((b'x' as u32, b'y' as u32), 100)].into_iter().collect());
+    assert_eq!(tokenizer.encode_nbest("xy", 2), vec![vec![100]]);
+}
+
+#[test]
+fn ascii_pattern_matches_gpt4_on_plain_ascii() {
+    let gpt4 = Tokenizer::with_preset("gpt4").unwrap();
+    let ascii = Tokenizer::with_preset("ascii").unwrap();
+    let pieces = |t: &Tokenizer, text: &str| -> Vec<String> {
+        t.chunks(text).map(|(_, c)| c.to_string()).collect()
+    };
+
+    let text = "Hello, world! It's 2024.\nDONE";
+    assert_eq!(pieces(&ascii, text), pieces(&gpt4, text));
+    assert_eq!(pieces(&ascii, "a  b"), ["a", "  ", "b"]);
+}
+
+#[test]
+fn benchmark_encode_reports_consistent_rates() {
+    let tokenizer = Tokenizer::default();
+    let texts = vec!["hello world ".repeat(2000); 4];
+    let (tokens_per_sec, bytes_per_sec) = tokenizer.benchmark_encode(texts);
+    assert!(tokens_per_sec > 0.0);
+    // Without merges every byte is one token
+    assert!((bytes_per_sec / tokens_per_sec - 1.0).abs() < 1e-9);
+
+    assert_eq!(tokenizer.benchmark_encode(Vec::new()), (0.0, 0.0));
+}
+
+#[test]
+fn save_and_load_stream_merges_line_by_line() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello hello world wonderful"], 265).unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    tokenizer.set_word_marker(Some("_".to_string()), false);
+
+    let path = std::env::temp_dir().join(format!("tok_save_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    tokenizer.save(&path).unwrap();
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(text.lines().count(), 1 + tokenizer.merges.len());
+    assert!(text.lines().nth(1).unwrap().starts_with('['));
+
+    let loaded = Tokenizer::load(&path).unwrap();
+    assert_eq!(loaded.merges, tokenizer.merges);
+    assert_eq!(loaded.to_bytes(), tokenizer.to_bytes());
+
+    // A file cut short is rejected rather than loaded with missing merges
+    let truncated: Vec<&str> = text.lines().take(3).collect();
+    std::fs::write(&path, truncated.join("\n")).unwrap();
+    assert!(Tokenizer::load(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn find_forward_references_lists_bad_merges_and_decode_survives() {
+    let mut tokenizer = Tokenizer::default();
+    let merges: StdHashMap<(u32, u32), u32> = [
+        ((104, 105), 256), // "hi"
+        ((257, 97), 258),  // fine: 257 < 258
+        ((258, 98), 257),  // forward reference to 258
+        ((259, 97), 259),  // refers to itself
+    ]
+    .into_iter()
+    .collect();
+    tokenizer.load_merges(merges);
+
+    assert_eq!(
+        tokenizer.find_forward_references(),
+        vec![((258, 98), 257), ((259, 97), 259)]
+    );
+    assert_eq!(tokenizer.decode_bytes(&[256, 259]), b"hia");
+}
+
+#[test]
+fn train_from_files_matches_training_on_lines() {
+    let dir = std::env::temp_dir();
+    let a = dir.join(format!("tok_files_a_{}.txt", std::process::id()));
+    let b = dir.join(format!("tok_files_b_{}.txt", std::process::id()));
+    std::fs::write(&a, "hello hello\nworld wide\n").unwrap();
+    // Last line without a newline, plus a byte that is not UTF-8
+    std::fs::write(&b, b"hello world\nwide \xff web").unwrap();
+    let paths = [
+        a.to_str().unwrap().to_string(),
+        b.to_str().unwrap().to_string(),
+    ];
+
+    let mut from_files = Tokenizer::default();
+    from_files.train_from_files(&paths, 262, 2).unwrap();
+
+    let mut from_lines = Tokenizer::default();
+    train_on(
+        &mut from_lines,
+        &[
+            "hello hello",
+            "world wide",
+            "",
+            "hello world",
+            "wide \u{fffd} web",
+        ],
+        262,
+    )
+    .unwrap();
+    assert_eq!(from_files.merges, from_lines.merges);
+
+    std::fs::remove_file(&a).unwrap();
+    std::fs::remove_file(&b).unwrap();
+}
+
+#[test]
+fn next_merge_matches_the_first_trained_merge() {
+    let words: Vec<Vec<u32>> = ["hello", "yellow", "low"]
+        .iter()
+        .map(|w| w.bytes().map(u32::from).collect())
+        .collect();
+    let counts = [3, 2, 4];
+
+    let mut tokenizer = Tokenizer::default();
+    let (pair, count) = tokenizer.next_merge(&words, &counts).unwrap().unwrap();
+    assert_eq!(pair, (b'l' as u32, b'o' as u32));
+    assert_eq!(count, 9);
+
+    let corpus: Vec<(String, u32)> = ["hello", "yellow", "low"]
+        .iter()
+        .zip(counts)
+        .map(|(w, c)| (w.to_string(), c as u32))
+        .collect();
+    tokenizer.train(&corpus, 257).unwrap();
+    assert_eq!(tokenizer.merges.get(&pair), Some(&256));
+
+    assert_eq!(tokenizer.next_merge(&[vec![97]], &[5]).unwrap(), None);
+    assert!(tokenizer.next_merge(&words, &[1]).is_err());
+}
+
+#[test]
+fn encode_with_word_ids_maps_tokens_to_chunks() {
+    let mut tokenizer = Tokenizer::default();
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let (tokens, word_ids) = tokenizer.encode_with_word_ids("hey you<|eot|>he");
+    assert_eq!(tokens, tokenizer.encode("hey you<|eot|>he"));
+    assert_eq!(tokens, vec![he, 121, 32, 121, 111, 117, 5000, he]);
+    assert_eq!(word_ids, vec![0, 0, 1, 1, 1, 1, 2, 3]);
+}
+
+#[test]
+fn split_leading_space_shares_word_tokens() {
+    let mut tokenizer = Tokenizer::with_preset("gpt4").unwrap();
+    tokenizer.split_leading_space = true;
+    train_on(&mut tokenizer, &["hello hello hello", "hello  world"], 264).unwrap();
+
+    let text = "hello hello  world";
+    let chunks: Vec<_> = tokenizer.chunks(text).map(|(_, c)| c.to_string()).collect();
+    assert_eq!(chunks, ["hello", " ", "hello", " ", " ", "world"]);
+
+    let ids = tokenizer.encode(text);
+    assert_eq!(ids[0], ids[2]);
+    assert_eq!(ids[1], b' ' as u32);
+    assert_eq!(tokenizer.decode(ids.clone()), text);
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert!(restored.split_leading_space);
+    assert_eq!(restored.encode(text), ids);
+}
+
+#[test]
+fn last_token_matches_full_encode() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(
+        &mut tokenizer,
+        &["hello world", "hello there", "world peace"],
+        270,
+    )
+    .unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 500);
+
+    for text in ["hello world", "say hello", "peace<|eot|>", "x", "  "] {
+        assert_eq!(
+            tokenizer.last_token(text),
+            tokenizer.encode(text).last().copied()
+        );
+    }
+    assert_eq!(tokenizer.last_token(""), None);
+}
+
+#[test]
+fn decode_survives_pathological_merge_chains() {
+    // A 5000-deep chain: each token is the previous one plus 'a'
+    let mut deep = Tokenizer::default();
+    let mut last = b'a' as u32;
+    for _ in 0..5000 {
+        last = deep.add_merge(last, b'a' as u32).unwrap();
+    }
+    assert_eq!(deep.try_decode(&[last]).unwrap(), "a".repeat(5001));
+
+    // Each merge doubles the previous token: 2^40 bytes if left unchecked
+    let mut doubling = Tokenizer::default();
+    let mut ids = vec![b'a' as u32];
+    for _ in 0..40 {
+        let top = *ids.last().unwrap();
+        ids.push(doubling.add_merge(top, top).unwrap());
+    }
+    let huge = *ids.last().unwrap();
+    let fits = ids[20];
+    assert_eq!(doubling.decode_bytes(&[fits]).len(), MAX_TOKEN_BYTES);
+    assert!(doubling.try_decode_bytes(&[fits, 98]).is_ok());
+    assert!(doubling.try_decode_bytes(&[98, huge]).is_err());
+    assert_eq!(
+        doubling.decode(vec![98, huge, 98]),
+        format!("b<oversized_token:{}>b", huge)
+    );
+    // 1025 tokens of 1 MiB each are refused before anything is expanded
+    assert!(doubling.try_decode_bytes(&vec![fits; 1025]).is_err());
+}
+
+#[test]
+fn save_tiktoken_writes_base64_rank_lines() {
+    for (raw, encoded) in [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+    ] {
+        assert_eq!(base64_encode(raw.as_bytes()), encoded);
+    }
+
+    let mut tokenizer = Tokenizer::default();
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let hel = tokenizer.add_merge(he, b'l' as u32).unwrap();
+
+    let path = std::env::temp_dir().join(format!("tok_tiktoken_{}.model", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    tokenizer.save_tiktoken(&path).unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 258);
+    assert_eq!(lines[32], "IA== 32");
+    assert_eq!(lines[256], format!("aGU= {}", he));
+    assert_eq!(lines[257], format!("aGVs {}", hel));
+}
+
+#[test]
+fn encode_sampled_varies_with_seed_and_is_greedy_when_cold() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(
+        &mut tokenizer,
+        &["hello world", "hello there", "world peace", "help held"],
+        280,
+    )
+    .unwrap();
+    let text = "hello world, hello there";
+    let greedy = tokenizer.encode(text);
+
+    assert_eq!(tokenizer.encode_sampled(text, 0.0, 7), greedy);
+    assert_eq!(tokenizer.encode_sampled(text, 1e-9, 7), greedy);
+    assert_eq!(
+        tokenizer.encode_sampled(text, 2.0, 7),
+        tokenizer.encode_sampled(text, 2.0, 7)
+    );
+
+    let samples: StdHashSet<Vec<u32>> = (0..32)
+        .map(|seed| tokenizer.encode_sampled(text, 2.0, seed))
+        .collect();
+    assert!(samples.len() > 1);
+    for ids in &samples {
+        assert_eq!(tokenizer.decode(ids.clone()), text);
+    }
+}
+
+#[test]
+fn special_token_for_id_reverses_special_tokens() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    tokenizer
+        .register_special_pattern(r"<\|img_\d+\|>".to_string(), 5001)
+        .unwrap();
+    tokenizer
+        .register_literal_token("<br>".to_string(), 5002)
+        .unwrap();
+
+    assert_eq!(
+        tokenizer.special_token_for_id(5000).as_deref(),
+        Some("<|eot|>")
+    );
+    assert!(tokenizer.is_special_id(5000));
+    assert_eq!(tokenizer.special_token_for_id(5001), None);
+    assert!(tokenizer.is_special_id(5001));
+    assert_eq!(tokenizer.special_token_for_id(5002), None);
+    assert!(!tokenizer.is_special_id(5002));
+    assert!(!tokenizer.is_special_id(104));
+}
+
+#[test]
+fn count_corpus_counts_pattern_chunks() {
+    let pattern = compile_pattern(DEFAULT_PATTERN).unwrap();
+    let counts = Tokenizer::count_corpus(&["hello world", "hello hello", ""], &pattern).unwrap();
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts["hello"], 2);
+    assert_eq!(counts[" hello"], 1);
+    assert_eq!(counts[" world"], 1);
+
+    let catastrophic = compile_pattern(CATASTROPHIC_PATTERN).unwrap();
+    let err = Tokenizer::count_corpus(&[&"a".repeat(64)], &catastrophic).unwrap_err();
+    assert!(err.to_string().contains("regex"), "{}", err);
+}
+
+#[test]
+fn last_train_reached_target_flags_short_vocabs() {
+    let mut tokenizer = Tokenizer::default();
+    assert!(!tokenizer.last_train_reached_target());
+
+    train_on(&mut tokenizer, &["hello world", "hello there"], 260).unwrap();
+    assert!(tokenizer.last_train_reached_target());
+
+    // Every pair is digit-digit, so split_digits leaves nothing to merge
+    tokenizer.split_digits = true;
+    train_on(&mut tokenizer, &["123 456", "789"], 258).unwrap();
+    assert!(tokenizer.merges.is_empty());
+    assert!(!tokenizer.last_train_reached_target());
+}
+
+#[test]
+fn repeated_token_runs_finds_consecutive_repeats() {
+    let tokenizer = Tokenizer::default();
+    // Without merges every byte is its own token
+    assert_eq!(
+        tokenizer.repeated_token_runs("aaab!!!!c", 3),
+        vec![(97, 0, 3), (33, 4, 4)]
+    );
+    assert_eq!(tokenizer.repeated_token_runs("abc", 2), vec![]);
+    assert_eq!(tokenizer.repeated_token_runs("", 1), vec![]);
+    assert_eq!(tokenizer.repeated_token_runs("xy", 0).len(), 2);
+}
+
+#[test]
+fn register_special_tokens_follows_conflict_policy() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let batch = || vec![("<|pad|>".to_string(), 5001), ("<|eot|>".to_string(), 6000)];
+    assert!(tokenizer
+        .register_special_tokens(batch(), SpecialConflict::Error)
+        .is_err());
+    assert!(!tokenizer.special_tokens.contains_key("<|pad|>"));
+
+    tokenizer
+        .register_special_tokens(batch(), SpecialConflict::Ignore)
+        .unwrap();
+    assert_eq!(tokenizer.special_tokens["<|eot|>"], 5000);
+    assert_eq!(tokenizer.special_tokens["<|pad|>"], 5001);
+
+    // Re-registering the same ID is not a conflict
+    tokenizer
+        .register_special_token_with_policy("<|eot|>".to_string(), 5000, SpecialConflict::Error)
+        .unwrap();
+    tokenizer
+        .register_special_token_with_policy("<|eot|>".to_string(), 6000, SpecialConflict::default())
+        .unwrap();
+    assert_eq!(tokenizer.encode("<|eot|>"), vec![6000]);
+}
+
+#[test]
+fn token_edit_distance_counts_token_edits() {
+    let bytes = Tokenizer::default();
+    let mut merged = Tokenizer::default();
+    let ll = merged.add_merge(108, 108).unwrap();
+    merged.add_merge(b'h' as u32, b'e' as u32).unwrap();
+
+    assert_eq!(bytes.token_edit_distance(&bytes, "hello"), 0);
+    // [h e l l o] -> [he ll o]: two substitutions and two deletions
+    assert_eq!(bytes.token_edit_distance(&merged, "hello"), 4);
+    assert_eq!(merged.token_edit_distance(&bytes, "hello"), 4);
+    // Only the "ll" differs: [w a ll] vs [w a l l]
+    assert_eq!(merged.encode("wall"), vec![119, 97, ll]);
+    assert_eq!(merged.token_edit_distance(&bytes, "wall"), 2);
+    assert_eq!(merged.token_edit_distance(&bytes, ""), 0);
+}
+
+#[test]
+fn encode_bytes_only_skips_merges() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello world", "hello there"], 270).unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let text = "hello<|eot|> world";
+    let ids = tokenizer.encode_bytes_only(text);
+    assert_eq!(ids.len(), text.len() - "<|eot|>".len() + 1);
+    assert_eq!(ids[5], 5000);
+    assert!(ids.iter().all(|&id| id < 256 || id == 5000));
+    assert!(tokenizer.encode(text).len() < ids.len());
+    assert_eq!(tokenizer.decode(ids), text);
+}
+
+#[test]
+fn validation_texts_report_compression_after_training() {
+    let corpus = ["hello world", "hello there", "world peace"];
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &corpus, 270).unwrap();
+    assert_eq!(tokenizer.last_train_validation_ratio(), None);
+
+    let held_out = vec!["hello peace".to_string(), "there world".to_string()];
+    tokenizer.validation_texts = held_out.clone();
+    train_on(&mut tokenizer, &corpus, 260).unwrap();
+    let small = tokenizer.last_train_validation_ratio().unwrap();
+    assert_eq!(small, tokenizer.compression_ratio(held_out));
+
+    train_on(&mut tokenizer, &corpus, 270).unwrap();
+    assert!(tokenizer.last_train_validation_ratio().unwrap() > small);
+}
+
+#[test]
+fn encode_batch_keeps_input_order_across_threshold() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello world", "hello there"], 270).unwrap();
+
+    for size in [
+        PARALLEL_BATCH_THRESHOLD - 1,
+        PARALLEL_BATCH_THRESHOLD,
+        3 * PARALLEL_BATCH_THRESHOLD,
+    ] {
+        let texts: Vec<String> = (0..size)
+            .map(|i| format!("hello {} world{}", i, "!".repeat(i % 7)))
+            .collect();
+        let expected: Vec<Vec<u32>> = texts.iter().map(|t| tokenizer.encode(t)).collect();
+
+        for threshold in [PARALLEL_BATCH_THRESHOLD, 0, 1, size, size + 1, usize::MAX] {
+            tokenizer.parallel_threshold = threshold;
+            assert_eq!(tokenizer.encode_batch(texts.clone()), expected);
+
+            let (flat, offsets) = tokenizer.encode_batch_flat(texts.clone());
+            let unflattened: Vec<Vec<u32>> = offsets
+                .windows(2)
+                .map(|w| flat[w[0]..w[1]].to_vec())
+                .collect();
+            assert_eq!(unflattened, expected);
+        }
+    }
+}
+
+#[test]
+fn batch_statistics_agree_on_both_sides_of_threshold() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello world", "hello there"], 270).unwrap();
+    let texts: Vec<String> = (0..2 * PARALLEL_BATCH_THRESHOLD)
+        .map(|i| format!("hello {} world{}", i, "!".repeat(i % 7)))
+        .collect();
+    let stats = |tokenizer: &Tokenizer| {
+        (
+            tokenizer.token_histogram(texts.clone()),
+            tokenizer.compression_ratio(texts.clone()),
+            tokenizer.token_cooccurrence(texts.clone(), 2),
+            tokenizer.hot_merges(texts.clone(), 5),
+            tokenizer.scan_byte_coverage(texts.clone()),
+            tokenizer.agreement(&Tokenizer::default(), texts.clone()),
+            tokenizer.pattern_impact(r"\S+|\s+", texts.clone()).unwrap(),
+            tokenizer.specialize(texts.clone()).unwrap().1,
+        )
+    };
+
+    tokenizer.parallel_threshold = usize::MAX;
+    let sequential = stats(&tokenizer);
+    let entropy = tokenizer.pair_entropy(texts.clone());
+    tokenizer.parallel_threshold = 0;
+    assert_eq!(stats(&tokenizer), sequential);
+    assert!((tokenizer.pair_entropy(texts.clone()) - entropy).abs() < 1e-9);
+}
+
+#[test]
+fn mergeable_sides_follow_merges() {
+    let mut tokenizer = Tokenizer::default();
+    assert!(tokenizer.mergeable_lefts().is_empty());
+
+    let he = tokenizer.add_merge(b'h' as u32, b'e' as u32).unwrap();
+    let hel = tokenizer.add_merge(he, b'l' as u32).unwrap();
+    tokenizer.add_merge(hel, b'l' as u32).unwrap();
+
+    let lefts: AHashSet<u32> = [b'h' as u32, he, hel].into_iter().collect();
+    let rights: AHashSet<u32> = [b'e' as u32, b'l' as u32].into_iter().collect();
+    assert_eq!(tokenizer.mergeable_lefts(), lefts);
+    assert_eq!(tokenizer.mergeable_rights(), rights);
+}
+
+#[test]
+fn allowed_bytes_map_other_bytes_to_unk() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello world", "hello there"], 270).unwrap();
+    tokenizer.register_special_token("<unk>".to_string(), 5000);
+    let mut ascii = [false; 256];
+    ascii[..128].fill(true);
+    assert!(tokenizer.set_allowed_bytes(Some(ascii), None).is_err());
+    tokenizer
+        .set_allowed_bytes(Some(ascii), Some(5000))
+        .unwrap();
+
+    // "é" is two bytes, so two unks
+    let ids = tokenizer.encode("hello é");
+    assert_eq!(&ids[ids.len() - 2..], &[5000, 5000]);
+    assert_eq!(&ids[..ids.len() - 2], &tokenizer.encode("hello ")[..]);
+    assert_eq!(tokenizer.decode(ids.clone()), "hello <unk><unk>");
+
+    let longest = tokenizer.encode_with_strategy("hello é", EncodeStrategy::LongestToken);
+    assert!(longest.ends_with(&[5000, 5000]));
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert_eq!(restored.get_allowed_bytes(), Some(ascii));
+    assert_eq!(restored.get_unk_id(), Some(5000));
+}
+
+#[test]
+fn from_parts_builds_and_validates_in_one_step() {
+    let merges: StdHashMap<(u32, u32), u32> =
+        [((104, 101), 256), ((256, 108), 257)].into_iter().collect();
+    let specials: StdHashMap<String, u32> = [("<|eot|>".to_string(), 300)].into_iter().collect();
+
+    let tokenizer = Tokenizer::from_parts(
+        merges.clone(),
+        Some(r"\S+|\s+".to_string()),
+        specials.clone(),
+    )
+    .unwrap();
+    assert_eq!(tokenizer.get_pattern(), r"\S+|\s+");
+    assert_eq!(tokenizer.encode("hel<|eot|>"), vec![257, 300]);
+
+    let gap: StdHashMap<(u32, u32), u32> = [((104, 101), 257)].into_iter().collect();
+    assert!(Tokenizer::from_parts(gap, None, StdHashMap::new()).is_err());
+    let forward: StdHashMap<(u32, u32), u32> =
+        [((257, 101), 256), ((104, 101), 257)].into_iter().collect();
+    assert!(Tokenizer::from_parts(forward, None, StdHashMap::new()).is_err());
+
+    let on_merge: StdHashMap<String, u32> = [("<|eot|>".to_string(), 257)].into_iter().collect();
+    assert!(Tokenizer::from_parts(merges.clone(), None, on_merge).is_err());
+    let shared: StdHashMap<String, u32> =
+        [("<|eot|>".to_string(), 300), ("<|pad|>".to_string(), 300)]
+            .into_iter()
+            .collect();
+    assert!(Tokenizer::from_parts(merges, None, shared).is_err());
+    assert!(Tokenizer::from_parts(StdHashMap::new(), Some("(".to_string()), specials).is_err());
+}
+
+#[test]
+fn sliding_window_encoder_matches_full_encode_tail() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(
+        &mut tokenizer,
+        &["hello world", "hello there", "world peace"],
+        270,
+    )
+    .unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+
+    let pieces = [
+        "hello",
+        " wor",
+        "ld",
+        "<|eot|>",
+        "",
+        " there",
+        " pe",
+        "ace hello",
+    ];
+    for max_window in [3, 8, 100] {
+        let mut window = SlidingWindowEncoder::new(max_window);
+        let mut text = String::new();
+        for piece in pieces {
+            window.push(&tokenizer, piece);
+            text.push_str(piece);
+            let full = tokenizer.encode(&text);
+            assert_eq!(
+                window.tokens(),
+                full[full.len().saturating_sub(max_window)..]
+            );
+        }
+        assert_eq!(window.len(), max_window.min(tokenizer.encode(&text).len()));
+    }
+}
+
+#[test]
+fn bytes_for_token_budget_splits_a_document() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(
+        &mut tokenizer,
+        &["hello world", "hello there", "world peace"],
+        270,
+    )
+    .unwrap();
+    let doc = "hello world, hello there. world peace hello world";
+
+    let mut rest = doc;
+    let mut pieces = Vec::new();
+    while !rest.is_empty() {
+        let n = tokenizer.bytes_for_token_budget(rest, 4);
+        assert!(n > 0);
+        assert!(tokenizer.encode(&rest[..n]).len() <= 4);
+        pieces.push(&rest[..n]);
+        rest = &rest[n..];
+    }
+    assert_eq!(pieces.concat(), doc);
+    assert_eq!(tokenizer.bytes_for_token_budget(doc, 0), 0);
+    assert_eq!(tokenizer.bytes_for_token_budget(doc, usize::MAX), doc.len());
+
+    // Inside one long chunk the cut lands between characters, taking a whole
+    // character even when it alone is over the budget
+    let wide = "é".repeat(5);
+    assert_eq!(tokenizer.bytes_for_token_budget(&wide, 3), 2);
+    assert_eq!(tokenizer.bytes_for_token_budget(&wide, 1), 2);
+    assert_eq!(
+        tokenizer.encode_budget(&wide, 4),
+        (wide.bytes().take(4).map(u32::from).collect(), 4)
+    );
+}
+
+#[test]
+fn no_cross_script_keeps_latin_and_cjk_apart() {
+    let corpus = ["abc中文 abc中文", "abc中文abc", "中文abc 中文"];
+    let mixes = |t: &Tokenizer| {
+        t.token_bytes().iter().any(|bytes| {
+            let text = String::from_utf8_lossy(bytes);
+            text.chars().any(|c| c.is_ascii_alphabetic()) && bytes.iter().any(|&b| b >= 0x80)
+        })
+    };
+
+    let mut plain = Tokenizer::with_preset("gpt4").unwrap();
+    train_on(&mut plain, &corpus, 275).unwrap();
+    assert!(mixes(&plain));
+
+    let mut filtered = Tokenizer::with_preset("gpt4").unwrap();
+    filtered.merge_filter = MergeFilter::NoCrossScript;
+    train_on(&mut filtered, &corpus, 270).unwrap();
+    assert!(!mixes(&filtered));
+    assert!(filtered.merges.len() > 5);
+
+    assert_eq!(Script::of_bytes("中".as_bytes()), Script::Cjk);
+    assert_eq!(Script::of_bytes(&"中".as_bytes()[..1]), Script::Cjk);
+    assert_eq!(Script::of_bytes(b" 42, abc"), Script::Latin);
+    assert_eq!(Script::of_bytes(b"\x80\x80"), Script::Neutral);
+}
+
+#[test]
+fn special_tokens_save_and_load_on_their_own() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.add_merge(104, 105).unwrap();
+    tokenizer.register_special_token("<|eot|>".to_string(), 5000);
+    tokenizer.register_special_token("<|pad|>".to_string(), 5001);
+
+    let path = std::env::temp_dir().join(format!("tok_specials_{}.json", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    tokenizer.save_special_tokens(&path).unwrap();
+    let json = std::fs::read_to_string(&path).unwrap();
+    assert!(json.find("<|eot|>").unwrap() < json.find("<|pad|>").unwrap());
+
+    let mut other = Tokenizer::default();
+    other.add_merge(104, 105).unwrap();
+    other.register_special_token("<|old|>".to_string(), 6000);
+    other.load_special_tokens(&path).unwrap();
+    assert_eq!(other.special_tokens, tokenizer.special_tokens);
+    assert_eq!(other.encode("<|pad|>"), vec![5001]);
+
+    // 256 is a merge here
+    std::fs::write(&path, r#"{"<|bad|>": 256}"#).unwrap();
+    assert!(other.load_special_tokens(&path).is_err());
+    assert_eq!(other.special_tokens, tokenizer.special_tokens);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn encode_rle_compacts_runs_of_the_same_token() {
+    let tokenizer = Tokenizer::default();
+    assert_eq!(
+        tokenizer.encode_rle("aaab!!"),
+        vec![(97, 3), (98, 1), (33, 2)]
+    );
+    assert!(tokenizer.encode_rle("").is_empty());
+
+    let text = "x....  y";
+    let expanded: Vec<u32> = tokenizer
+        .encode_rle(text)
+        .into_iter()
+        .flat_map(|(id, len)| std::iter::repeat_n(id, len as usize))
+        .collect();
+    assert_eq!(expanded, tokenizer.encode(text));
+}
+
+#[test]
+fn strip_bom_and_zero_width_normalize_before_tokenizing() {
+    let corpus = [
+        "\u{feff}hello world",
+        "hel\u{200b}lo there",
+        "hello\u{200d} world",
+    ];
+    let mut tokenizer = Tokenizer {
+        strip_bom: true,
+        strip_zero_width: true,
+        ..Default::default()
+    };
+    train_on(&mut tokenizer, &corpus, 270).unwrap();
+    assert!(!tokenizer
+        .token_bytes()
+        .iter()
+        .any(|bytes| String::from_utf8_lossy(bytes).contains(ZERO_WIDTH_CHARS)));
+
+    let clean = tokenizer.encode("hello world");
+    assert_eq!(tokenizer.encode("\u{feff}hel\u{200c}lo world"), clean);
+    assert_eq!(tokenizer.encode_ordinary("\u{feff}hello world"), clean);
+    assert_eq!(tokenizer.decode(clean.clone()), "hello world");
+
+    // Offset and budget encoders normalize too, reporting positions in the
+    // original text
+    let text = "\u{feff}hel\u{200c}lo world";
+    let (ids, offsets) = tokenizer.encode_with_offsets(text, false);
+    assert_eq!(ids, clean);
+    let seen: String = offsets
+        .iter()
+        .map(|&(start, end)| &text[start..end])
+        .collect();
+    assert_eq!(seen, "hel\u{200c}lo world");
+    assert_eq!(offsets[0].0, 3);
+    assert_eq!(
+        tokenizer.encode_budget(text, ids.len()),
+        (clean, text.len())
+    );
+    let steps = tokenizer.encode_trace(text);
+    assert_eq!(steps.len(), tokenizer.encode_trace("hello world").len());
+    assert!(steps
+        .iter()
+        .all(|&(offset, _)| text.is_char_boundary(offset)));
+
+    // Only a leading BOM is a BOM; elsewhere U+FEFF counts as zero-width
+    tokenizer.strip_zero_width = false;
+    assert_eq!(tokenizer.encode("\u{feff}hello"), tokenizer.encode("hello"));
+    assert_ne!(tokenizer.encode("hel\u{feff}lo"), tokenizer.encode("hello"));
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert!(restored.strip_bom && !restored.strip_zero_width);
+}
+
+#[test]
+fn merge_diff_reports_added_removed_and_renumbered() {
+    let mut old = Tokenizer::default();
+    old.add_merge(104, 101).unwrap(); // he: 256
+    old.add_merge(108, 108).unwrap(); // ll: 257
+    old.add_merge(111, 32).unwrap(); // "o ": 258
+
+    let mut new = Tokenizer::default();
+    new.add_merge(108, 108).unwrap(); // ll: 256
+    new.add_merge(104, 101).unwrap(); // he: 257
+    new.add_merge(119, 111).unwrap(); // wo: 258
+
+    let (added, removed, changed) = old.merge_diff(&new);
+    assert_eq!(added, vec![((119, 111), 258)]);
+    assert_eq!(removed, vec![((111, 32), 258)]);
+    assert_eq!(
+        changed,
+        vec![((104, 101), 256, 257), ((108, 108), 257, 256)]
+    );
+
+    let (added, removed, changed) = old.merge_diff(&old);
+    assert!(added.is_empty() && removed.is_empty() && changed.is_empty());
+}
+
+#[test]
+fn encode_with_mask_masks_tokens_without_resegmenting() {
+    let mut t = Tokenizer::default();
+    train_on(&mut t, &["hello world hello world"], 264).unwrap();
+    let text = "hello world, hello there";
+    let plain = t.encode(text);
+
+    assert_eq!(t.encode_with_mask(text, 999, 0.0, 7), plain);
+    assert_eq!(
+        t.encode_with_mask(text, 999, 1.0, 7),
+        vec![999; plain.len()]
+    );
+
+    let masked = t.encode_with_mask(text, 999, 0.5, 7);
+    assert_eq!(masked, t.encode_with_mask(text, 999, 0.5, 7));
+    assert_eq!(masked.len(), plain.len());
+    assert!(masked.iter().zip(&plain).all(|(&m, &p)| m == p || m == 999));
+}
+
+#[test]
+fn merge_path_lists_each_merge_once_in_build_order() {
+    let mut t = Tokenizer::default();
+    t.add_merge(97, 98).unwrap(); // ab: 256
+    t.add_merge(256, 256).unwrap(); // abab: 257
+    t.add_merge(257, 99).unwrap(); // ababc: 258
+
+    assert_eq!(t.merge_path(97), vec![]);
+    assert_eq!(t.merge_path(256), vec![(97, 98)]);
+    assert_eq!(t.merge_path(258), vec![(97, 98), (256, 256), (257, 99)]);
+    assert_eq!(t.merge_path(999), vec![]);
+
+    // A 50k-deep chain on a small stack: each token is the previous plus 'a'
+    let mut last = b'a' as u32;
+    for _ in 0..50_000 {
+        last = t.add_merge(last, b'a' as u32).unwrap();
+    }
+    let path = std::thread::Builder::new()
+        .stack_size(256 * 1024)
+        .spawn(move || t.merge_path(last))
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(path.len(), 50_000);
+    assert_eq!(path[0], (97, 97));
+    assert_eq!(path[49_999], (last - 1, 97));
+}
+
+#[test]
+#[cfg(feature = "python")]
+fn encode_into_buffer_fills_and_truncates() {
+    Python::with_gil(|py| {
+        let tokenizer = Tokenizer::default();
+        let ids = tokenizer.encode("hello");
+        let array = py.import_bound("array").unwrap().getattr("array").unwrap();
+        let read = |buffer: &Bound<'_, PyAny>| -> Vec<u32> {
+            buffer.call_method0("tolist").unwrap().extract().unwrap()
+        };
+
+        let big = array.call1(("I", vec![0u32; 8])).unwrap();
+        assert_eq!(
+            tokenizer
+                .encode_into_buffer(py, "hello", &big, true)
+                .unwrap(),
+            5
+        );
+        assert_eq!(read(&big)[..5], ids[..]);
+        assert_eq!(read(&big)[5..], [0, 0, 0]);
+
+        let small = array.call1(("I", vec![0u32; 3])).unwrap();
+        assert!(tokenizer
+            .encode_into_buffer(py, "hello", &small, true)
+            .is_err());
+        assert_eq!(read(&small), [0, 0, 0]);
+        assert_eq!(
+            tokenizer
+                .encode_into_buffer(py, "hello", &small, false)
+                .unwrap(),
+            3
+        );
+        assert_eq!(read(&small), ids[..3]);
+
+        let wrong_type = array.call1(("d", vec![0.0f64; 8])).unwrap();
+        assert!(tokenizer
+            .encode_into_buffer(py, "hello", &wrong_type, false)
+            .is_err());
+    });
+}
+
+#[test]
+fn load_merges_remapped_closes_id_gaps() {
+    let mut tokenizer = Tokenizer::default();
+    let merges = [((104, 101), 300), ((108, 108), 310), ((300, 310), 400)]
+        .into_iter()
+        .collect();
+    let remap = tokenizer.load_merges_remapped(merges).unwrap();
+
+    assert_eq!(
+        remap,
+        [(300, 256), (310, 257), (400, 258)].into_iter().collect()
+    );
+    let expected: StdHashMap<Pair, u32> = [((104, 101), 256), ((108, 108), 257), ((256, 257), 258)]
+        .into_iter()
+        .collect();
+    assert_eq!(tokenizer.merges, expected);
+    assert_eq!(tokenizer.decode(vec![258]), "hell");
+
+    let forward = [((104, 101), 300), ((400, 101), 350)].into_iter().collect();
+    assert!(tokenizer.load_merges_remapped(forward).is_err());
+    let low = [((104, 101), 100)].into_iter().collect();
+    assert!(tokenizer.load_merges_remapped(low).is_err());
+    assert_eq!(tokenizer.merges, expected);
+}
+
+#[test]
+fn word_token_counts_attribute_tokens_to_chunks() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges([((108, 108), 256)].into_iter().collect()); // "ll"
+    tokenizer.register_special_token("<eos>".to_string(), 1000);
+
+    let (tokens, counts) = tokenizer.encode_with_word_token_counts("hello world<eos>");
+    assert_eq!(tokens, tokenizer.encode("hello world<eos>"));
+    // "hello" -> h e ll o, " world" -> 6 bytes, "<eos>" -> one special
+    assert_eq!(counts, vec![4, 6, 1]);
+    assert_eq!(counts.iter().sum::<usize>(), tokens.len());
+}
+
+#[test]
+fn max_merges_per_second_slows_training_down() {
+    let corpus: Vec<(String, u32)> = (0..200).map(|i| (format!("w{i:03} "), 1)).collect();
+    let mut tokenizer = Tokenizer::new().unwrap();
+    tokenizer.max_merges_per_second = Some(400.0);
+
+    let start = std::time::Instant::now();
+    tokenizer.train(&corpus, 256 + 64).unwrap();
+    assert_eq!(tokenizer.merges.len(), 64);
+    // One check after 64 merges, due at 64 / 400 s
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+
+    assert_eq!(
+        throttle_due(64, 400.0),
+        std::time::Duration::from_millis(160)
+    );
+    assert_eq!(throttle_due(64, 1e-300), std::time::Duration::MAX);
+    assert_eq!(throttle_due(64, f64::INFINITY), std::time::Duration::ZERO);
+}
+
+#[test]
+fn encode_is_stable_across_scripts_emoji_and_newlines() {
+    let sample = [
+        "hello world",
+        "  indented\n\tlines\r\n\n",
+        "naïve café, Ünïcödé",
+        "日本語のテキスト and English",
+        "Привет, мир! 123 4567",
+        "emoji 👍🏽👨‍👩‍👧 family",
+        "مرحبا بالعالم",
+        "<|endoftext|> not registered",
+        "",
+    ];
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &sample, 300).unwrap();
+    tokenizer.register_special_token("<|endoftext|>".to_string(), 5000);
+
+    for text in sample {
+        assert!(tokenizer.is_stable(text), "{text:?}");
+    }
+}
+
+#[test]
+fn stream_decoder_holds_back_split_characters() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<eos>".to_string(), 1000);
+    let mut ids = tokenizer.encode("é👍");
+    assert_eq!(ids.len(), 6); // one token per byte, no merges
+    ids.push(1000);
+
+    let mut stream = StreamDecoder::new();
+    let pieces: Vec<String> = ids.iter().map(|&id| stream.push(&tokenizer, id)).collect();
+    assert_eq!(pieces, ["", "é", "", "", "", "👍", "<eos>"]);
+    assert_eq!(stream.finish(), "");
+
+    // A lone continuation byte can never complete; a dangling lead byte is
+    // flushed by a special token or by `finish`
+    assert_eq!(stream.push(&tokenizer, 0x80), "\u{fffd}");
+    assert_eq!(stream.push(&tokenizer, 0xe2), "");
+    assert_eq!(stream.push(&tokenizer, 1000), "\u{fffd}<eos>");
+    assert_eq!(stream.push(&tokenizer, 0xe2), "");
+    assert_eq!(stream.finish(), "\u{fffd}");
+}
+
+#[test]
+fn stream_decoder_matches_decode_with_a_word_marker() {
+    for (marker, at_end) in [("▁", false), ("</w>", true)] {
+        let mut tokenizer = Tokenizer::default();
+        tokenizer.set_word_marker(Some(marker.to_string()), at_end);
+        train_on(&mut tokenizer, &["hello world hello"], 262).unwrap();
+        tokenizer.register_special_token("<eos>".to_string(), 1000);
+        let mut ids = tokenizer.encode("hello world é");
+        ids.extend([999, 1000]);
+
+        let mut stream = StreamDecoder::new();
+        let mut streamed: String = ids.iter().map(|&id| stream.push(&tokenizer, id)).collect();
+        streamed += &stream.finish();
+        assert_eq!(streamed, tokenizer.decode(ids.clone()));
+        assert_eq!(streamed, "hello world é<eos>");
+
+        tokenizer.unknown_id_policy = UnknownIdPolicy::Placeholder;
+        let streamed: String = ids.iter().map(|&id| stream.push(&tokenizer, id)).collect();
+        assert_eq!(streamed, tokenizer.decode(ids.clone()));
+
+        tokenizer.unknown_id_policy = UnknownIdPolicy::Error;
+        assert!(stream.try_push(&tokenizer, 999).is_err());
+        assert_eq!(stream.try_push(&tokenizer, 1000).unwrap(), "<eos>");
+    }
+}
+
+#[test]
+fn unknown_id_policy_controls_decoding_of_unknown_ids() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<eos>".to_string(), 1000);
+    let ids = [104, 105, 999, 1000];
+
+    assert_eq!(tokenizer.decode(ids.to_vec()), "hi<eos>");
+    assert_eq!(tokenizer.try_decode(&ids).unwrap(), "hi<eos>");
+
+    tokenizer.unknown_id_policy = UnknownIdPolicy::Placeholder;
+    assert_eq!(tokenizer.decode(ids.to_vec()), "hi<unk_special:999><eos>");
+
+    tokenizer.unknown_id_policy = UnknownIdPolicy::Error;
+    assert!(tokenizer.try_decode(&ids).is_err());
+    assert_eq!(tokenizer.try_decode(&[104, 1000]).unwrap(), "h<eos>");
+    assert_eq!(tokenizer.decode(ids.to_vec()), "hi<eos>");
+}
+
+#[test]
+fn token_cooccurrence_counts_pairs_within_window_per_document() {
+    let tokenizer = Tokenizer::default();
+    // No merges: one token per byte
+    let texts = vec!["abc".to_string(), "ab".to_string()];
+
+    let adjacent = tokenizer.token_cooccurrence(texts.clone(), 1);
+    let expected: StdHashMap<(u32, u32), u64> =
+        [((97, 98), 2), ((98, 99), 1)].into_iter().collect();
+    assert_eq!(adjacent, expected);
+
+    let wide = tokenizer.token_cooccurrence(texts.clone(), 5);
+    assert_eq!(wide.get(&(97, 99)), Some(&1));
+    assert_eq!(wide.get(&(99, 97)), None); // "c" then "a" only across documents
+    assert!(tokenizer.token_cooccurrence(texts, 0).is_empty());
+}
+
+#[test]
+fn byte_level_only_skips_merges_but_keeps_specials() {
+    let mut tokenizer = Tokenizer::default();
+    assert_eq!(tokenizer.vocab_size(), 256);
+    tokenizer.register_special_token("<eos>".to_string(), 256);
+    assert_eq!(tokenizer.max_token_id() + 1, 257);
+
+    tokenizer.load_merges([((108, 108), 257)].into_iter().collect()); // "ll"
+    tokenizer.byte_level_only = true;
+    let ids = tokenizer.encode("hello<eos>");
+    assert_eq!(ids, [104, 101, 108, 108, 111, 256]);
+    assert_eq!(tokenizer.encode_with_offsets("hello<eos>", false).0, ids);
+    assert_eq!(tokenizer.decode(ids), "hello<eos>");
+
+    let restored = Tokenizer::from_bytes(&tokenizer.to_bytes()).unwrap();
+    assert!(restored.byte_level_only);
+    tokenizer.byte_level_only = false;
+    assert_eq!(tokenizer.encode("hello"), [104, 101, 257, 111]);
+}
+
+#[test]
+fn hot_merges_ranks_merges_by_times_applied() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges(
+        [((108, 108), 256), ((104, 101), 257), ((119, 111), 258)]
+            .into_iter()
+            .collect(),
+    ); // "ll", "he", "wo"
+    let texts = vec!["hello hello".to_string(), "all well".to_string()];
+
+    let hot = tokenizer.hot_merges(texts.clone(), 10);
+    assert_eq!(hot, vec![((108, 108), 4), ((104, 101), 2)]);
+    assert_eq!(tokenizer.hot_merges(texts, 1), vec![((108, 108), 4)]);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn training_is_identical_across_thread_counts() {
+    // Enough distinct chunks for parallel counting, and many equal counts
+    // so tie-breaking decides most merges
+    let corpus: Vec<(String, u32)> = (0..3000)
+        .map(|i| {
+            (
+                format!("w{} x{} {}y ", i % 1500, i % 7, i % 11),
+                1 + (i % 3) as u32,
+            )
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    for (run, tie_break) in [TieBreak::SmallerPair, TieBreak::ByFirstOccurrence]
+        .into_iter()
+        .cycle()
+        .take(10)
+        .enumerate()
+    {
+        let threads = [1, 2, 3, 4, 8][run % 5];
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer {
+            tie_break,
+            pair_reduce: if run % 4 < 2 {
+                PairReduce::Sharded
+            } else {
+                PairReduce::Tree
+            },
+            ..Default::default()
+        };
+        pool.install(|| tokenizer.train(&corpus, 400)).unwrap();
+        results.push((tie_break, tokenizer.merges_ordered()));
+    }
+
+    for (tie_break, merges) in &results {
+        let first = &results.iter().find(|(t, _)| t == tie_break).unwrap().1;
+        assert_eq!(merges, first, "{tie_break:?}");
+    }
+}
+
+#[test]
+fn pretokenize_returns_chunks_with_specials_split_out() {
+    let mut tokenizer = Tokenizer::with_preset("gpt4").unwrap();
+    tokenizer.register_special_token("<eos>".to_string(), 1000);
+
+    let text = "Hello world's 1234<eos>  done";
+    let chunks = tokenizer.pretokenize(text);
+    assert_eq!(
+        chunks,
+        ["Hello", " world", "'s", " ", "123", "4", "<eos>", " ", " done"]
+    );
+    assert_eq!(chunks.concat(), text);
+}
+
+#[test]
+fn pattern_impact_is_the_fraction_of_changed_chunks() {
+    let tokenizer = Tokenizer::default();
+    let texts = vec!["hello world".to_string(), "abc 1234".to_string()];
+
+    assert_eq!(
+        tokenizer
+            .pattern_impact(&tokenizer.pattern, texts.clone())
+            .unwrap(),
+        0.0
+    );
+    // GPT-4 chunks: "hello", " world", "abc", " ", "123", "4". With `\S+|\s+`
+    // "hello", "abc" and " " survive.
+    let impact = tokenizer.pattern_impact(r"\S+|\s+", texts.clone()).unwrap();
+    assert_eq!(impact, 0.5);
+    assert!(tokenizer.pattern_impact("(", texts).is_err());
+}
+
+#[test]
+fn i64_encoders_match_u32_encoders() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<eos>".to_string(), u32::MAX);
+    let text = "hello world<eos>";
+
+    let wide: Vec<i64> = tokenizer.encode(text).into_iter().map(i64::from).collect();
+    assert_eq!(tokenizer.encode_i64(text), wide);
+    assert_eq!(*wide.last().unwrap(), u32::MAX as i64);
+    assert_eq!(
+        tokenizer.encode_batch_i64(vec![text.to_string(), String::new()]),
+        vec![wide, vec![]]
+    );
+}
+
+#[test]
+fn specialize_keeps_used_tokens_and_their_parts() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.load_merges(
+        [
+            ((104, 101), 256),
+            ((119, 111), 257),
+            ((256, 108), 258),
+            ((108, 108), 259),
+        ]
+        .into_iter()
+        .collect(),
+    ); // "he", "wo", "hel", "ll"
+    tokenizer.register_special_token("<eos>".to_string(), 1000);
+    tokenizer
+        .register_special_pattern(r"<img\d>".to_string(), 1001)
+        .unwrap();
+    tokenizer
+        .register_literal_token("@@".to_string(), 1002)
+        .unwrap();
+
+    // "help<eos>" uses only "hel", which needs "he"
+    let (small, remap) = tokenizer.specialize(vec!["help<eos>".to_string()]).unwrap();
+    let expected: StdHashMap<Pair, u32> =
+        [((104, 101), 256), ((256, 108), 257)].into_iter().collect();
+    assert_eq!(small.merges, expected);
+    assert_eq!(remap[&256], 256);
+    assert_eq!(remap[&258], 257);
+    assert_eq!(remap[&1000], 1000);
+    assert_eq!(remap[&1001], 1001);
+    assert_eq!(remap[&1002], 1002);
+    assert!(!remap.contains_key(&257) && !remap.contains_key(&259));
+
+    let old: Vec<u32> = tokenizer
+        .encode("help<eos>")
+        .iter()
+        .map(|id| remap[id])
+        .collect();
+    assert_eq!(small.encode("help<eos>"), old);
+
+    // Encode options carry over, so the copy refuses what the original does
+    tokenizer.overlong_chunk_policy = OverlongChunkPolicy::Error;
+    tokenizer.max_chunk_bytes = 4;
+    tokenizer.unknown_id_policy = UnknownIdPolicy::Placeholder;
+    let (small, _) = tokenizer.specialize(vec!["help".to_string()]).unwrap();
+    assert_eq!(small.overlong_chunk_policy, OverlongChunkPolicy::Error);
+    assert_eq!(small.max_chunk_bytes, 4);
+    assert_eq!(small.unknown_id_policy, UnknownIdPolicy::Placeholder);
+    assert!(small.try_encode("helping").is_err());
+    assert!(tokenizer.specialize(vec!["helping".to_string()]).is_err());
+
+    // A special sitting where the renumbered merges land is refused
+    tokenizer.register_special_token("<pad>".to_string(), 257);
+    assert!(tokenizer.specialize(vec!["help".to_string()]).is_err());
+}
+
+#[test]
+fn decode_escaped_keeps_logs_on_one_line() {
+    let tokenizer = Tokenizer::default();
+    let mut ids = tokenizer.encode("a\tb\ncafé \\ \u{0}");
+    ids.push(0xff); // invalid UTF-8 on its own
+
+    assert_eq!(
+        tokenizer.decode_escaped(&ids, EscapeMode::Display),
+        "a\\tb\\ncafé \\ \\x00\\xff"
+    );
+    assert_eq!(
+        tokenizer.decode_escaped(&ids, EscapeMode::Strict),
+        "a\\tb\\ncaf\\xc3\\xa9 \\\\ \\x00\\xff"
+    );
+}
+
+#[test]
+fn training_session_steps_match_train() {
+    let corpus = vec![
+        ("hello world hello there".to_string(), 3),
+        ("low lower lowest".to_string(), 2),
+    ];
+    let mut trained = Tokenizer::default();
+    trained.train(&corpus, 270).unwrap();
+
+    let mut stepped = Tokenizer::default();
+    let mut session = stepped.start_training(&corpus, 270).unwrap();
+    let first_count = *session.pair_counts().values().max().unwrap();
+    let mut learned = Vec::new();
+    while let Some((pair, id)) = session.step(&mut stepped).unwrap() {
+        if learned.is_empty() {
+            assert_eq!(trained.merges[&pair], id);
+            assert!(session.pair_counts().values().all(|&c| c <= first_count));
+        }
+        learned.push((pair, id));
+        assert_eq!(stepped.merges.len() as u32, session.merges_done());
+        assert_eq!(stepped.decode(vec![id]), trained.decode(vec![id]));
+    }
+
+    assert_eq!(learned, trained.merges_ordered());
+    assert!(session.step(&mut stepped).unwrap().is_none());
+}
+
+#[test]
+fn training_session_rejects_a_different_tokenizer() {
+    let corpus = vec![("hello hello world".to_string(), 3)];
+    let mut tokenizer = Tokenizer::default();
+    let mut session = tokenizer.start_training(&corpus, 262).unwrap();
+    session.step(&mut tokenizer).unwrap().unwrap();
+
+    let mut other = Tokenizer::default();
+    assert!(session.step(&mut other).is_err());
+    tokenizer.merges.clear();
+    assert!(session.step(&mut tokenizer).is_err());
+    assert_eq!(session.merges_done(), 1);
+}
+
+#[test]
+fn scan_specials_matches_encode_time_splitting() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token("<|end|>".to_string(), 1000);
+    tokenizer.register_special_token("<|endoftext|>".to_string(), 1001);
+    tokenizer.register_special_token_case_insensitive("<SYS>".to_string(), 1002);
+    tokenizer
+        .register_literal_token("@@".to_string(), 1003)
+        .unwrap();
+    tokenizer
+        .register_special_pattern(r"<img:\d+>".to_string(), 1004)
+        .unwrap();
+
+    let text = "a<|endoftext|>b<sys>@@<img:42><|end|>";
+    assert_eq!(
+        tokenizer.scan_specials(text),
+        vec![
+            ("<|endoftext|>".to_string(), 1),
+            ("<SYS>".to_string(), 15),
+            ("<img:42>".to_string(), 22),
+            ("<|end|>".to_string(), 30),
+        ]
+    );
+    assert!(tokenizer.scan_specials("plain text").is_empty());
+}
+
+#[test]
+fn pair_entropy_measures_pair_spread() {
+    let tokenizer = Tokenizer::default();
+    // One distinct pair only
+    assert_eq!(tokenizer.pair_entropy(vec!["aaaa".to_string()]), 0.0);
+    // Two equally likely pairs
+    let entropy = tokenizer.pair_entropy(vec!["ab".to_string(), "cd".to_string()]);
+    assert!((entropy - 1.0).abs() < 1e-12, "{}", entropy);
+    assert_eq!(tokenizer.pair_entropy(vec!["a".to_string()]), 0.0);
+    assert_eq!(tokenizer.pair_entropy(Vec::new()), 0.0);
+}
+
+#[test]
+fn pattern_engine_falls_back_to_fancy_for_lookaround() {
+    let mut tokenizer = Tokenizer::with_preset("whitespace").unwrap();
+    assert!(matches!(
+        *tokenizer.compiled_pattern,
+        CompiledPattern::Fast(_)
+    ));
+
+    let text = "Hello,  world!\n  It's 2024  ";
+    let fast = tokenizer.pretokenize(text);
+    tokenizer.set_pattern_engine(PatternEngine::Fancy).unwrap();
+    assert!(matches!(
+        *tokenizer.compiled_pattern,
+        CompiledPattern::Fancy(_)
+    ));
+    assert_eq!(tokenizer.pretokenize(text), fast);
+    assert_eq!(tokenizer.get_pattern_engine(), PatternEngine::Fancy);
+
+    // GPT-4's `\s+(?!\S)` needs lookahead, so Auto still ends up on fancy
+    let mut tokenizer = Tokenizer::with_preset("gpt4").unwrap();
+    tokenizer.set_pattern_engine(PatternEngine::Auto).unwrap();
+    assert!(matches!(
+        *tokenizer.compiled_pattern,
+        CompiledPattern::Fancy(_)
+    ));
+}
+
+#[test]
+fn merges_array_roundtrips_in_id_order() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello hello world world"], 262).unwrap();
+    let array = tokenizer.merges_array();
+    assert_eq!(array.len(), 3 * tokenizer.merges.len());
+    let ids: Vec<u32> = array.chunks_exact(3).map(|t| t[2]).collect();
+    assert_eq!(
+        ids,
+        (256..256 + tokenizer.merges.len() as u32).collect::<Vec<_>>()
+    );
+
+    let mut loaded = Tokenizer::default();
+    loaded.merges_from_array(array).unwrap();
+    assert_eq!(loaded.merges, tokenizer.merges);
+
+    assert!(loaded.merges_from_array(vec![1, 2]).is_err());
+    assert!(loaded
+        .merges_from_array(vec![1, 2, 256, 1, 2, 257])
+        .is_err());
+    assert_eq!(loaded.merges, tokenizer.merges);
+}
+
+#[test]
+fn self_overlapping_pairs_keep_counts_consistent() {
+    let recount = |words: &[Word]| {
+        let mut counts: AHashMap<Pair, i32> = AHashMap::new();
+        for word in words {
+            for pair in word.pairs() {
+                *counts.entry(pair).or_insert(0) += 1;
+            }
+        }
+        counts
+    };
+
+    let a = b'a' as u32;
+    for len in 2..=7 {
+        let mut words = vec![Word::new(vec![a; len])];
+        let mut counts = recount(&words);
+        // (a, a) -> 256, then (256, 256) -> 257, ...
+        for (step, new_id) in (256u32..259).enumerate() {
+            let pair = if step == 0 {
+                (a, a)
+            } else {
+                (new_id - 1, new_id - 1)
+            };
+            for (pair, delta) in words[0].merge_pair(pair, new_id) {
+                *counts.entry(pair).or_insert(0) += delta;
+            }
+            counts.retain(|_, c| *c != 0);
+            assert_eq!(counts, recount(&words), "len {} step {}", len, step);
+        }
+    }
+
+    let mut tokenizer = Tokenizer::default();
+    let corpus: Vec<(String, u32)> = ["aaaa", "aaa", "aaaaa"]
+        .iter()
+        .map(|s| (s.to_string(), 1000))
+        .collect();
+    let mut session = tokenizer.start_training(&corpus, 260).unwrap();
+    while session.step(&mut tokenizer).unwrap().is_some() {
+        assert!(session.pair_counts.values().all(|&c| c >= 0));
+    }
+    for text in ["aaaa", "aaa", "aaaaa", "a", "aaaaaaaaaaa"] {
+        assert_eq!(tokenizer.decode(tokenizer.encode(text)), text);
+    }
+}
+
+#[test]
+fn stats_reports_table_sizes() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello hello world"], 260).unwrap();
+    tokenizer.register_special_token("<|end|>".to_string(), 1000);
+
+    let stats = tokenizer.stats();
+    assert_eq!(stats["merges_count"], 4);
+    assert_eq!(stats["special_count"], 1);
+    assert_eq!(stats["pattern_len"], tokenizer.pattern.len() as u64);
+    assert!(stats["merges_bytes"] >= 4 * std::mem::size_of::<(Pair, u32)>() as u64);
+}
+
+#[test]
+fn overlong_chunk_policy_windows_or_rejects_long_chunks() {
+    // GPT-4's pattern hits fancy-regex's backtrack limit on a 1 MiB run
+    let mut tokenizer = Tokenizer::with_preset("whitespace").unwrap();
+    train_on(&mut tokenizer, &["hello hello hello world"], 262).unwrap();
+
+    let text = "x".repeat(1 << 20);
+    tokenizer.overlong_chunk_policy = OverlongChunkPolicy::Windowed;
+    assert_eq!(tokenizer.max_chunk_bytes, 65536);
+    let ids = tokenizer.try_encode(&text).unwrap();
+    assert_eq!(tokenizer.decode(ids), text);
+
+    // Windows merge independently: "hel" | "lo" | ...
+    tokenizer.max_chunk_bytes = 3;
+    let windowed = tokenizer.encode("hello");
+    let expected: Vec<u32> = ["hel", "lo"]
+        .iter()
+        .flat_map(|piece| tokenizer.encode(piece))
+        .collect();
+    assert_eq!(windowed, expected);
+    assert_eq!(tokenizer.decode(windowed), "hello");
+
+    tokenizer.overlong_chunk_policy = OverlongChunkPolicy::Error;
+    let err = tokenizer.try_encode("hi hello").unwrap_err();
+    assert!(err.to_string().contains("offset 3"), "{}", err);
+    assert!(tokenizer.try_encode("hi abc").is_ok());
+
+    tokenizer.overlong_chunk_policy = OverlongChunkPolicy::Full;
+    assert_eq!(tokenizer.encode("hello").len(), 1);
+}
+
+#[test]
+fn overlong_chunk_policy_applies_to_every_encoder() {
+    let mut tokenizer = Tokenizer::with_preset("whitespace").unwrap();
+    train_on(&mut tokenizer, &["hello hello hello world"], 262).unwrap();
+    tokenizer.overlong_chunk_policy = OverlongChunkPolicy::Windowed;
+    tokenizer.max_chunk_bytes = 3;
+
+    let text = "hello world";
+    let ids = tokenizer.encode(text);
+    let (tokens, offsets) = tokenizer.encode_with_offsets(text, false);
+    assert_eq!(tokens, ids);
+    assert_eq!(offsets.last().unwrap().1, text.len());
+    assert_eq!(tokenizer.encode_ordinary(text), ids);
+    assert_eq!(tokenizer.encode_budget(text, 100).0, ids);
+    assert_eq!(tokenizer.encode_truncate_left(text, 100), ids);
+    assert_eq!(tokenizer.encode_with_word_ids(text).0, ids);
+    assert_eq!(tokenizer.encode_with_word_token_counts(text).0, ids);
+    let merges = tokenizer.encode_trace(text).len();
+    assert_eq!(text.len() - merges, ids.len());
+    let mut window = SlidingWindowEncoder::new(100);
+    window.push(&tokenizer, text);
+    assert_eq!(window.tokens(), ids);
+    let longest = tokenizer.encode_with_strategy(text, EncodeStrategy::LongestToken);
+    let sampled = tokenizer.encode_sampled(text, 2.0, 7);
+    let nbest = tokenizer.encode_nbest(text, 4);
+    assert_eq!(nbest[0], ids);
+    for tokens in nbest.iter().chain([&longest, &sampled]) {
+        assert_eq!(tokenizer.decode(tokens.clone()), text);
+        assert!(tokens
+            .iter()
+            .all(|&id| tokenizer.decode(vec![id]).len() <= 3));
+    }
+
+    tokenizer.overlong_chunk_policy = OverlongChunkPolicy::Error;
+    let texts = vec!["hi".to_string(), text.to_string()];
+    assert!(tokenizer.try_encode_batch(texts.clone()).is_err());
+    assert!(tokenizer.try_encode_batch_flat(texts).is_err());
+    assert!(tokenizer.try_encode_with_offsets(text, false).is_err());
+    assert!(tokenizer.try_encode_trace(text).is_err());
+    assert!(tokenizer
+        .try_encode_with_strategy(text, EncodeStrategy::LongestToken)
+        .is_err());
+    assert!(tokenizer.try_encode_sampled(text, 2.0, 7).is_err());
+    assert!(tokenizer.try_encode_nbest(text, 4).is_err());
+    assert!(window.try_push(&tokenizer, " again").is_err());
+    assert_eq!(window.tokens(), ids);
+}
+
+#[test]
+fn rank_tokens_by_frequency_sorts_histogram() {
+    let tokenizer = Tokenizer::default();
+    let ranked = tokenizer.rank_tokens_by_frequency(vec!["abcbcc".to_string(), "a".to_string()]);
+    assert_eq!(
+        ranked,
+        vec![(b'c' as u32, 3), (b'a' as u32, 2), (b'b' as u32, 2)]
+    );
+    assert!(tokenizer.rank_tokens_by_frequency(Vec::new()).is_empty());
+}
+
+#[test]
+fn scan_byte_coverage_counts_chunk_bytes() {
+    let tokenizer = Tokenizer::default();
+    let coverage = tokenizer.scan_byte_coverage(vec!["aab".to_string(), "é\0".to_string()]);
+    assert_eq!(coverage[b'a' as usize], 2);
+    assert_eq!(coverage[b'b' as usize], 1);
+    assert_eq!(coverage[0], 1);
+    assert_eq!(coverage[0xc3] + coverage[0xa9], 2);
+    assert_eq!(coverage.iter().sum::<u64>(), 6);
+}
+
+#[test]
+fn train_from_counts_matches_training_on_the_chunks() {
+    let corpus = [("hello", 5u32), (" world", 3), ("hello", 2), ("!", 1)];
+
+    let mut from_corpus = Tokenizer::default();
+    let texts: Vec<(String, u32)> = corpus.iter().map(|&(s, c)| (s.to_string(), c)).collect();
+    from_corpus.train(&texts, 264).unwrap();
+
+    let mut counts = StdHashMap::new();
+    for &(chunk, count) in &corpus {
+        *counts.entry(chunk.to_string()).or_insert(0) += count;
+    }
+    let mut from_counts = Tokenizer::default();
+    from_counts.train_from_counts(counts, 264).unwrap();
+    assert_eq!(from_counts.merges, from_corpus.merges);
+
+    // Keys are not split: "a b" stays one chunk, so " " can merge
+    let mut tokenizer = Tokenizer::default();
+    tokenizer
+        .train_from_counts(StdHashMap::from([("a b".to_string(), 1)]), 258)
+        .unwrap();
+    assert_eq!(tokenizer.decode(vec![257]), "a b");
+
+    assert!(tokenizer.train_from_counts(StdHashMap::new(), 257).is_err());
+}
+
+#[test]
+fn max_heap_pairs_bounds_tracked_pairs() {
+    let corpus: Vec<(String, u32)> = (0..200)
+        .map(|i| (format!("word{} token{} ", i, i * 7 % 50), 1 + i % 5))
+        .collect();
+
+    let mut full = Tokenizer::default();
+    full.train(&corpus, 300).unwrap();
+
+    // A cap above the number of distinct pairs changes nothing
+    let mut generous = Tokenizer::new().unwrap();
+    generous.max_heap_pairs = Some(1_000_000);
+    generous.train(&corpus, 300).unwrap();
+    assert_eq!(generous.merges, full.merges);
+
+    // Positions are recorded only for the kept pairs, and match full counting
+    let (words, counts) = Tokenizer::default()
+        .count_training_corpus(&corpus, 300)
+        .unwrap();
+    let (all_pc, all_wtu) = Tokenizer::count_pairs_sequential(&words, &counts);
+    let (pc, wtu) = Tokenizer::count_pairs_capped(&words, &counts, 8);
+    assert_eq!(pc.len(), 8);
+    assert_eq!(wtu.len(), 8);
+    for (pair, count) in &pc {
+        assert_eq!(all_pc[pair], *count);
+        assert_eq!(all_wtu[pair], wtu[pair]);
+    }
+    assert!(
+        all_pc
+            .values()
+            .filter(|&&c| c > *pc.values().min().unwrap())
+            .count()
+            < 8
+    );
+
+    let mut capped = Tokenizer::new().unwrap();
+    capped.max_heap_pairs = Some(8);
+    let mut session = capped.start_training(&corpus, 300).unwrap();
+    assert_eq!(session.pair_counts.len(), 8);
+    assert!(session.heap.len() <= 8);
+    let first = session.step(&mut capped).unwrap().unwrap();
+    assert_eq!(first, full.merges_ordered()[0]);
+    while session.step(&mut capped).unwrap().is_some() {}
+
+    let text = "word17 token42";
+    assert_eq!(capped.decode(capped.encode(text)), text);
+}
+
+#[test]
+fn same_tokens_compares_encodings() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello hello world"], 262).unwrap();
+    tokenizer.register_special_token("<|end|>".to_string(), 1000);
+    tokenizer.strip_zero_width = true;
+
+    assert!(tokenizer.same_tokens("hello world", "hello world"));
+    assert!(tokenizer.same_tokens("hel\u{200b}lo<|end|>", "hello<|end|>"));
+    assert!(!tokenizer.same_tokens("hello world", "hello  world"));
+    assert!(!tokenizer.same_tokens("hello", "hello<|end|>"));
+    assert!(!tokenizer.same_tokens("", "a"));
+    assert!(tokenizer.same_tokens("", ""));
+}
+
+#[test]
+fn register_special_tokens_rebuilds_matcher_lazily() {
+    let mut tokenizer = Tokenizer::default();
+    tokenizer.register_special_token_case_insensitive("<CTRL_0>".to_string(), 999);
+    assert_eq!(tokenizer.encode("<CTRL_0>"), vec![999]);
+    let tokens: Vec<(String, u32)> = (0..300)
+        .map(|i| (format!("<ctrl_{}>", i), 1000 + i))
+        .collect();
+    tokenizer
+        .register_special_tokens(tokens, SpecialConflict::Overwrite)
+        .unwrap();
+    assert!(tokenizer.cache.special_matcher.get().is_none());
+
+    assert_eq!(
+        tokenizer.encode("a<ctrl_0><ctrl_299>"),
+        vec![b'a' as u32, 1000, 1299]
+    );
+    assert!(tokenizer.cache.special_matcher.get().is_some());
+    // The case-insensitive `<CTRL_0>` is a different string and survives
+    assert_eq!(tokenizer.encode("<CTRL_0>"), vec![999]);
+}
+
+#[test]
+fn token_distribution_kl_detects_drift() {
+    let tokenizer = Tokenizer::default();
+    let same = tokenizer.token_distribution_kl(vec!["abab".to_string()], vec!["baba".to_string()]);
+    assert!(same.abs() < 1e-12, "{}", same);
+
+    // a: {a: 3, b: 1} -> (4/6, 2/6); b: {b: 4} -> (1/6, 5/6)
+    let kl = tokenizer.token_distribution_kl(vec!["aaab".to_string()], vec!["bbbb".to_string()]);
+    let expected = (4.0 / 6.0) * 4f64.log2() + (2.0 / 6.0) * 0.4f64.log2();
+    assert!((kl - expected).abs() < 1e-12, "{} vs {}", kl, expected);
+
+    assert_eq!(tokenizer.token_distribution_kl(Vec::new(), Vec::new()), 0.0);
+}
+
+#[test]
+fn encode_collapse_rare_replaces_tokens_outside_allowed_set() {
+    let tokenizer = Tokenizer::default();
+    let ranked = tokenizer.rank_tokens_by_frequency(vec!["aaab".to_string()]);
+    let allowed: StdHashSet<u32> = ranked.iter().take(1).map(|&(id, _)| id).collect();
+    assert_eq!(
+        tokenizer.encode_collapse_rare("abca", allowed, 5000),
+        vec![b'a' as u32, 5000, 5000, b'a' as u32]
+    );
+    assert!(tokenizer
+        .encode_collapse_rare("", StdHashSet::new(), 0)
+        .is_empty());
+}
+
+#[test]
+fn first_divergence_reports_index_and_ids() {
+    let mut tokenizer = Tokenizer::default();
+    train_on(&mut tokenizer, &["hello hello world"], 262).unwrap();
+    let ids = tokenizer.encode("hello world");
+
+    assert_eq!(tokenizer.first_divergence("hello world", ids.clone()), None);
+
+    let mut changed = ids.clone();
+    changed[1] = 7;
+    assert_eq!(
+        tokenizer.first_divergence("hello world", changed),
+        Some((1, Some(ids[1]), Some(7)))
+    );
+
+    let short = ids[..1].to_vec();
+    assert_eq!(
+        tokenizer.first_divergence("hello world", short),
+        Some((1, Some(ids[1]), None))
+    );
+    let mut long = ids.clone();
+    long.push(42);
+    assert_eq!(
+        tokenizer.first_divergence("hello world", long),
+        Some((ids.len(), None, Some(42)))
+    );
+}
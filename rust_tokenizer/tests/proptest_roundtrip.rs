@@ -0,0 +1,42 @@
+use proptest::prelude::*;
+use rust_tokenizer::Tokenizer;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Tokenizer with a handful of merges so round-trips exercise merged tokens,
+/// including ones that span whitespace and multi-byte UTF-8 sequences.
+fn tokenizer_with_merges() -> &'static Tokenizer {
+    static TOK: OnceLock<Tokenizer> = OnceLock::new();
+    TOK.get_or_init(|| {
+        let mut tok = Tokenizer::new().unwrap();
+        let merges: HashMap<(u32, u32), u32> = [
+            ((116, 104), 256),   // "th"
+            ((256, 101), 257),   // "the"
+            ((32, 257), 258),    // " the"
+            ((10, 10), 259),     // "\n\n"
+            ((32, 32), 260),     // "  "
+            ((0xC3, 0xA9), 261), // "é"
+        ]
+        .into_iter()
+        .collect();
+        tok.load_merges(merges);
+        tok
+    })
+}
+
+proptest! {
+    /// For text without special tokens, encoding is lossless
+    #[test]
+    fn decode_bytes_inverts_encode(s in any::<String>()) {
+        let tok = tokenizer_with_merges();
+        let ids = tok.encode(&s);
+        prop_assert_eq!(tok.decode_bytes(&ids), s.as_bytes());
+    }
+
+    #[test]
+    fn decode_bytes_inverts_encode_whitespace_heavy(s in "[ \t\r\n\u{a0}\u{3000}a-z0-9é]{0,64}") {
+        let tok = tokenizer_with_merges();
+        let ids = tok.encode(&s);
+        prop_assert_eq!(tok.decode(ids), s);
+    }
+}
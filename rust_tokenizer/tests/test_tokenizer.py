@@ -44,3 +44,30 @@ def test_vocab_size_and_special_tokens():
 
     tokens = tok.encode("<SPECIAL>")
     assert tokens == [base_vocab + 1]
+
+
+def test_to_bytes_round_trip():
+    rust_tokenizer = pytest.importorskip("rust_tokenizer")
+
+    tok = rust_tokenizer.Tokenizer()
+    tok.register_special_token("<SPECIAL>", 1000)
+
+    data = tok.to_bytes()
+    assert isinstance(data, bytes)
+
+    restored = rust_tokenizer.Tokenizer.from_bytes(data)
+    assert restored.encode("hello <SPECIAL>") == tok.encode("hello <SPECIAL>")
+
+
+def test_overlong_chunk_policy():
+    rust_tokenizer = pytest.importorskip("rust_tokenizer")
+
+    tok = rust_tokenizer.Tokenizer.with_preset("whitespace")
+    text = "x" * 1_000_000
+
+    tok.overlong_chunk_policy = rust_tokenizer.OverlongChunkPolicy.Windowed
+    assert len(tok.encode(text)) == len(text)
+
+    tok.overlong_chunk_policy = rust_tokenizer.OverlongChunkPolicy.Error
+    with pytest.raises(ValueError):
+        tok.encode(text)